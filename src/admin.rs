@@ -0,0 +1,299 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    net::TcpStream,
+    path::PathBuf,
+    thread::sleep,
+    time::Duration,
+};
+
+use crate::{
+    failures::{During, Failure, Op, Reasons},
+    hostsfile::PeerList,
+    socketry::{
+        framing::{read_frame, write_frame},
+        tls::TlsConfig,
+        wire::{self, Codec, Parcel},
+        Channel, ATTEMPT_WAIT, MAX_ATTEMPTS, PORT,
+    },
+    state::{
+        messaging::{Letter, Message, Operation, RoleKind, Stage, Status},
+        PeerId, ViewId,
+    },
+};
+
+/// Sender id admin clients put on their letters, no peer is ever 0
+pub const ADMIN_ID: PeerId = 0;
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Sends one admin letter to a running peer and waits for its answer.
+/// Groups running with TLS need `tls` set up with a certificate from the same CA.
+fn request(peer_name: &str, tls: Option<&TlsConfig>, message: Message) -> Result<Message, Failure> {
+    let addr = format!("{peer_name}:{PORT}");
+    let with_addr = |f: Failure| f.addr(&addr);
+
+    let sock = TcpStream::connect(&addr)
+        .during(Op::Connect)
+        .map_err(with_addr)?;
+    sock.set_read_timeout(Some(REPLY_TIMEOUT))
+        .during(Op::Connect)
+        .map_err(with_addr)?;
+    let mut sock = Channel::connect(sock, peer_name, tls)?;
+
+    let version = wire::handshake(&mut sock, ADMIN_ID, Codec::Bincode).map_err(with_addr)?;
+    wire::check_version(version, &message)
+        .and_then(|_| match message {
+            Message::VIEWS => Ok(()),
+            // anything else can be answered with a REPORT
+            _ => wire::check_report(version),
+        })
+        .during(Op::Send)
+        .map_err(with_addr)?;
+
+    let letter: Letter = (ADMIN_ID, message).into();
+    let buf = wire::encode_letter(version, Codec::Bincode, &letter)
+        .map_err(Reasons::BadMessage)
+        .during(Op::Send)?;
+    write_frame(&mut sock, &buf)
+        .during(Op::Send)
+        .map_err(with_addr)?;
+
+    match receive(&mut sock, Op::Receive).map_err(with_addr)? {
+        Parcel::Letter(reply) => Ok(reply.message().clone()),
+        _ => Err(Failure::new(Op::Receive, Reasons::UnexpectedReply).addr(&addr)),
+    }
+}
+
+// blocks for the next frame on `sock`
+fn receive(sock: &mut Channel, during: Op) -> Result<Parcel, Failure> {
+    read_frame(sock)
+        .map_err(Reasons::IO)
+        .and_then(|frame| wire::decode(&frame).map_err(Reasons::BadFrame))
+        .during(during)
+}
+
+fn expect_report(peer_name: &str, reply: Message) -> Result<Status, Failure> {
+    match reply {
+        Message::REPORT(status) => Ok(*status),
+        _ => Err(Failure::new(Op::Receive, Reasons::UnexpectedReply).addr(peer_name)),
+    }
+}
+
+/// Asks a running process for its STATUS
+pub fn query_status(peer_name: &str, tls: Option<&TlsConfig>) -> Result<Status, Failure> {
+    expect_report(peer_name, request(peer_name, tls, Message::STATUS)?)
+}
+
+/// Has a running process JOIN if it hasn't yet, answers with its status from just after
+pub fn join(peer_name: &str, tls: Option<&TlsConfig>) -> Result<Status, Failure> {
+    expect_report(peer_name, request(peer_name, tls, Message::JOIN)?)
+}
+
+/// Tells a running process to LEAVE, answers with its status from just before
+pub fn leave(peer_name: &str, tls: Option<&TlsConfig>) -> Result<Status, Failure> {
+    expect_report(peer_name, request(peer_name, tls, Message::LEAVE)?)
+}
+
+/// Asks the leader to delete `id`. Followers ignore it, check the role in the answer.
+pub fn kick(peer_name: &str, id: PeerId, tls: Option<&TlsConfig>) -> Result<Status, Failure> {
+    expect_report(peer_name, request(peer_name, tls, Message::KICK(id))?)
+}
+
+/// Has a running process multicast `payload` to its view, causally ordered if `causal`
+pub fn send(
+    peer_name: &str,
+    payload: Vec<u8>,
+    causal: bool,
+    tls: Option<&TlsConfig>,
+) -> Result<Status, Failure> {
+    let message = match causal {
+        true => Message::CSEND(payload),
+        false => Message::SEND(payload),
+    };
+    expect_report(peer_name, request(peer_name, tls, message)?)
+}
+
+/// Has a running process submit `payload` for the leader to order
+pub fn submit(
+    peer_name: &str,
+    payload: Vec<u8>,
+    tls: Option<&TlsConfig>,
+) -> Result<Status, Failure> {
+    expect_report(
+        peer_name,
+        request(peer_name, tls, Message::SUBMIT(payload))?,
+    )
+}
+
+/// Has a running member ACQUIRE or RELEASE a lock for itself through the leader
+pub fn lock(
+    peer_name: &str,
+    name: String,
+    acquire: bool,
+    tls: Option<&TlsConfig>,
+) -> Result<Status, Failure> {
+    let message = match acquire {
+        true => Message::ACQUIRE(name),
+        false => Message::RELEASE(name),
+    };
+    expect_report(peer_name, request(peer_name, tls, message)?)
+}
+
+/// Every view a running process has installed, oldest first
+pub fn query_views(
+    peer_name: &str,
+    tls: Option<&TlsConfig>,
+) -> Result<Vec<(ViewId, HashSet<PeerId>)>, Failure> {
+    match request(peer_name, tls, Message::VIEWS)? {
+        Message::HISTORY(views) => Ok(views),
+        _ => Err(Failure::new(Op::Receive, Reasons::UnexpectedReply).addr(peer_name)),
+    }
+}
+
+/// Loads the hostsfile, and if it doesn't list this host has the leader
+/// admit it with `token`. Every listed host is asked in turn, following the
+/// leader each one reports, and the whole round is retried while nobody
+/// answers. A leader that answers without admitting us refused the token.
+pub fn load_or_admit(
+    hostsfile: PathBuf,
+    token: Option<&str>,
+    tls: Option<&TlsConfig>,
+) -> Result<PeerList, Failure> {
+    let Some(token) = token else {
+        return PeerList::load(hostsfile);
+    };
+    let mut peer_list = PeerList::load_guest(hostsfile)?;
+    if peer_list.is_listed() {
+        return Ok(peer_list);
+    }
+    let admit = Message::ADMIT {
+        name: peer_list.hostname().to_string(),
+        token: token.to_string(),
+    };
+    for _ in 0..MAX_ATTEMPTS {
+        let mut ask: VecDeque<String> = peer_list
+            .ids_and_names()
+            .map(|(_, name)| name.clone())
+            .collect();
+        let mut asked = HashSet::new();
+        while let Some(peer_name) = ask.pop_front() {
+            if !asked.insert(peer_name.clone()) {
+                continue;
+            }
+            match request(&peer_name, tls, admit.clone()) {
+                Ok(Message::ADMITTED { peer_id, hosts }) => {
+                    eprintln!("{peer_name} admitted this host as peer {peer_id}");
+                    peer_list.learn(&hosts);
+                    peer_list.persist()?;
+                    return Ok(peer_list);
+                }
+                Ok(Message::REPORT(status)) if status.leader_id == status.peer_id => {
+                    return Err(Failure::new(Op::Admission, Reasons::NotAdmitted).addr(peer_name));
+                }
+                Ok(Message::REPORT(status)) => {
+                    if let Some(leader) = peer_list.name(status.leader_id) {
+                        ask.push_front(leader.to_string());
+                    }
+                }
+                Ok(_) => eprintln!("{peer_name} didn't answer the ADMIT"),
+                Err(failure) => eprintln!("{failure}"),
+            }
+        }
+        sleep(ATTEMPT_WAIT);
+    }
+    Err(Failure::new(Op::Admission, Reasons::NotAdmitted))
+}
+
+/// Human readable dump of a REPORT for the command line
+pub fn print_status(status: &Status) {
+    let mut members: Vec<_> = status.members.iter().collect();
+    members.sort();
+
+    println!(
+        "peer {} ({:?}, {:?})",
+        status.peer_id, status.role, status.stage
+    );
+    println!(
+        "view {}, leader {}, members {:?}",
+        status.view_id, status.leader_id, members
+    );
+    match status.lease {
+        Some(left) => println!(
+            "epoch {}, lease held for {:.1}s more",
+            status.epoch,
+            left.as_secs_f64()
+        ),
+        None if matches!(
+            (status.role, status.stage),
+            (RoleKind::Leader, Stage::Living)
+        ) =>
+        {
+            println!("epoch {}, no lease: view changes held back", status.epoch)
+        }
+        None => println!("epoch {}", status.epoch),
+    }
+
+    if status.blocked {
+        println!("blocked: can't reach a majority of the view");
+    }
+    if status.ordered > 0 {
+        println!("ordered messages delivered up to #{}", status.ordered);
+    }
+    if !status.clock.is_empty() {
+        println!("vector clock {:?}", status.clock);
+    }
+    if !status.locks.is_empty() {
+        println!("locks:");
+        for (name, holder) in &status.locks {
+            println!("  {name:?} held by peer {holder}");
+        }
+    }
+    if !status.pending_requests.is_empty() {
+        println!("pending requests:");
+        for pending in &status.pending_requests {
+            let mut oks: Vec<_> = pending.oks.iter().collect();
+            oks.sort();
+            let instr = &pending.instruction;
+            println!(
+                "  #{} {} from view {}, oks {:?}",
+                instr.request_id,
+                describe(&instr.op, instr.peer_id),
+                instr.view_id,
+                oks
+            );
+        }
+    }
+    if !status.ack_queue.is_empty() {
+        println!("waiting to OK:");
+        for instr in &status.ack_queue {
+            println!(
+                "  #{} {} from view {}",
+                instr.request_id,
+                describe(&instr.op, instr.peer_id),
+                instr.view_id
+            );
+        }
+    }
+    if !status.heartbeat_ages.is_empty() {
+        println!("last heartbeat:");
+        for (peer, age) in &status.heartbeat_ages {
+            println!("  peer {peer}: {:.1}s ago", age.as_secs_f64());
+        }
+    }
+}
+
+pub fn print_views(views: &[(ViewId, HashSet<PeerId>)]) {
+    for (view_id, members) in views {
+        let mut members: Vec<_> = members.iter().collect();
+        members.sort();
+        println!("view {view_id}: {members:?}");
+    }
+}
+
+fn describe(op: &Operation, peer_id: PeerId) -> String {
+    match op {
+        Operation::Add => format!("add peer {peer_id}"),
+        Operation::Delete => format!("delete peer {peer_id}"),
+        Operation::Acquire(name) => format!("lock {name:?} for peer {peer_id}"),
+        Operation::Release(name) => format!("unlock {name:?} for peer {peer_id}"),
+    }
+}
@@ -1,18 +1,227 @@
-use std::path::PathBuf;
+use std::{net::SocketAddrV4, path::PathBuf};
 
-use clap::Parser;
+use clap::{ArgAction, Args, Parser, Subcommand};
+
+use prj3::{
+    socketry::{tls::TlsFiles, wire::Codec},
+    state::{Election, PeerId},
+};
 
 #[derive(Parser)]
 pub struct Project3 {
-    #[arg(short = 'h')]
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run a peer of the group (what every container does)
+    Run(RunArgs),
+
+    /// Print a running peer's role, view, queues and heartbeat ages
+    Status {
+        /// Hostname of the running peer
+        peer: String,
+        #[command(flatten)]
+        tls: TlsArgs,
+    },
+
+    /// Make a running peer JOIN now instead of at the end of its start delay
+    Join {
+        /// Hostname of the running peer
+        peer: String,
+        #[command(flatten)]
+        tls: TlsArgs,
+    },
+
+    /// Make a running peer LEAVE the group and exit
+    Leave {
+        /// Hostname of the running peer
+        peer: String,
+        #[command(flatten)]
+        tls: TlsArgs,
+    },
+
+    /// Ask the leader to delete a member from the view
+    Kick {
+        /// Hostname of the leader
+        peer: String,
+        /// Id of the member to delete
+        id: PeerId,
+        #[command(flatten)]
+        tls: TlsArgs,
+    },
+
+    /// Multicast a message to the view a running peer is in
+    Send {
+        /// Hostname of the running peer
+        peer: String,
+        /// What to send, delivered as is by every member
+        text: String,
+        /// Deliver it causally ordered instead of view synchronously
+        #[arg(long)]
+        causal: bool,
+        #[command(flatten)]
+        tls: TlsArgs,
+    },
+
+    /// Have the leader order a message, every member delivers it in that order
+    Submit {
+        /// Hostname of any running member
+        peer: String,
+        /// What to submit
+        text: String,
+        #[command(flatten)]
+        tls: TlsArgs,
+    },
+
+    /// Have a member take a named lock through the leader
+    Lock {
+        /// Hostname of the member that should hold it
+        peer: String,
+        /// Name of the lock
+        name: String,
+        #[command(flatten)]
+        tls: TlsArgs,
+    },
+
+    /// Have a member give back a lock it holds
+    Unlock {
+        /// Hostname of the member holding it
+        peer: String,
+        /// Name of the lock
+        name: String,
+        #[command(flatten)]
+        tls: TlsArgs,
+    },
+
+    /// Print every view a running peer has installed
+    Views {
+        /// Hostname of the running peer
+        peer: String,
+        #[command(flatten)]
+        tls: TlsArgs,
+    },
+
+    /// Pretty-print frames captured off a peer's connections
+    Decode {
+        /// Raw payload of one TCP connection, e.g. saved from Wireshark's
+        /// Follow TCP Stream. Read from stdin if left out
+        capture: Option<PathBuf>,
+
+        /// The capture is a single heartbeat datagram, not a TCP stream
+        #[arg(long)]
+        datagram: bool,
+    },
+}
+
+// -h is taken by the hostsfile, so help is long only
+#[derive(Args)]
+#[command(disable_help_flag = true)]
+pub struct RunArgs {
+    /// File listing every peer's hostname, one per line, leader first
+    #[arg(short = 'h', long)]
     pub hostsfile: PathBuf,
 
-    #[arg(short = 'd')]
+    /// Seconds to wait before sending JOIN
+    #[arg(short = 'd', long)]
     pub start_delay: Option<u64>,
 
-    #[arg(short = 'c')]
+    /// Seconds after it starts heartbeating that the process crashes
+    #[arg(short = 'c', long)]
     pub crash_delay: Option<u64>,
 
-    #[arg(short = 't')]
+    #[arg(short = 't', long)]
     pub testcase4: bool,
+
+    /// Install views once a majority of the previous view OKs them instead of all of it,
+    /// so a minority side of a partition blocks instead of carrying on
+    #[arg(long)]
+    pub quorum: bool,
+
+    /// Run without a leader: members probe each other SWIM style and spread
+    /// joins and failures on the probes. Views converge rather than agree
+    #[arg(long, conflicts_with = "quorum")]
+    pub gossip: bool,
+
+    /// Who takes over when the leader stops heartbeating: nobody (fixed), or the
+    /// lowest id still heartbeating, found with the Bully algorithm (bully)
+    #[arg(long, default_value_t = Election::Fixed, conflicts_with = "gossip")]
+    pub election: Election,
+
+    /// Commit membership changes through a Raft log: a majority of the
+    /// configuration has to store each one, and leaders are elected by term
+    #[arg(long, conflicts_with_all = ["quorum", "gossip", "election"])]
+    pub raft: bool,
+
+    /// Address to serve Prometheus metrics on, e.g. 127.0.0.1:9100
+    #[arg(long)]
+    pub metrics: Option<String>,
+
+    /// How letters are written on peer connections: bincode, or json to read
+    /// them in a packet capture. Heartbeats are always bincode
+    #[arg(long, default_value_t = Codec::Bincode)]
+    pub codec: Codec,
+
+    /// Multicast group to send heartbeats to instead of each peer, e.g. 239.255.3.3:6789.
+    /// Every peer of the group has to be given the same one
+    #[arg(long, value_parser = multicast_group)]
+    pub multicast: Option<SocketAddrV4>,
+
+    /// Secret shared with hosts the hostsfile doesn't list: the leader admits
+    /// any that present it, and a host that isn't listed asks to be admitted with it
+    #[arg(long)]
+    pub admission_token: Option<String>,
+
+    #[command(flatten)]
+    pub tls: TlsArgs,
+
+    /// Name the certificate of an admin client has to be issued to for it to
+    /// join, leave, kick, send, submit or lock. Without it any host in the hostsfile's will do
+    #[arg(long, requires = "tls_cert")]
+    pub admin_name: Option<String>,
+
+    /// Drive the peer with tokio tasks instead of the poll loop (no TLS yet)
+    #[cfg(feature = "tokio")]
+    #[arg(long, conflicts_with = "tls_cert")]
+    pub tokio: bool,
+
+    /// Print help
+    #[arg(long, action = ArgAction::HelpLong)]
+    help: Option<bool>,
+}
+
+#[derive(Args)]
+pub struct TlsArgs {
+    /// PEM certificate this peer presents, enables mutual TLS on every channel
+    #[arg(long, requires_all = ["tls_key", "tls_ca"])]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key matching --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// PEM certificate of the CA that signed every peer's certificate. Any
+    /// certificate it signed can query a peer's status and views, only the
+    /// admin's (see run --admin-name) can change anything
+    #[arg(long, requires = "tls_cert")]
+    pub tls_ca: Option<PathBuf>,
+}
+
+impl TlsArgs {
+    pub fn files(&self) -> Option<TlsFiles> {
+        Some(TlsFiles {
+            cert: self.tls_cert.clone()?,
+            key: self.tls_key.clone()?,
+            ca: self.tls_ca.clone()?,
+        })
+    }
+}
+
+fn multicast_group(s: &str) -> Result<SocketAddrV4, String> {
+    let group: SocketAddrV4 = s.parse().map_err(|e| format!("{e}"))?;
+    if !group.ip().is_multicast() {
+        return Err(format!("{} isn't a multicast address", group.ip()));
+    }
+    Ok(group)
 }
@@ -0,0 +1,59 @@
+//! Where `Data` gets the time from. Peers run on [`SystemClock`], a
+//! [`ManualClock`] only moves when it's told to, so heartbeat timeouts and
+//! leases can be driven through in no time at all.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn elapsed(&self, since: Instant) -> Duration {
+        self.now().saturating_duration_since(since)
+    }
+}
+
+/// The real time
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Stands still until [`advance`](ManualClock::advance)d.
+/// Clones share the same time, so keep one to move the clock a peer was given.
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    start: Instant,
+    passed: Arc<Mutex<Duration>>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            passed: Arc::default(),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.passed.lock().unwrap() += by;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.passed.lock().unwrap()
+    }
+}
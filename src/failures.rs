@@ -1,7 +1,227 @@
+use std::{fmt, path::PathBuf, process::ExitCode};
+
+use crate::{
+    socketry::wire::{self, Malformed},
+    state::PeerId,
+};
+
+/// What actually went wrong, underneath whatever we were doing at the time.
 #[derive(Debug)]
 pub enum Reasons {
-    #[allow(dead_code)]
     IO(std::io::Error),
     HostNotInHostsfile,
-    BadMessage,
+    // a reloaded hostsfile put a different host on an existing line
+    HostMoved(PeerId),
+    // no host asked would let this one in
+    NotAdmitted,
+    BadMessage(bincode::Error),
+    BadFrame(Malformed),
+    // the other end only speaks these wire versions
+    Incompatible {
+        min_version: u16,
+        max_version: u16,
+    },
+    // the letter came in after the version the other end agreed on
+    Unsupported {
+        kind: &'static str,
+        version: u16,
+        since: u16,
+    },
+    Tls(rustls::Error),
+    BadCertificate,
+    TlsUnsupported,
+    UnexpectedReply,
+}
+
+/// The phase of the program a failure happened in.
+#[derive(Debug)]
+pub enum Op {
+    LoadHostsfile(PathBuf),
+    Admission,
+    LoadCertificates(PathBuf),
+    LoadCapture(PathBuf),
+    Bind,
+    Connect,
+    Accept,
+    Handshake,
+    Send,
+    Receive,
+    Poll,
+    Heartbeat,
+    Timer,
+    Signals,
+}
+
+/// Error returned out of main: the cause plus enough context to tell
+/// which peer and which phase it came from.
+///
+/// Exit codes:
+///  - 0 exited normally
+///  - 2 bad command line (reported by clap)
+///  - 3 hostsfile couldn't be read or doesn't list this host (and it wasn't
+///    admitted), or a capture to decode couldn't be read
+///  - 4 certificates couldn't be loaded or a TLS session failed
+///  - 5 couldn't bind, connect to or accept from a peer
+///  - 6 a peer connection or heartbeat socket failed mid-run
+///  - 7 a letter couldn't be encoded, the peer speaks no wire version we do
+///    or one too old for the letter, or an admin query got a bad answer
+#[derive(Debug)]
+pub struct Failure(Box<Context>);
+
+// boxed so a Result<_, Failure> stays small on the happy path
+#[derive(Debug)]
+struct Context {
+    reason: Reasons,
+    op: Op,
+    peer: Option<PeerId>,
+    addr: Option<String>,
+}
+
+impl Failure {
+    pub fn new(op: Op, reason: Reasons) -> Self {
+        Self(Box::new(Context {
+            reason,
+            op,
+            peer: None,
+            addr: None,
+        }))
+    }
+
+    pub fn peer(mut self, id: PeerId) -> Self {
+        self.0.peer = Some(id);
+        self
+    }
+
+    pub fn addr(mut self, addr: impl ToString) -> Self {
+        self.0.addr = Some(addr.to_string());
+        self
+    }
+
+    pub fn exit_code(&self) -> ExitCode {
+        let code = match (&self.0.reason, &self.0.op) {
+            (Reasons::HostNotInHostsfile | Reasons::NotAdmitted, _)
+            | (_, Op::LoadHostsfile(_) | Op::LoadCapture(_)) => 3,
+            (Reasons::Tls(_) | Reasons::BadCertificate | Reasons::TlsUnsupported, _)
+            | (_, Op::LoadCertificates(_)) => 4,
+            (
+                Reasons::BadMessage(_)
+                | Reasons::BadFrame(_)
+                | Reasons::Incompatible { .. }
+                | Reasons::Unsupported { .. }
+                | Reasons::UnexpectedReply,
+                _,
+            ) => 7,
+            (_, Op::Bind | Op::Connect | Op::Accept) => 5,
+            _ => 6,
+        };
+        ExitCode::from(code)
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Op::LoadHostsfile(path) => write!(f, "loading hostsfile {}", path.display()),
+            Op::LoadCertificates(path) => write!(f, "loading certificates from {}", path.display()),
+            Op::LoadCapture(path) => write!(f, "reading capture {}", path.display()),
+            Op::Admission => write!(f, "asking the leader to admit this host"),
+            Op::Bind => write!(f, "binding"),
+            Op::Connect => write!(f, "connecting"),
+            Op::Accept => write!(f, "accepting"),
+            Op::Handshake => write!(f, "handshaking"),
+            Op::Send => write!(f, "sending"),
+            Op::Receive => write!(f, "receiving"),
+            Op::Poll => write!(f, "polling channels"),
+            Op::Heartbeat => write!(f, "heartbeating"),
+            Op::Timer => write!(f, "arming a timer"),
+            Op::Signals => write!(f, "handling signals"),
+        }
+    }
+}
+
+impl fmt::Display for Reasons {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reasons::IO(e) => write!(f, "{e}"),
+            Reasons::HostNotInHostsfile => write!(f, "this host isn't listed in the hostsfile"),
+            Reasons::HostMoved(id) => write!(
+                f,
+                "line {id} names a different host now, ids can't be reassigned"
+            ),
+            Reasons::NotAdmitted => write!(
+                f,
+                "no leader admitted this host, check --admission-token"
+            ),
+            Reasons::BadMessage(e) => write!(f, "malformed message: {e}"),
+            Reasons::BadFrame(e) => write!(f, "malformed frame: {e}"),
+            Reasons::Incompatible {
+                min_version,
+                max_version,
+            } => write!(
+                f,
+                "peer speaks wire versions {min_version}..={max_version}, this build speaks {}..={}",
+                wire::MIN_VERSION,
+                wire::VERSION
+            ),
+            Reasons::Unsupported {
+                kind,
+                version,
+                since,
+            } => write!(
+                f,
+                "{kind} needs wire version {since}, the peer agreed on {version}"
+            ),
+            Reasons::Tls(e) => write!(f, "{e}"),
+            Reasons::BadCertificate => write!(f, "missing or unusable certificate"),
+            Reasons::TlsUnsupported => write!(f, "the tokio transport can't do TLS yet"),
+            Reasons::UnexpectedReply => write!(f, "peer didn't answer with a REPORT"),
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.op)?;
+        match (self.0.peer, &self.0.addr) {
+            (Some(id), Some(addr)) => write!(f, " (peer {id} at {addr})")?,
+            (Some(id), None) => write!(f, " (peer {id})")?,
+            (None, Some(addr)) => write!(f, " ({addr})")?,
+            (None, None) => {}
+        }
+        write!(f, ": {}", self.0.reason)
+    }
+}
+
+impl std::error::Error for Reasons {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Reasons::IO(e) => Some(e),
+            Reasons::BadMessage(e) => Some(e),
+            Reasons::Tls(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl std::error::Error for Failure {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0.reason)
+    }
+}
+
+/// Attaches the phase to a bare cause
+pub trait During<T> {
+    fn during(self, op: Op) -> Result<T, Failure>;
+}
+
+impl<T> During<T> for Result<T, Reasons> {
+    fn during(self, op: Op) -> Result<T, Failure> {
+        self.map_err(|reason| Failure::new(op, reason))
+    }
+}
+
+impl<T> During<T> for std::io::Result<T> {
+    fn during(self, op: Op) -> Result<T, Failure> {
+        self.map_err(|e| Failure::new(op, Reasons::IO(e)))
+    }
 }
@@ -0,0 +1,711 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::Write,
+    net::{SocketAddrV4, TcpListener},
+    os::fd::{AsFd, AsRawFd, RawFd},
+    path::PathBuf,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use nix::{
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags, PollTimeout},
+    sys::eventfd::{EfdFlags, EventFd},
+};
+
+use crate::{
+    admin::{self, ADMIN_ID},
+    clock::{Clock, SystemClock},
+    failures::{During, Failure, Op, Reasons},
+    metrics::Metrics,
+    socketry::{
+        bind_listener, dial,
+        framing::{Drained, Frame, FrameReader, MAX_FRAME},
+        make_channels,
+        tls::TlsConfig,
+        wire::{self, Codec, Parcel},
+        Channel, Inbound,
+    },
+    state::{
+        alarm::Alarms,
+        messaging::{Causal, Letter, Ordered},
+        Data, Election, Membership, PeerId, ViewId,
+    },
+};
+
+/// Everything a peer needs to take part in the group
+pub struct Config {
+    /// File listing every peer's hostname, one per line, leader first
+    pub hostsfile: PathBuf,
+    /// Stop heartbeating this long after starting to, to fake a crash
+    pub crash_delay: Option<Duration>,
+    /// Wraps every channel in mutual TLS
+    pub tls: Option<TlsConfig>,
+    /// Address to serve Prometheus metrics on
+    pub metrics: Option<String>,
+    /// How many members have to agree on a view change
+    pub membership: Membership,
+    /// Who takes over when the leader stops heartbeating
+    pub election: Election,
+    /// How this peer writes its letters, see [`Codec`]
+    pub codec: Codec,
+    /// What heartbeat timeouts, leases and the LEAVE timeout are measured on
+    pub clock: Arc<dyn Clock>,
+    /// Multicast group to send heartbeats to, once per period for everyone,
+    /// instead of one datagram per peer
+    pub multicast: Option<SocketAddrV4>,
+    /// Token a host the hostsfile doesn't list needs to be admitted. The
+    /// leader's admits anyone presenting it, a guest's is what it presents
+    pub admission_token: Option<String>,
+    /// Over TLS, the name the certificate of an admin client has to be issued
+    /// to for commands that change anything. Unset, any host in the hostsfile's will do
+    pub admin_name: Option<String>,
+}
+
+impl Config {
+    pub fn new(hostsfile: impl Into<PathBuf>) -> Self {
+        Self {
+            hostsfile: hostsfile.into(),
+            crash_delay: None,
+            tls: None,
+            metrics: None,
+            membership: Membership::default(),
+            election: Election::default(),
+            codec: Codec::default(),
+            clock: Arc::new(SystemClock),
+            multicast: None,
+            admission_token: None,
+            admin_name: None,
+        }
+    }
+}
+
+/// A view as subscribers see it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ViewChange {
+    pub view_id: ViewId,
+    pub members: HashSet<PeerId>,
+    pub leader: PeerId,
+}
+
+/// An application message multicast to a view we were in
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Delivery {
+    pub view_id: ViewId,
+    pub from: PeerId,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Watch {
+    current: Option<ViewChange>,
+    subscribers: Vec<Sender<ViewChange>>,
+    receivers: Vec<Sender<Delivery>>,
+    sequenced: Vec<Sender<Ordered>>,
+    causal: Vec<Sender<Causal>>,
+    locks: Vec<Sender<BTreeMap<String, PeerId>>>,
+}
+
+/// The latest view plus everyone who wants to hear about the next ones,
+/// and about the messages multicast in them.
+/// Clones share the same views, `Data` installs into it.
+#[derive(Clone, Default)]
+pub struct Views(Arc<Mutex<Watch>>);
+
+impl Views {
+    pub fn current(&self) -> Option<ViewChange> {
+        self.0.lock().unwrap().current.clone()
+    }
+
+    /// Every view installed from now on, oldest first
+    pub fn subscribe(&self) -> Receiver<ViewChange> {
+        let (tx, rx) = mpsc::channel();
+        self.0.lock().unwrap().subscribers.push(tx);
+        rx
+    }
+
+    /// Every message delivered from now on. A view's messages all arrive
+    /// before the next view is installed.
+    pub fn messages(&self) -> Receiver<Delivery> {
+        let (tx, rx) = mpsc::channel();
+        self.0.lock().unwrap().receivers.push(tx);
+        rx
+    }
+
+    pub(crate) fn deliver(&self, delivery: Delivery) {
+        self.0
+            .lock()
+            .unwrap()
+            .receivers
+            .retain(|tx| tx.send(delivery.clone()).is_ok());
+    }
+
+    /// Every totally ordered message delivered from now on, in seq order
+    pub fn ordered(&self) -> Receiver<Ordered> {
+        let (tx, rx) = mpsc::channel();
+        self.0.lock().unwrap().sequenced.push(tx);
+        rx
+    }
+
+    pub(crate) fn sequence(&self, ordered: Ordered) {
+        self.0
+            .lock()
+            .unwrap()
+            .sequenced
+            .retain(|tx| tx.send(ordered.clone()).is_ok());
+    }
+
+    /// Every causally ordered message delivered from now on
+    pub fn causal(&self) -> Receiver<Causal> {
+        let (tx, rx) = mpsc::channel();
+        self.0.lock().unwrap().causal.push(tx);
+        rx
+    }
+
+    pub(crate) fn deliver_causal(&self, causal: Causal) {
+        self.0
+            .lock()
+            .unwrap()
+            .causal
+            .retain(|tx| tx.send(causal.clone()).is_ok());
+    }
+
+    /// The lock table every time a lock changes hands from now on
+    pub fn locks(&self) -> Receiver<BTreeMap<String, PeerId>> {
+        let (tx, rx) = mpsc::channel();
+        self.0.lock().unwrap().locks.push(tx);
+        rx
+    }
+
+    pub(crate) fn publish_locks(&self, held: BTreeMap<String, PeerId>) {
+        self.0
+            .lock()
+            .unwrap()
+            .locks
+            .retain(|tx| tx.send(held.clone()).is_ok());
+    }
+
+    pub(crate) fn install(&self, change: ViewChange) {
+        let mut watch = self.0.lock().unwrap();
+        // subscribers that hung up are forgotten
+        watch
+            .subscribers
+            .retain(|tx| tx.send(change.clone()).is_ok());
+        watch.current = Some(change);
+    }
+}
+
+type Runner = JoinHandle<Result<(), Failure>>;
+
+enum Request {
+    Join,
+    Leave,
+    Multicast(Vec<u8>),
+    Submit(Vec<u8>),
+    Causal(Vec<u8>),
+    Lock(String),
+    Unlock(String),
+}
+
+/// Handle on a peer running in the background. Clones control the same peer.
+#[derive(Clone)]
+pub struct Group {
+    requests: Sender<Request>,
+    // wakes the peer's poll loop up to read `requests`
+    waker: Arc<EventFd>,
+    views: Views,
+    // hangs up once every channel is up, or the peer stopped before that
+    ready: Arc<Mutex<Receiver<()>>>,
+    runner: Arc<Mutex<Option<Runner>>>,
+}
+
+impl Group {
+    /// Connects to every peer in the hostsfile on a background thread.
+    /// The peer isn't part of the group until `join`.
+    pub fn start(config: Config) -> Result<Self, Failure> {
+        let waker = EventFd::from_flags(EfdFlags::EFD_NONBLOCK)
+            .map_err(std::io::Error::from)
+            .during(Op::Poll)?;
+        let waker = Arc::new(waker);
+        let (requests, inbox) = mpsc::channel();
+        let (ready_tx, ready) = mpsc::channel();
+        let views = Views::default();
+
+        let runner = {
+            let (waker, views) = (waker.clone(), views.clone());
+            thread::spawn(move || run(config, inbox, &waker, views, ready_tx))
+        };
+        Ok(Self {
+            requests,
+            waker,
+            views,
+            ready: Arc::new(Mutex::new(ready)),
+            runner: Arc::new(Mutex::new(Some(runner))),
+        })
+    }
+
+    /// Blocks until every peer in the hostsfile is connected.
+    /// False if the peer was told to leave before that.
+    pub fn connected(&self) -> Result<bool, Failure> {
+        // a dropped sender means the peer thread is done
+        while self.ready.lock().unwrap().recv().is_ok() {}
+        if self.views.current().is_some() {
+            return Ok(true);
+        }
+        self.wait().map(|()| false)
+    }
+
+    /// Sends JOIN to the leader, as soon as every channel is up
+    pub fn join(&self) {
+        self.request(Request::Join);
+    }
+
+    /// Asks the leader to drop us from the view, the peer stops once it has
+    pub fn leave(&self) {
+        self.request(Request::Leave);
+    }
+
+    /// The latest view this peer installed
+    pub fn view(&self) -> Option<ViewChange> {
+        self.views.current()
+    }
+
+    /// Every view this peer installs from now on
+    pub fn subscribe(&self) -> Receiver<ViewChange> {
+        self.views.subscribe()
+    }
+
+    /// Sends `payload` to every member of the current view, including us.
+    /// Every member that makes it into the next view delivers it, or none do.
+    pub fn multicast(&self, payload: Vec<u8>) {
+        self.request(Request::Multicast(payload));
+    }
+
+    /// Every message this peer delivers from now on, see [`Views::messages`]
+    pub fn messages(&self) -> Receiver<Delivery> {
+        self.views.messages()
+    }
+
+    /// Has the leader number `payload`, every member delivers it in that order
+    pub fn submit(&self, payload: Vec<u8>) {
+        self.request(Request::Submit(payload));
+    }
+
+    /// Every totally ordered message this peer delivers from now on.
+    /// A member that joins late gets the ones before it too.
+    pub fn ordered(&self) -> Receiver<Ordered> {
+        self.views.ordered()
+    }
+
+    /// Sends `payload` to the current view. Every member delivers it after
+    /// anything we had delivered from the view before sending it.
+    pub fn send_causal(&self, payload: Vec<u8>) {
+        self.request(Request::Causal(payload));
+    }
+
+    /// Every causally ordered message this peer delivers from now on
+    pub fn causal(&self) -> Receiver<Causal> {
+        self.views.causal()
+    }
+
+    /// Asks the leader for the named lock. It's ours once [`Group::locks`]
+    /// shows us holding it, which may wait until whoever has it lets go.
+    pub fn lock(&self, name: impl Into<String>) {
+        self.request(Request::Lock(name.into()));
+    }
+
+    /// Gives a lock we hold back. Leaving the view does that too.
+    pub fn unlock(&self, name: impl Into<String>) {
+        self.request(Request::Unlock(name.into()));
+    }
+
+    /// The lock table, by holder, every time a lock changes hands from now on
+    pub fn locks(&self) -> Receiver<BTreeMap<String, PeerId>> {
+        self.views.locks()
+    }
+
+    /// Blocks until the peer has left the group or failed.
+    /// Only the first caller gets the outcome, later ones get Ok.
+    pub fn wait(&self) -> Result<(), Failure> {
+        match self.runner.lock().unwrap().take() {
+            Some(runner) => runner.join().expect("peer thread panicked"),
+            None => Ok(()),
+        }
+    }
+
+    fn request(&self, request: Request) {
+        // if the peer already stopped there's nobody to tell
+        if self.requests.send(request).is_ok() {
+            let _ = self.waker.write(1);
+        }
+    }
+}
+
+fn run(
+    config: Config,
+    requests: Receiver<Request>,
+    waker: &EventFd,
+    views: Views,
+    ready: Sender<()>,
+) -> Result<(), Failure> {
+    let tls = config.tls.as_ref();
+    let peer_list = admin::load_or_admit(
+        config.hostsfile.clone(),
+        config.admission_token.as_deref(),
+        tls,
+    )?;
+    let metrics = Metrics::default();
+    if let Some(addr) = &config.metrics {
+        metrics.serve(addr)?;
+    }
+
+    let listener = bind_listener(peer_list.hostname())?;
+    listener
+        .set_nonblocking(true)
+        .during(Op::Accept)
+        .map_err(|f| f.addr(peer_list.hostname()))?;
+    let mut outgoing_channels = make_channels(&peer_list, tls)?;
+
+    // a JOIN asked for this early has to wait until everyone's connected
+    let mut join_requested = false;
+    let mut incoming_channels = HashMap::new();
+    while incoming_channels.len() < peer_list.len() {
+        let mut poll_fds = [
+            PollFd::new(listener.as_fd(), PollFlags::POLLIN),
+            PollFd::new(waker.as_fd(), PollFlags::POLLIN),
+        ];
+        match poll(&mut poll_fds, PollTimeout::NONE) {
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(e) => return Err(Failure::new(Op::Poll, Reasons::IO(e.into()))),
+        }
+        let _ = waker.read();
+        for request in requests.try_iter() {
+            match request {
+                Request::Join => join_requested = true,
+                Request::Leave => return Ok(()),
+                // no view to send in before everyone's connected
+                Request::Multicast(_)
+                | Request::Submit(_)
+                | Request::Causal(_)
+                | Request::Lock(_)
+                | Request::Unlock(_) => {}
+            }
+        }
+        while let Ok((sock, addr)) = listener.accept() {
+            let chan = Channel::accept(sock, tls).map_err(|f| f.addr(addr))?;
+            incoming_channels.insert(chan.as_fd().as_raw_fd(), Inbound::from(chan));
+        }
+    }
+
+    // everyone's dialed everyone by now and reads from here on, so our HELLO
+    // (and over TLS the handshake before it) can go out
+    for (&id, chan) in outgoing_channels.iter_mut() {
+        wire::say_hello(chan, peer_list.id(), config.codec).map_err(|f| f.peer(id))?;
+    }
+
+    let mut alarms = Alarms::new()?;
+    let mut data = Data::new(peer_list, &config, metrics, views, &mut alarms)?;
+    drop(ready);
+    // outgoing channels whose HELLO hasn't been answered, and what's arrived on them
+    let mut unanswered: HashMap<PeerId, FrameReader> = outgoing_channels
+        .keys()
+        .map(|&id| (id, FrameReader::default()))
+        .collect();
+    if join_requested {
+        data.ask_to_join(&mut outgoing_channels)?;
+    }
+
+    loop {
+        if data.has_left() {
+            data.shutdown(&mut alarms);
+            return Ok(());
+        }
+
+        // channels get read mutably (tls state) so the fds can't outlive the poll
+        let mut poll_fds = vec![
+            PollFd::new(listener.as_fd(), PollFlags::POLLIN),
+            PollFd::new(waker.as_fd(), PollFlags::POLLIN),
+        ];
+        poll_fds.extend(incoming_channels.values().map(|s| {
+            // replies a full socket didn't take go out once it's writable
+            let flags = match s.chan.wants_write() {
+                true => PollFlags::POLLIN | PollFlags::POLLOUT,
+                false => PollFlags::POLLIN,
+            };
+            PollFd::new(s.chan.as_fd(), flags)
+        }));
+        // outgoing channels are only read for the answer to our HELLO
+        poll_fds.extend(outgoing_channels.iter().filter_map(|(id, chan)| {
+            let mut flags = PollFlags::empty();
+            flags.set(PollFlags::POLLIN, unanswered.contains_key(id));
+            flags.set(PollFlags::POLLOUT, chan.wants_write());
+            (!flags.is_empty()).then(|| PollFd::new(chan.as_fd(), flags))
+        }));
+        // heartbeat sockets plus the heartbeat, failure check and crash timers.
+        // sockets go first so beats that queued up while we stalled count before a check
+        poll_fds.extend(
+            data.heartbeat_fds()
+                .into_iter()
+                .chain(alarms.fds())
+                .map(|fd| PollFd::new(fd, PollFlags::POLLIN)),
+        );
+        match poll(&mut poll_fds, PollTimeout::NONE) {
+            Ok(_) => {}
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(Failure::new(Op::Poll, Reasons::IO(e.into()))),
+        }
+        let ready: Vec<RawFd> = poll_fds
+            .iter()
+            .filter(|pfd| {
+                pfd.revents()
+                    .unwrap_or(PollFlags::empty())
+                    .contains(PollFlags::POLLIN)
+            })
+            .map(|pfd| pfd.as_fd().as_raw_fd())
+            .collect();
+        // a socket that failed shows up here too, the write tells us how
+        let writable: Vec<RawFd> = poll_fds
+            .iter()
+            .filter(|pfd| {
+                pfd.revents()
+                    .unwrap_or(PollFlags::empty())
+                    .intersects(PollFlags::POLLOUT | PollFlags::POLLERR | PollFlags::POLLHUP)
+            })
+            .map(|pfd| pfd.as_fd().as_raw_fd())
+            .collect();
+        drop(poll_fds);
+
+        for fd in writable {
+            let outgoing = outgoing_channels
+                .iter()
+                .find(|(_, chan)| chan.as_fd().as_raw_fd() == fd)
+                .map(|(&id, _)| id);
+            if let Some(id) = outgoing {
+                // dialed again at the next failure check if it comes back
+                if let Err(e) = outgoing_channels.get_mut(&id).unwrap().flush() {
+                    data.log(format_args!("dropping channel to peer {id}: {e}"));
+                    outgoing_channels.remove(&id);
+                    unanswered.remove(&id);
+                }
+                continue;
+            }
+            let Some(inbound) = incoming_channels.get_mut(&fd) else {
+                continue;
+            };
+            if let Err(e) = inbound.chan.flush() {
+                if !inbound.admin {
+                    data.log(format_args!("dropping channel from {}: {e}", inbound.who()));
+                }
+                incoming_channels.remove(&fd);
+            }
+        }
+
+        let mut message_queue = Vec::new();
+        for fd in ready {
+            if fd == waker.as_fd().as_raw_fd() {
+                let _ = waker.read();
+                for request in requests.try_iter() {
+                    match request {
+                        Request::Join => data.ask_to_join(&mut outgoing_channels)?,
+                        Request::Leave => data.leave(&mut outgoing_channels),
+                        Request::Multicast(payload) => {
+                            data.multicast(payload, &mut outgoing_channels)
+                        }
+                        Request::Submit(payload) => data.submit(payload, &mut outgoing_channels),
+                        Request::Causal(payload) => {
+                            data.send_causal(payload, &mut outgoing_channels)
+                        }
+                        Request::Lock(name) => data.lock(&name, true, &mut outgoing_channels),
+                        Request::Unlock(name) => data.lock(&name, false, &mut outgoing_channels),
+                    }
+                }
+                continue;
+            }
+            if fd == listener.as_raw_fd() {
+                accept_admins(&listener, tls, &data, &mut incoming_channels);
+                continue;
+            }
+            let answering = unanswered.keys().copied().find(|id| {
+                outgoing_channels
+                    .get(id)
+                    .is_some_and(|c| c.as_fd().as_raw_fd() == fd)
+            });
+            if let Some(id) = answering {
+                let chan = outgoing_channels.get_mut(&id).unwrap();
+                if let Some(answer) = read_answer(chan, unanswered.get_mut(&id).unwrap()) {
+                    unanswered.remove(&id);
+                    data.answered(id, answer, &mut outgoing_channels);
+                }
+                continue;
+            }
+            if let Some(tick) = alarms.fired(fd) {
+                data.on_tick(tick, &mut alarms);
+                continue;
+            }
+            let Some(inbound) = incoming_channels.get_mut(&fd) else {
+                data.recv_heartbeats(fd);
+                continue;
+            };
+            let mut admin_queue = Vec::new();
+            let dropped = read_letters(inbound, &mut data, &mut message_queue, &mut admin_queue);
+            let trusted = inbound
+                .chan
+                .admin_trusted(data.peer_list(), config.admin_name.as_deref());
+            for letter in admin_queue {
+                let version = inbound.version.unwrap_or(wire::MIN_VERSION);
+                data.answer_admin(
+                    &letter,
+                    &mut inbound.chan,
+                    version,
+                    trusted,
+                    &mut outgoing_channels,
+                );
+            }
+            if let Some(reason) = dropped {
+                if !inbound.admin {
+                    data.log(format_args!(
+                        "dropping channel from {}: {reason}",
+                        inbound.who()
+                    ));
+                }
+                incoming_channels.remove(&fd);
+            }
+        }
+
+        for letter in message_queue {
+            data.recv_message(&letter);
+        }
+
+        // send out any reqs we may need to take care of
+        data.proceed_reqs(&mut outgoing_channels)?;
+
+        // if we have any satisfied OKs then send a newview
+        data.flush_instructions(&mut outgoing_channels, &mut alarms)?;
+        // channels to removed hosts were closed before they answered
+        unanswered.retain(|id, _| outgoing_channels.contains_key(id));
+
+        // hosts the hostsfile added since we started, ones that aren't
+        // up yet get tried again after the next failure check
+        for (id, name) in data.to_dial(&outgoing_channels) {
+            let own_id = data.peer_list().id();
+            let dialed = dial(&name, tls).and_then(|mut chan| {
+                wire::say_hello(&mut chan, own_id, config.codec).map(|_| chan)
+            });
+            if let Ok(chan) = dialed {
+                outgoing_channels.insert(id, chan);
+                unanswered.insert(id, FrameReader::default());
+                data.dialed(id);
+            }
+        }
+    }
+}
+
+// everyone connecting after startup is an admin client (or a peer that restarted)
+fn accept_admins(
+    listener: &TcpListener,
+    tls: Option<&TlsConfig>,
+    data: &Data,
+    incoming_channels: &mut HashMap<RawFd, Inbound>,
+) {
+    while let Ok((sock, addr)) = listener.accept() {
+        match Channel::accept(sock, tls) {
+            Ok(chan) => {
+                incoming_channels.insert(chan.as_fd().as_raw_fd(), Inbound::from(chan));
+            }
+            Err(failure) => data.log(failure.addr(addr)),
+        }
+    }
+}
+
+/// Reads what's arrived on an outgoing channel since we said HELLO on it,
+/// returns the answer once it's all there
+fn read_answer(chan: &mut Channel, frames: &mut FrameReader) -> Option<Result<u16, Failure>> {
+    let drained = frames.fill(chan);
+    let hung_up = |e: std::io::Error| Some(Err(Failure::new(Op::Handshake, Reasons::IO(e))));
+    match (frames.next_frame(), drained) {
+        (Some(Frame::Whole(answer)), _) => Some(wire::hear_answer(&answer)),
+        (Some(Frame::Oversized(_)), _) => hung_up(std::io::ErrorKind::InvalidData.into()),
+        (None, Drained::Open) => None,
+        (None, Drained::Closed) => hung_up(std::io::ErrorKind::UnexpectedEof.into()),
+        (None, Drained::Failed(e)) => hung_up(e),
+    }
+}
+
+/// Drains whatever an incoming channel has buffered into `message_queue`,
+/// or `admin_queue` for letters from admin clients. The first frame has to be
+/// a HELLO, which gets answered right away.
+/// Bad frames are reported and skipped, returns why the channel should be
+/// dropped if it can't be read from anymore.
+fn read_letters(
+    inbound: &mut Inbound,
+    data: &mut Data,
+    message_queue: &mut Vec<Letter>,
+    admin_queue: &mut Vec<Letter>,
+) -> Option<String> {
+    let state = inbound.frames.fill(&mut inbound.chan);
+
+    while let Some(frame) = inbound.frames.next_frame() {
+        let body = match frame {
+            Frame::Whole(body) => body,
+            Frame::Oversized(len) => {
+                return Some(format!(
+                    "{len} byte frame is over the {MAX_FRAME} byte limit"
+                ))
+            }
+        };
+        let parcel = match wire::decode(&body) {
+            Ok(parcel) => parcel,
+            Err(e) if inbound.version.is_none() => return Some(format!("bad HELLO: {e}")),
+            Err(e) => {
+                data.log(format_args!("skipping malformed letter: {e}"));
+                continue;
+            }
+        };
+        let letter = match (parcel, inbound.version) {
+            (Parcel::Letter(letter), Some(_)) => letter,
+            (Parcel::Hello(hello), None) => {
+                inbound.admin = hello.peer_id == ADMIN_ID;
+                match data.answer_hello(&hello, &mut inbound.chan) {
+                    Some(version) => inbound.version = Some(version),
+                    None => return Some("no wire version in common".to_string()),
+                }
+                continue;
+            }
+            (_, None) => return Some("didn't open with a HELLO".to_string()),
+            (parcel, Some(_)) => {
+                data.log(format_args!("skipping unexpected {parcel:?}"));
+                continue;
+            }
+        };
+
+        if letter.from_whom() == ADMIN_ID {
+            inbound.admin = true;
+            admin_queue.push(letter);
+            continue;
+        }
+
+        // over tls the letter has to come from whoever the certificate says
+        if inbound.chan.is_tls()
+            && inbound.chan.peer_identity(data.peer_list()) != Some(letter.from_whom())
+        {
+            data.log(format_args!(
+                "dropping letter claiming to be from {} over unverified channel",
+                letter.from_whom()
+            ));
+            continue;
+        }
+        inbound.peer = Some(letter.from_whom());
+        message_queue.push(letter);
+    }
+
+    match state {
+        Drained::Open => None,
+        Drained::Closed => Some("connection closed".to_string()),
+        Drained::Failed(e) => Some(e.to_string()),
+    }
+}
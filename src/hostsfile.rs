@@ -1,140 +1,592 @@
-use nix::poll::{self, PollFd, PollFlags, PollTimeout};
-
-use crate::{failures::Reasons, socketry::attempt_op, state::PeerId, Letter, Message};
+use crate::{
+    failures::{During, Failure, Op, Reasons},
+    metrics::Metrics,
+    socketry::{
+        attempt_op,
+        wire::{self, Codec, Parcel},
+    },
+    state::PeerId,
+    Letter,
+};
+use nix::sys::socket::{
+    bind, setsockopt, socket, sockopt::ReuseAddr, AddressFamily, SockFlag, SockType, SockaddrIn,
+};
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashSet},
     fs::File,
     io::Read,
-    net::UdpSocket,
-    os::fd::{AsFd, AsRawFd},
-    path::PathBuf,
+    net::{Ipv4Addr, SocketAddrV4, UdpSocket},
+    os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd},
+    path::{Path, PathBuf},
+    time::SystemTime,
 };
 
-pub struct Broadcaster(pub Vec<(String, UdpSocket)>, pub Letter);
+/// Where heartbeats and their ACKs go
+pub enum Broadcaster {
+    // a socket per peer, each on its own port
+    Unicast(Vec<(PeerId, String, UdpSocket)>, Metrics),
+    // one socket in a multicast group every peer joined, a beat is sent once.
+    // ACKs are unicast to the peer's address on the group's port
+    Multicast {
+        group: SocketAddrV4,
+        peers: Vec<(PeerId, String)>,
+        sock: UdpSocket,
+        metrics: Metrics,
+    },
+}
+
 impl Broadcaster {
-    fn new(peer_list: &PeerList) -> Result<Self, Reasons> {
-        let mut heart_port = 6790;
+    fn new(peer_list: &PeerList, metrics: Metrics) -> Result<Self, Failure> {
         let mut scks = Vec::new();
-        for (_, name) in peer_list.ids_and_names() {
-            let sock = attempt_op(
-                UdpSocket::bind,
-                peer_list.hostname(),
-                Some(&heart_port.to_string()),
-            )?;
-            sock.set_nonblocking(true).map_err(Reasons::IO)?;
-            scks.push((format!("{}:{}", name, heart_port), sock));
-            heart_port += 1;
+        for (id, name, ours, theirs) in peer_list.heart_ports() {
+            scks.push(bind_heart(peer_list, id, name, ours, theirs)?);
         }
-        let letter = (peer_list.id(), Message::HEARTBEAT).into();
-        Ok(Self(scks, letter))
+        Ok(Self::Unicast(scks, metrics))
     }
 
-    pub fn beat(&self) {
-        let buf = bincode::serialize(&self.1).unwrap();
-        let mut poll_fds: Vec<PollFd> = self
-            .0
-            .iter()
-            .map(|s| PollFd::new(s.1.as_fd(), PollFlags::POLLOUT))
+    /// Starts sending to the peers a reloaded hostsfile added, and stops
+    /// sending to the ones it removed
+    pub fn update(&mut self, peer_list: &PeerList) -> Result<(), Failure> {
+        match self {
+            Self::Unicast(scks, _) => {
+                scks.retain(|(id, _, _)| peer_list.name(*id).is_some());
+                for (id, name, ours, theirs) in peer_list.heart_ports() {
+                    if !scks.iter().any(|(known, _, _)| *known == id) {
+                        scks.push(bind_heart(peer_list, id, name, ours, theirs)?);
+                    }
+                }
+            }
+            Self::Multicast { group, peers, .. } => {
+                *peers = peer_list
+                    .ids_and_names()
+                    .map(|(id, name)| (id, format!("{name}:{}", group.port())))
+                    .collect();
+            }
+        }
+        Ok(())
+    }
+
+    fn multicast(
+        peer_list: &PeerList,
+        group: SocketAddrV4,
+        metrics: Metrics,
+    ) -> Result<Self, Failure> {
+        let sock = reusable_socket(group.port())
+            .and_then(|sock| {
+                sock.join_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED)?;
+                // peers sharing a host hear each other over loopback
+                sock.set_multicast_loop_v4(true)?;
+                sock.set_nonblocking(true)?;
+                Ok(sock)
+            })
+            .during(Op::Heartbeat)
+            .map_err(|f| f.addr(group))?;
+        let peers = peer_list
+            .ids_and_names()
+            .map(|(id, name)| (id, format!("{name}:{}", group.port())))
             .collect();
+        Ok(Self::Multicast {
+            group,
+            peers,
+            sock,
+            metrics,
+        })
+    }
 
-        let Ok(events) = poll::poll(&mut poll_fds, PollTimeout::NONE) else {
-            return;
-        };
-        if events == 0 {
-            return;
+    /// Sends one HEARTBEAT to every peer. UDP sockets are basically always
+    /// writable, a beat that would block is just skipped.
+    /// Datagrams go out at the oldest wire version that has them, always bincode, so any compatible peer can read them.
+    pub fn beat(&self, letter: &Letter) {
+        let buf =
+            wire::encode_letter(wire::since(letter.message()), Codec::Bincode, letter).unwrap();
+        match self {
+            Self::Unicast(scks, metrics) => {
+                for (id, addr, sock) in scks {
+                    if sock.send_to(&buf, addr).is_ok() {
+                        metrics.heartbeat_sent(*id);
+                    }
+                }
+            }
+            Self::Multicast {
+                group,
+                peers,
+                sock,
+                metrics,
+            } => {
+                if sock.send_to(&buf, group).is_ok() {
+                    for (id, _) in peers {
+                        metrics.heartbeat_sent(*id);
+                    }
+                }
+            }
         }
+    }
 
-        for pfd in poll_fds.iter().filter(|pfd| {
-            pfd.revents()
-                .unwrap_or(PollFlags::empty())
-                .contains(PollFlags::POLLOUT)
-        }) {
-            let Some(sock) = self
-                .0
+    /// Sends a datagram to just one peer, dropped like a beat if it would block
+    pub fn send_to(&self, to: PeerId, letter: &Letter) {
+        let buf =
+            wire::encode_letter(wire::since(letter.message()), Codec::Bincode, letter).unwrap();
+        let dest = match self {
+            Self::Unicast(scks, _) => scks
+                .iter()
+                .find(|(id, _, _)| *id == to)
+                .map(|(_, addr, sock)| (sock, addr)),
+            Self::Multicast { peers, sock, .. } => peers
                 .iter()
-                .find(|v| v.1.as_raw_fd() == pfd.as_fd().as_raw_fd())
-            else {
-                unreachable!();
-            };
-            sock.1.send_to(&buf, &sock.0).expect("Successful send");
+                .find(|(id, _)| *id == to)
+                .map(|(_, addr)| (sock, addr)),
+        };
+        if let Some((sock, addr)) = dest {
+            let _ = sock.send_to(&buf, addr);
+        }
+    }
+
+    /// Drains every datagram waiting on the socket behind `fd`.
+    /// Empty if `fd` isn't one of ours.
+    pub fn recv(&self, fd: RawFd) -> Vec<Letter> {
+        let mut out = Vec::new();
+        let Some(sock) = self.sockets().into_iter().find(|s| s.as_raw_fd() == fd) else {
+            return out;
+        };
+        let mut buf = [0; 1024];
+        while let Ok(bytes_read) = sock.recv(&mut buf) {
+            if let Ok(Parcel::Letter(letter)) = wire::decode(&buf[..bytes_read]) {
+                out.push(letter);
+            }
         }
+        out
     }
+
+    pub fn sockets(&self) -> Vec<&UdpSocket> {
+        match self {
+            Self::Unicast(scks, _) => scks.iter().map(|(_, _, s)| s).collect(),
+            Self::Multicast { sock, .. } => vec![sock],
+        }
+    }
+
+    pub fn fds(&self) -> impl Iterator<Item = BorrowedFd<'_>> {
+        self.sockets().into_iter().map(|s| s.as_fd())
+    }
+}
+
+// a socket on this host for the beats to and from one peer, bound to `ours`
+// and sending to `theirs` on the peer's host
+fn bind_heart(
+    peer_list: &PeerList,
+    id: PeerId,
+    name: &str,
+    ours: u16,
+    theirs: u16,
+) -> Result<(PeerId, String, UdpSocket), Failure> {
+    let sock = attempt_op(
+        UdpSocket::bind,
+        Op::Heartbeat,
+        peer_list.hostname(),
+        Some(&ours.to_string()),
+    )?;
+    sock.set_nonblocking(true).during(Op::Heartbeat)?;
+    Ok((id, format!("{}:{}", name, theirs), sock))
+}
+
+// every peer on a host binds the group's port, so it has to be shared
+fn reusable_socket(port: u16) -> std::io::Result<UdpSocket> {
+    let fd = socket(
+        AddressFamily::Inet,
+        SockType::Datagram,
+        SockFlag::empty(),
+        None,
+    )?;
+    setsockopt(&fd, ReuseAddr, &true)?;
+    bind(
+        fd.as_raw_fd(),
+        &SockaddrIn::from(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)),
+    )?;
+    Ok(UdpSocket::from(fd))
 }
 
 // Decouples a stage and organizes code better
 #[derive(Clone)]
-pub struct PeerList(String, Vec<String>);
+pub struct PeerList {
+    hostname: String,
+    // a host's id is its line number, a removed host's line is left blank
+    names: Vec<String>,
+    path: PathBuf,
+    // when the file was last changed, as of the last time we read it
+    modified: Option<SystemTime>,
+    // hosts the leader admitted without a line in the file, kept next to it
+    admitted: BTreeMap<PeerId, String>,
+}
+
+// admitted hosts get ids past this, so they never land on a hostsfile line
+const FIRST_ADMITTED_ID: PeerId = 1000;
+// a host takes the beats from peer `id` on this port plus `id`
+const HEART_PORT: u16 = 6790;
+
+/// Hosts a reloaded hostsfile added or removed, by id
+#[derive(Debug, Default)]
+pub struct Reload {
+    pub added: Vec<PeerId>,
+    pub removed: Vec<PeerId>,
+}
 
 impl PeerList {
     /// Reads a hostsfile to create the structure.
     /// This host of this process must be in the hostsfile.
-    pub fn load(path: PathBuf) -> Result<Self, Reasons> {
+    pub fn load(path: PathBuf) -> Result<Self, Failure> {
+        let peer_list = Self::load_guest(path)?;
+
+        if !peer_list.is_listed() {
+            return Err(Failure::new(
+                Op::LoadHostsfile(peer_list.path),
+                Reasons::HostNotInHostsfile,
+            )
+            .addr(peer_list.hostname));
+        }
+        Ok(peer_list)
+    }
+
+    /// Reads a hostsfile that may not list this host, which then has to be
+    /// admitted by the leader before it can do anything else
+    pub fn load_guest(path: PathBuf) -> Result<Self, Failure> {
         let hostname = hostname::get()
             .expect("Hostname of image")
             .into_string()
             .unwrap();
-        let peer_names: Vec<String> = match File::open(path) {
-            Ok(mut f) => {
-                let mut out = String::new();
-                let _ = f.read_to_string(&mut out);
-                out.lines().map(str::to_string).collect()
+        Self::load_as(path, hostname)
+    }
+
+    /// Reads a hostsfile as if this were `hostname`
+    pub(crate) fn load_as(path: PathBuf, hostname: String) -> Result<Self, Failure> {
+        let (peer_names, modified) = read_names(&path)?;
+        let admitted = read_admitted(&admitted_path(&path))?;
+        Ok(Self {
+            hostname,
+            names: peer_names,
+            path,
+            modified,
+            admitted,
+        })
+    }
+
+    /// True if the hostsfile lists this host, or it was admitted before
+    pub fn is_listed(&self) -> bool {
+        self.hosts().any(|(_, name)| *name == self.hostname)
+    }
+
+    /// Every host the leader admitted so far
+    pub fn admitted(&self) -> &BTreeMap<PeerId, String> {
+        &self.admitted
+    }
+
+    /// Gives `name` the next free id, or the one it already has
+    pub fn admit(&mut self, name: &str) -> PeerId {
+        if let Some((id, _)) = self.hosts().find(|(_, known)| *known == name) {
+            return id;
+        }
+        let id = self
+            .admitted
+            .keys()
+            .last()
+            .map_or(FIRST_ADMITTED_ID + 1, |last| last + 1);
+        self.admitted.insert(id, name.to_string());
+        id
+    }
+
+    /// Takes in hosts the leader admitted, returns the ones we didn't know.
+    /// Ids the hostsfile numbers and ones we already know keep their names,
+    /// whatever the leader says
+    pub fn learn(&mut self, admitted: &BTreeMap<PeerId, String>) -> Vec<PeerId> {
+        let mut learned = Vec::new();
+        for (&id, name) in admitted {
+            if id > FIRST_ADMITTED_ID && !self.admitted.contains_key(&id) {
+                self.admitted.insert(id, name.clone());
+                learned.push(id);
             }
-            Err(e) => return Err(Reasons::IO(e)),
-        };
+        }
+        learned
+    }
 
-        if !peer_names.contains(&hostname) {
-            return Err(Reasons::HostNotInHostsfile);
+    /// Writes the admitted hosts next to the hostsfile, one `id name` per
+    /// line, so they keep their ids when the group restarts
+    pub fn persist(&self) -> Result<(), Failure> {
+        let path = admitted_path(&self.path);
+        let lines: String = self
+            .admitted
+            .iter()
+            .map(|(id, name)| format!("{id} {name}\n"))
+            .collect();
+        // everyone sharing the file writes the same thing, the rename keeps
+        // readers from seeing half of it
+        let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+        std::fs::write(&tmp, lines)
+            .and_then(|_| std::fs::rename(&tmp, &path))
+            .during(Op::LoadHostsfile(path))
+    }
+
+    /// Reads the hostsfile again if it changed since we last did. Hosts can be
+    /// appended, or removed by blanking their line, so every id stays its line
+    /// number. A file that moves a host, or drops this one, is refused and the
+    /// old list kept.
+    pub fn reload(&mut self) -> Result<Option<Reload>, Failure> {
+        let modified = std::fs::metadata(&self.path)
+            .and_then(|meta| meta.modified())
+            .ok();
+        if modified.is_none() || modified == self.modified {
+            return Ok(None);
+        }
+        let (mut names, modified) = read_names(&self.path)?;
+        // a refused edit is only reported once, the next one gets another look
+        self.modified = modified;
+        let refuse = |reason| Err(Failure::new(Op::LoadHostsfile(self.path.clone()), reason));
+        if !names.contains(&self.hostname) {
+            return refuse(Reasons::HostNotInHostsfile);
+        }
+        let mut reload = Reload::default();
+        for (index, name) in names.iter().enumerate() {
+            let id = index + 1;
+            match self.names.get(index).map(String::as_str) {
+                Some(old) if old == name => {}
+                Some(_) if name.is_empty() => reload.removed.push(id),
+                // a blank line stays blank, ids aren't reused
+                Some(_) => return refuse(Reasons::HostMoved(id)),
+                None if name.is_empty() => {}
+                None => reload.added.push(id),
+            }
+        }
+        // lines cut off the end were removed too
+        for (index, old) in self.names.iter().enumerate().skip(names.len()) {
+            if !old.is_empty() {
+                reload.removed.push(index + 1);
+            }
         }
-        Ok(Self(hostname, peer_names))
+        names.resize(names.len().max(self.names.len()), String::new());
+        self.names = names;
+        Ok(Some(reload))
+    }
+
+    /// The name on line `id`, or the admitted host with that id
+    pub fn name(&self, id: PeerId) -> Option<&str> {
+        self.hosts()
+            .find(|(known, _)| *known == id)
+            .map(|(_, name)| name.as_str())
+    }
+
+    // every host, this one included, minus the removed ones
+    fn hosts(&self) -> impl Iterator<Item = (PeerId, &String)> {
+        self.names
+            .iter()
+            .enumerate()
+            .filter(|(_, name)| !name.is_empty())
+            .map(|(index, name)| (index + 1, name))
+            .chain(self.admitted.iter().map(|(&id, name)| (id, name)))
     }
 
     /// gets name of host device as it appears on the system/hostsfile
     pub fn hostname(&self) -> &str {
-        &self.0
+        &self.hostname
     }
 
     /// true if the hostname is the first one in the file
     pub fn is_leader(&self) -> bool {
-        self.0 == self.1[0]
+        self.hostname == self.names[0]
     }
 
-    /// Retrieve the 1-based id of this process
+    /// Retrieve the 1-based id of this process, its line or the id it was admitted with
     pub fn id(&self) -> usize {
-        self.1
-            .iter()
-            .position(|name| *name == self.0)
+        self.hosts()
+            .find(|(_, name)| **name == self.hostname)
+            .map(|(id, _)| id)
             .expect("Host should be in hostsfile")
-            + 1
     }
 
-    // Returns a slice of all names excluding the host
+    // Returns a slice of all names excluding the host, and removed ones
     pub fn ids_and_names(&self) -> impl Iterator<Item = (usize, &String)> {
-        self.1.iter().enumerate().filter_map(|(index, name)| {
-            if *name != self.0 {
-                Some((index + 1, name))
-            } else {
-                None
-            }
-        })
+        self.hosts().filter(|(_, name)| **name != self.hostname)
+    }
+
+    // each peer's id and name, the port we take its beats on and the one it
+    // takes ours on. Both are worked out from the sender's id, so the two
+    // ends agree whatever else is in the file, and a removed host's ports
+    // go unused. Peers whose port would be past the last one are left out
+    fn heart_ports(&self) -> impl Iterator<Item = (PeerId, &str, u16, u16)> {
+        let port = |id: PeerId| HEART_PORT.checked_add(u16::try_from(id).ok()?);
+        let own_id = self.id();
+        self.ids_and_names()
+            .filter_map(move |(id, name)| Some((id, name.as_str(), port(id)?, port(own_id)?)))
     }
 
     /// Count of peers this process has (excludes host from list)
     pub fn len(&self) -> usize {
-        self.1.len() - 1
+        self.ids_and_names().count()
     }
 
     /// Check if the peer ids in the current membership matches the ones in the list
     pub fn members_match_hosts(&self, current_members: &HashSet<PeerId>) -> bool {
-        self.1
-            .iter()
-            .enumerate()
-            .map(|(index, _)| index + 1)
-            .collect::<HashSet<PeerId>>()
-            == *current_members
+        self.hosts().map(|(id, _)| id).collect::<HashSet<PeerId>>() == *current_members
+    }
+
+    /// bind a UDP socket to the host, or join `multicast` if set
+    pub fn make_broadcaster(
+        &self,
+        metrics: Metrics,
+        multicast: Option<SocketAddrV4>,
+    ) -> Result<Broadcaster, Failure> {
+        match multicast {
+            Some(group) => Broadcaster::multicast(self, group, metrics),
+            None => Broadcaster::new(self, metrics),
+        }
+    }
+}
+
+// every line of the hostsfile, and when it was last changed
+fn read_names(path: &PathBuf) -> Result<(Vec<String>, Option<SystemTime>), Failure> {
+    let mut f = File::open(path).during(Op::LoadHostsfile(path.clone()))?;
+    let modified = f.metadata().and_then(|meta| meta.modified()).ok();
+    let mut out = String::new();
+    let _ = f.read_to_string(&mut out);
+    Ok((out.lines().map(str::to_string).collect(), modified))
+}
+
+// the admitted hosts are kept in <hostsfile>.admitted
+fn admitted_path(hostsfile: &Path) -> PathBuf {
+    let mut name = hostsfile.file_name().unwrap_or_default().to_os_string();
+    name.push(".admitted");
+    hostsfile.with_file_name(name)
+}
+
+// every host admitted so far, none if nobody ever was
+fn read_admitted(path: &Path) -> Result<BTreeMap<PeerId, String>, Failure> {
+    let out = match std::fs::read_to_string(path) {
+        Ok(out) => out,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e).during(Op::LoadHostsfile(path.to_path_buf())),
+    };
+    Ok(out
+        .lines()
+        .filter_map(|line| {
+            let (id, name) = line.split_once(' ')?;
+            Some((id.parse().ok()?, name.to_string()))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn hostsfile(test: &str, lines: &[&str]) -> (PathBuf, PeerList) {
+        let path =
+            std::env::temp_dir().join(format!("prj3-hostsfile-{test}-{}.txt", std::process::id()));
+        std::fs::write(&path, lines.join("\n")).unwrap();
+        let peer_list = PeerList::load_as(path.clone(), "one".to_string()).unwrap();
+        (path, peer_list)
+    }
+
+    // writes `lines` over the file, making sure the change shows in its
+    // modified time even when both writes land on the same tick
+    fn rewrite(path: &Path, lines: &[&str]) {
+        let before = std::fs::metadata(path).unwrap().modified().unwrap();
+        std::fs::write(path, lines.join("\n")).unwrap();
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(before + Duration::from_secs(1))
+            .unwrap();
+    }
+
+    fn reloaded(peer_list: &mut PeerList) -> (Vec<PeerId>, Vec<PeerId>) {
+        let reload = peer_list.reload().unwrap().expect("the file changed");
+        (reload.added, reload.removed)
+    }
+
+    #[test]
+    fn does_nothing_until_the_file_changes() {
+        let (path, mut peer_list) = hostsfile("unchanged", &["one", "two"]);
+        assert!(peer_list.reload().unwrap().is_none());
+        rewrite(&path, &["one", "two"]);
+        assert_eq!(reloaded(&mut peer_list), (vec![], vec![]));
+        assert!(peer_list.reload().unwrap().is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn appended_and_blanked_lines_add_and_remove_hosts() {
+        let (path, mut peer_list) = hostsfile("edited", &["one", "two", "three"]);
+        rewrite(&path, &["one", "", "three", "four"]);
+        assert_eq!(reloaded(&mut peer_list), (vec![4], vec![2]));
+        assert_eq!(peer_list.name(2), None);
+        assert_eq!(peer_list.name(4), Some("four"));
+        // ids stay line numbers, so four kept its heartbeat port
+        assert_eq!(
+            peer_list
+                .heart_ports()
+                .map(|(id, _, ours, theirs)| (id, ours, theirs))
+                .collect::<Vec<_>>(),
+            [(3, 6793, 6791), (4, 6794, 6791)]
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn both_ends_of_a_heartbeat_agree_on_its_port() {
+        let (path, mut one) = hostsfile("heartports", &["one", "two", "three", "four"]);
+        let mut three = PeerList::load_as(path.clone(), "three".to_string()).unwrap();
+        rewrite(&path, &["one", "", "three", "four"]);
+        reloaded(&mut one);
+        reloaded(&mut three);
+        let ports = |peer_list: &PeerList| -> BTreeMap<PeerId, (u16, u16)> {
+            peer_list
+                .heart_ports()
+                .map(|(id, _, ours, theirs)| (id, (ours, theirs)))
+                .collect()
+        };
+        let (at_one, at_three) = (ports(&one), ports(&three));
+        // where one sends its beats is where three takes them, and back
+        assert_eq!(at_one[&3].1, at_three[&1].0);
+        assert_eq!(at_three[&1].1, at_one[&3].0);
+        // and a third host sends to each of them on the port it's heard on
+        assert_eq!(at_one[&4].0, at_three[&4].0);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn truncated_lines_are_removed_hosts() {
+        let (path, mut peer_list) = hostsfile("truncated", &["one", "two", "", "four"]);
+        rewrite(&path, &["one", "two"]);
+        assert_eq!(reloaded(&mut peer_list), (vec![], vec![4]));
+        // appending again doesn't reuse the ids of the lines that were cut off
+        rewrite(&path, &["one", "two", "", "", "five"]);
+        assert_eq!(reloaded(&mut peer_list), (vec![5], vec![]));
+        assert_eq!(peer_list.len(), 2);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn refuses_a_moved_host_once() {
+        let (path, mut peer_list) = hostsfile("moved", &["one", "two", "three"]);
+        rewrite(&path, &["one", "three", "two"]);
+        let refused = peer_list.reload().unwrap_err();
+        assert!(refused
+            .to_string()
+            .ends_with(&Reasons::HostMoved(2).to_string()));
+        assert_eq!(peer_list.name(2), Some("two"));
+        // the same edit isn't refused again until the file changes
+        assert!(peer_list.reload().unwrap().is_none());
+        std::fs::remove_file(path).unwrap();
     }
 
-    /// bind a UDP socket to the host
-    pub fn make_broadcaster(&self) -> Result<Broadcaster, Reasons> {
-        Broadcaster::new(self)
+    #[test]
+    fn refuses_a_file_without_this_host() {
+        let (path, mut peer_list) = hostsfile("dropped", &["one", "two"]);
+        rewrite(&path, &["", "two"]);
+        let refused = peer_list.reload().unwrap_err();
+        assert!(refused
+            .to_string()
+            .ends_with(&Reasons::HostNotInHostsfile.to_string()));
+        assert_eq!(peer_list.id(), 1);
+        std::fs::remove_file(path).unwrap();
     }
 }
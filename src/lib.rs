@@ -0,0 +1,21 @@
+//! Group membership over TCP with UDP heartbeats. The first peer in the
+//! hostsfile leads, everyone else JOINs through it and hears about every
+//! view it installs.
+//!
+//! Start a peer with [`Group::start`], wait for it to connect, then [`Group::join`]
+//! and [`Group::subscribe`] to the views as they change.
+
+pub mod admin;
+pub mod clock;
+pub mod failures;
+mod group;
+mod hostsfile;
+mod metrics;
+pub mod socketry;
+pub mod state;
+#[cfg(feature = "tokio")]
+pub mod tokio_transport;
+
+pub use group::{Config, Delivery, Group, ViewChange, Views};
+
+use state::messaging::Letter;
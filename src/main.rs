@@ -1,87 +1,289 @@
 use std::{
-    collections::HashMap,
-    io::Read,
-    os::fd::{AsFd, AsRawFd},
-    thread::sleep,
+    collections::BTreeMap,
+    fs,
+    io::{self, Read},
+    path::PathBuf,
+    process::ExitCode,
+    sync::{mpsc::Receiver, Arc},
+    thread::{self, sleep},
     time::Duration,
 };
 
-use args::Project3;
+use args::{Command, Project3, RunArgs, TlsArgs};
 use clap::Parser;
-use failures::Reasons;
-use hostsfile::PeerList;
-use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
-use socketry::{bind_listener, make_channels};
-use state::{messaging::*, Data};
+use prj3::{
+    admin,
+    clock::SystemClock,
+    failures::{During, Failure, Op},
+    socketry::{
+        framing::{Frame, FrameReader},
+        tls::TlsConfig,
+        wire,
+    },
+    state::{
+        messaging::{Causal, Ordered, RoleKind},
+        Membership, PeerId,
+    },
+    Config, Delivery, Group,
+};
 
 mod args;
-mod failures;
-mod hostsfile;
-mod socketry;
-mod state;
+mod shutdown;
 
-fn main() -> Result<(), Reasons> {
-    let args = Project3::parse();
-    let peer_list = PeerList::load(args.hostsfile)?;
+fn main() -> ExitCode {
+    let outcome = match Project3::parse().command {
+        Command::Run(args) => run(args),
+        Command::Decode { capture, datagram } => decode(capture, datagram),
+        admin_command => manage(admin_command),
+    };
+    match outcome {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(failure) => {
+            eprintln!("error: {failure}");
+            failure.exit_code()
+        }
+    }
+}
 
-    let listener = bind_listener(peer_list.hostname())?;
-    let mut outgoing_channels = make_channels(&peer_list)?;
+// the subcommands that talk to an already running peer
+fn manage(command: Command) -> Result<(), Failure> {
+    match command {
+        Command::Run(_) | Command::Decode { .. } => unreachable!(),
+        Command::Status { peer, tls } => {
+            let status = admin::query_status(&peer, load_tls(&tls)?.as_ref())?;
+            admin::print_status(&status);
+        }
+        Command::Join { peer, tls } => {
+            let status = admin::join(&peer, load_tls(&tls)?.as_ref())?;
+            if status.members.contains(&status.peer_id) {
+                println!("peer {} is in view {}", status.peer_id, status.view_id);
+            } else {
+                println!(
+                    "peer {} asked leader {} to join",
+                    status.peer_id, status.leader_id
+                );
+            }
+        }
+        Command::Leave { peer, tls } => {
+            let status = admin::leave(&peer, load_tls(&tls)?.as_ref())?;
+            println!("peer {} is leaving view {}", status.peer_id, status.view_id);
+        }
+        Command::Kick { peer, id, tls } => {
+            let status = admin::kick(&peer, id, load_tls(&tls)?.as_ref())?;
+            match status.role {
+                RoleKind::Leader => println!("leader {} will delete peer {id}", status.peer_id),
+                RoleKind::Follower => println!(
+                    "peer {} isn't the leader, ask peer {} instead",
+                    status.peer_id, status.leader_id
+                ),
+                RoleKind::Gossip => println!(
+                    "peer {} is gossiping, nobody can delete members",
+                    status.peer_id
+                ),
+            }
+        }
+        Command::Send {
+            peer,
+            text,
+            causal,
+            tls,
+        } => {
+            let status = admin::send(&peer, text.into_bytes(), causal, load_tls(&tls)?.as_ref())?;
+            if status.members.contains(&status.peer_id) {
+                println!("peer {} sent it to view {}", status.peer_id, status.view_id);
+            } else {
+                println!(
+                    "peer {} isn't in view {}, nothing was sent",
+                    status.peer_id, status.view_id
+                );
+            }
+        }
+        Command::Submit { peer, text, tls } => {
+            let status = admin::submit(&peer, text.into_bytes(), load_tls(&tls)?.as_ref())?;
+            if !status.members.contains(&status.peer_id) {
+                println!(
+                    "peer {} isn't in view {}, nothing was submitted",
+                    status.peer_id, status.view_id
+                );
+            } else if matches!(status.role, RoleKind::Gossip) {
+                println!(
+                    "peer {} is gossiping, nobody orders messages",
+                    status.peer_id
+                );
+            } else {
+                println!(
+                    "peer {} submitted it to leader {}",
+                    status.peer_id, status.leader_id
+                );
+            }
+        }
+        Command::Lock { peer, name, tls } => {
+            let status = admin::lock(&peer, name.clone(), true, load_tls(&tls)?.as_ref())?;
+            match status.locks.get(&name) {
+                _ if !status.members.contains(&status.peer_id) => println!(
+                    "peer {} isn't in view {}, it can't hold locks",
+                    status.peer_id, status.view_id
+                ),
+                Some(&holder) if holder == status.peer_id => {
+                    println!("peer {holder} already holds {name:?}")
+                }
+                Some(holder) => println!(
+                    "peer {holder} holds {name:?}, peer {} waits for it",
+                    status.peer_id
+                ),
+                None => println!(
+                    "peer {} asked leader {} for {name:?}",
+                    status.peer_id, status.leader_id
+                ),
+            }
+        }
+        Command::Unlock { peer, name, tls } => {
+            let status = admin::lock(&peer, name.clone(), false, load_tls(&tls)?.as_ref())?;
+            match status.locks.get(&name) {
+                Some(&holder) if holder == status.peer_id => println!(
+                    "peer {holder} asked leader {} to release {name:?}",
+                    status.leader_id
+                ),
+                _ => println!("peer {} doesn't hold {name:?}", status.peer_id),
+            }
+        }
+        Command::Views { peer, tls } => {
+            let views = admin::query_views(&peer, load_tls(&tls)?.as_ref())?;
+            admin::print_views(&views);
+        }
+    }
+    Ok(())
+}
 
-    let mut incoming_channels = HashMap::new();
-    while incoming_channels.len() < peer_list.len() {
-        if let Ok((sock, _)) = listener.accept() {
-            sock.set_nonblocking(true).map_err(Reasons::IO)?;
-            incoming_channels.insert(sock.as_raw_fd(), sock);
+// prints every frame in a capture, up to one that's cut off
+fn decode(capture: Option<PathBuf>, datagram: bool) -> Result<(), Failure> {
+    let bytes = match &capture {
+        Some(path) => fs::read(path),
+        None => {
+            let mut buf = Vec::new();
+            io::stdin().read_to_end(&mut buf).map(|_| buf)
         }
     }
-    let mut poll_fds: Vec<_> = incoming_channels
-        .iter()
-        .map(|(_, s)| PollFd::new(s.as_fd(), PollFlags::POLLIN))
-        .collect();
+    .during(Op::LoadCapture(capture.unwrap_or_else(|| "stdin".into())))?;
 
+    if datagram {
+        println!("{}", wire::describe(&bytes));
+        return Ok(());
+    }
+    let mut frames = FrameReader::default();
+    frames.push(&bytes);
+    let mut count = 0;
+    while let Some(frame) = frames.next_frame() {
+        match frame {
+            Frame::Whole(body) => println!("#{count} {}\n", wire::describe(&body)),
+            Frame::Oversized(len) => {
+                println!("#{count} claims to be {len} bytes, the rest can't be framed");
+                break;
+            }
+        }
+        count += 1;
+    }
+    Ok(())
+}
+
+fn load_tls(args: &TlsArgs) -> Result<Option<TlsConfig>, Failure> {
+    args.files().map(|f| TlsConfig::load(&f)).transpose()
+}
+
+fn run(args: RunArgs) -> Result<(), Failure> {
     let start_delay = Duration::from_secs(args.start_delay.unwrap_or(0));
-    let mut data = Data::new(peer_list, args.crash_delay);
+    let config = Config {
+        hostsfile: args.hostsfile,
+        crash_delay: args.crash_delay.map(Duration::from_secs),
+        tls: load_tls(&args.tls)?,
+        metrics: args.metrics,
+        codec: args.codec,
+        clock: Arc::new(SystemClock),
+        multicast: args.multicast,
+        admission_token: args.admission_token,
+        admin_name: args.admin_name,
+        election: args.election,
+        membership: if args.quorum {
+            Membership::Majority
+        } else if args.gossip {
+            Membership::Gossip
+        } else if args.raft {
+            Membership::Raft
+        } else {
+            Membership::Unanimous
+        },
+    };
+    #[cfg(feature = "tokio")]
+    if args.tokio {
+        let views = prj3::Views::default();
+        print_deliveries(views.messages(), views.ordered(), views.causal());
+        print_locks(views.locks());
+        return prj3::tokio_transport::block_on(config, start_delay, views);
+    }
 
+    shutdown::block()?;
+    let group = Group::start(config)?;
+    print_deliveries(group.messages(), group.ordered(), group.causal());
+    print_locks(group.locks());
+    let leaver = group.clone();
+    thread::spawn(move || {
+        if shutdown::wait().is_ok() {
+            leaver.leave();
+        }
+    });
+
+    if !group.connected()? {
+        return Ok(());
+    }
     // i am a great big fool and need to read the project specs more
     sleep(start_delay);
-    data.ask_to_join(&mut outgoing_channels)?;
-
-    loop {
-        // Check heartbeats
-        //println!("Validating peers");
-        data.validate_peers()?;
-
-        if let Ok(events) =
-            poll(&mut poll_fds, PollTimeout::from(10u16)).map_err(|v| Reasons::IO(v.into()))
-        {
-            if events > 0 {
-                let mut message_queue = Vec::new();
-                for pfd in poll_fds.iter().filter(|pfd| {
-                    pfd.revents()
-                        .unwrap_or(PollFlags::empty())
-                        .contains(PollFlags::POLLIN)
-                }) {
-                    let mut chan = incoming_channels
-                        .get(&pfd.as_fd().as_raw_fd())
-                        .expect("Existent channel");
-                    let mut buffer = [0; 1024];
-                    let bytes_read = chan.read(&mut buffer).map_err(Reasons::IO)?;
-                    let letter: Letter = bincode::deserialize(&buffer[..bytes_read])
-                        .map_err(|_| Reasons::BadMessage)?;
-                    message_queue.push(letter);
-                }
+    group.join();
+    group.wait()
+}
 
-                for letter in message_queue {
-                    data.recv_message(&letter);
-                }
-            }
+// prints every multicast, ordered and causal message delivered, next to the view lines
+fn print_deliveries(
+    messages: Receiver<Delivery>,
+    ordered: Receiver<Ordered>,
+    causal: Receiver<Causal>,
+) {
+    thread::spawn(move || {
+        for delivery in messages {
+            eprintln!(
+                "{{view_id: {}, from: {}, delivered: {:?}}}",
+                delivery.view_id,
+                delivery.from,
+                String::from_utf8_lossy(&delivery.payload)
+            );
         }
-        // send out any reqs we may need to take care of
-        //println!("Proceeding reqs");
-        data.proceed_reqs(&mut outgoing_channels)?;
+    });
+    thread::spawn(move || {
+        for ordered in ordered {
+            eprintln!(
+                "{{seq: {}, from: {}, ordered: {:?}}}",
+                ordered.seq,
+                ordered.from,
+                String::from_utf8_lossy(&ordered.payload)
+            );
+        }
+    });
+    thread::spawn(move || {
+        for causal in causal {
+            eprintln!(
+                "{{from: {}, clock: {:?}, causal: {:?}}}",
+                causal.from,
+                causal.clock,
+                String::from_utf8_lossy(&causal.payload)
+            );
+        }
+    });
+}
 
-        // if we have any satisfied OKs then send a newview
-        //println!("Flushing instructions");
-        data.flush_instructions(&mut outgoing_channels)?;
-    }
+// prints the lock table each time a lock changes hands
+fn print_locks(locks: Receiver<BTreeMap<String, PeerId>>) {
+    thread::spawn(move || {
+        for held in locks {
+            eprintln!("{{locks: {held:?}}}");
+        }
+    });
 }
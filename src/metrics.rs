@@ -0,0 +1,239 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::TcpListener,
+    sync::{Arc, Mutex},
+    thread::spawn,
+    time::Duration,
+};
+
+use crate::{
+    failures::{During, Failure, Op},
+    state::{PeerId, ViewId},
+};
+
+const INTER_ARRIVAL_BUCKETS: [f64; 8] = [0.5, 1.0, 1.5, 2.0, 2.5, 3.0, 4.0, 5.0];
+const VIEW_CHANGE_BUCKETS: [f64; 8] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+// how long a scraper gets to send its request line
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// cumulative prometheus histogram, le buckets plus sum and count
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: Duration) {
+        let secs = value.as_secs_f64();
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter_mut()) {
+            if secs <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str) {
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {count}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", self.count);
+        let _ = writeln!(out, "{name}_sum {}", self.sum);
+        let _ = writeln!(out, "{name}_count {}", self.count);
+    }
+}
+
+struct Registry {
+    view_id: ViewId,
+    members: usize,
+    pending_requests: usize,
+    blocked: bool,
+    heartbeats_sent: BTreeMap<PeerId, u64>,
+    heartbeats_received: BTreeMap<PeerId, u64>,
+    suspicions: BTreeMap<PeerId, u64>,
+    inter_arrival: Histogram,
+    view_change: Histogram,
+}
+
+/// Counters and gauges about this process, shared between the main loop
+/// and the heartbeat threads. Cheap to clone, always on; the HTTP endpoint
+/// that exposes them is optional.
+#[derive(Clone)]
+pub struct Metrics(Arc<Mutex<Registry>>);
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(Registry {
+            view_id: 0,
+            members: 0,
+            pending_requests: 0,
+            blocked: false,
+            heartbeats_sent: BTreeMap::new(),
+            heartbeats_received: BTreeMap::new(),
+            suspicions: BTreeMap::new(),
+            inter_arrival: Histogram::new(&INTER_ARRIVAL_BUCKETS),
+            view_change: Histogram::new(&VIEW_CHANGE_BUCKETS),
+        })))
+    }
+}
+
+impl Metrics {
+    fn with(&self, f: impl FnOnce(&mut Registry)) {
+        if let Ok(mut reg) = self.0.lock() {
+            f(&mut reg);
+        }
+    }
+
+    pub fn set_view(&self, view_id: ViewId, members: usize) {
+        self.with(|r| {
+            r.view_id = view_id;
+            r.members = members;
+        });
+    }
+
+    pub fn set_pending_requests(&self, pending: usize) {
+        self.with(|r| r.pending_requests = pending);
+    }
+
+    pub fn set_blocked(&self, blocked: bool) {
+        self.with(|r| r.blocked = blocked);
+    }
+
+    pub fn heartbeat_sent(&self, to: PeerId) {
+        self.with(|r| *r.heartbeats_sent.entry(to).or_default() += 1);
+    }
+
+    /// `since_last` is None for the first heartbeat we hear from a peer
+    pub fn heartbeat_received(&self, from: PeerId, since_last: Option<Duration>) {
+        self.with(|r| {
+            *r.heartbeats_received.entry(from).or_default() += 1;
+            if let Some(gap) = since_last {
+                r.inter_arrival.observe(gap);
+            }
+        });
+    }
+
+    pub fn suspected(&self, peer: PeerId) {
+        self.with(|r| *r.suspicions.entry(peer).or_default() += 1);
+    }
+
+    pub fn view_changed(&self, took: Duration) {
+        self.with(|r| r.view_change.observe(took));
+    }
+
+    /// Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.with(|r| {
+            let _ = writeln!(out, "# HELP prj3_view_id Current view id.");
+            let _ = writeln!(out, "# TYPE prj3_view_id gauge");
+            let _ = writeln!(out, "prj3_view_id {}", r.view_id);
+
+            let _ = writeln!(out, "# HELP prj3_members Members in the current view.");
+            let _ = writeln!(out, "# TYPE prj3_members gauge");
+            let _ = writeln!(out, "prj3_members {}", r.members);
+
+            let _ = writeln!(
+                out,
+                "# HELP prj3_pending_requests Requests queued by the leader, 0 on followers."
+            );
+            let _ = writeln!(out, "# TYPE prj3_pending_requests gauge");
+            let _ = writeln!(out, "prj3_pending_requests {}", r.pending_requests);
+
+            let _ = writeln!(
+                out,
+                "# HELP prj3_blocked 1 while a majority of the view is unreachable."
+            );
+            let _ = writeln!(out, "# TYPE prj3_blocked gauge");
+            let _ = writeln!(out, "prj3_blocked {}", u8::from(r.blocked));
+
+            render_per_peer(
+                &mut out,
+                "prj3_heartbeats_sent_total",
+                "Heartbeats sent to each peer.",
+                &r.heartbeats_sent,
+            );
+            render_per_peer(
+                &mut out,
+                "prj3_heartbeats_received_total",
+                "Heartbeats received from each peer.",
+                &r.heartbeats_received,
+            );
+            render_per_peer(
+                &mut out,
+                "prj3_suspicions_total",
+                "Times the failure detector declared a peer unreachable.",
+                &r.suspicions,
+            );
+
+            let _ = writeln!(
+                out,
+                "# HELP prj3_heartbeat_interarrival_seconds Time between heartbeats from the same peer."
+            );
+            let _ = writeln!(out, "# TYPE prj3_heartbeat_interarrival_seconds histogram");
+            r.inter_arrival
+                .render(&mut out, "prj3_heartbeat_interarrival_seconds");
+
+            let _ = writeln!(
+                out,
+                "# HELP prj3_view_change_seconds Time from sending a REQ to installing its NEWVIEW."
+            );
+            let _ = writeln!(out, "# TYPE prj3_view_change_seconds histogram");
+            r.view_change.render(&mut out, "prj3_view_change_seconds");
+        });
+        out
+    }
+
+    /// Serves `render` over plain HTTP on `addr` from a background thread
+    pub fn serve(&self, addr: &str) -> Result<(), Failure> {
+        let listener = TcpListener::bind(addr)
+            .during(Op::Bind)
+            .map_err(|f| f.addr(addr))?;
+        let metrics = self.clone();
+        spawn(move || {
+            for mut conn in listener.incoming().flatten() {
+                // each scrape on its own thread, so a client that never sends
+                // its request only holds up itself, and only until the timeout
+                let metrics = metrics.clone();
+                spawn(move || {
+                    let _ = conn.set_read_timeout(Some(REQUEST_TIMEOUT));
+                    // only the request line matters, everything is GET /metrics
+                    let mut request_line = String::new();
+                    if BufReader::new(&conn).read_line(&mut request_line).is_err() {
+                        return;
+                    }
+                    let body = metrics.render();
+                    let _ = write!(
+                        conn,
+                        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                });
+            }
+        });
+        Ok(())
+    }
+}
+
+fn render_per_peer(out: &mut String, name: &str, help: &str, values: &BTreeMap<PeerId, u64>) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} counter");
+    for (peer, value) in values {
+        let _ = writeln!(out, "{name}{{peer=\"{peer}\"}} {value}");
+    }
+}
@@ -0,0 +1,27 @@
+use nix::sys::signal::{SigSet, Signal};
+
+use prj3::failures::{During, Failure, Op};
+
+fn signals() -> SigSet {
+    let mut set = SigSet::empty();
+    set.add(Signal::SIGTERM);
+    set.add(Signal::SIGINT);
+    set
+}
+
+/// Holds SIGTERM and SIGINT back on this thread and every thread it starts
+/// from now on, so they wait for `wait` instead of killing us mid-view.
+pub fn block() -> Result<(), Failure> {
+    signals()
+        .thread_block()
+        .map_err(std::io::Error::from)
+        .during(Op::Signals)
+}
+
+/// Sleeps until SIGTERM or SIGINT arrives
+pub fn wait() -> Result<Signal, Failure> {
+    signals()
+        .wait()
+        .map_err(std::io::Error::from)
+        .during(Op::Signals)
+}
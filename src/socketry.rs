@@ -1,29 +1,48 @@
 use std::{
     collections::HashMap,
-    net::{TcpListener, TcpStream},
+    io::{ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    os::fd::{AsFd, BorrowedFd},
     thread::sleep,
     time::Duration,
 };
 
-use crate::{failures::Reasons, hostsfile::PeerList};
+use crate::{
+    failures::{During, Failure, Op},
+    hostsfile::PeerList,
+    state::PeerId,
+};
+
+pub mod framing;
+pub mod tls;
+pub mod wire;
+
+use framing::FrameReader;
+use tls::{ClientStream, ServerStream, TlsConfig};
 
-const PORT: &'static str = "6969";
-const MAX_ATTEMPTS: i32 = 10;
-const ATTEMPT_WAIT: Duration = Duration::from_secs(5);
+pub const PORT: &str = "6969";
+pub const MAX_ATTEMPTS: i32 = 10;
+pub const ATTEMPT_WAIT: Duration = Duration::from_secs(5);
 
 // to decomplicate things
-pub fn attempt_op<Socket, F>(op: F, peer_name: &str, port: Option<&str>) -> Result<Socket, Reasons>
+pub fn attempt_op<Socket, F>(
+    op: F,
+    during: Op,
+    peer_name: &str,
+    port: Option<&str>,
+) -> Result<Socket, Failure>
 where
     F: Fn(String) -> std::io::Result<Socket>,
 {
     let mut attempts = 0;
+    let addr = format!("{}:{}", peer_name, port.unwrap_or(PORT));
 
     let sock = loop {
-        match op(format!("{}:{}", peer_name, port.unwrap_or(PORT))) {
+        match op(addr.clone()) {
             Ok(s) => break s,
             Err(e) => {
                 if attempts == MAX_ATTEMPTS {
-                    return Err(Reasons::IO(e));
+                    return Err(e).during(during).map_err(|f| f.addr(addr));
                 }
                 attempts += 1;
                 sleep(ATTEMPT_WAIT);
@@ -33,22 +52,354 @@ where
     Ok(sock)
 }
 
-fn connect_channel(to_send: &str) -> Result<TcpStream, Reasons> {
-    attempt_op(TcpStream::connect, to_send, None)
+/// A TCP connection to a peer, optionally wrapped in TLS.
+/// Reads and writes go through the TLS session when there is one.
+/// Writes never stop partway: what a nonblocking socket won't take yet is
+/// kept, plaintext channels next to the socket and TLS ones in the session,
+/// until `flush` gets it out.
+pub enum Channel {
+    Plain(TcpStream, Vec<u8>),
+    TlsClient(Box<ClientStream>),
+    TlsServer(Box<ServerStream>),
+}
+
+impl Channel {
+    /// Wraps a freshly accepted socket, setting it nonblocking for the poll loop
+    pub fn accept(sock: TcpStream, tls: Option<&TlsConfig>) -> Result<Self, Failure> {
+        sock.set_nonblocking(true).during(Op::Accept)?;
+        Ok(match tls {
+            Some(tls) => Self::TlsServer(Box::new(tls.accept(sock)?)),
+            None => Self::Plain(sock, Vec::new()),
+        })
+    }
+
+    /// Wraps a socket we dialed to `peer_name`
+    pub fn connect(
+        sock: TcpStream,
+        peer_name: &str,
+        tls: Option<&TlsConfig>,
+    ) -> Result<Self, Failure> {
+        Ok(match tls {
+            Some(tls) => Self::TlsClient(Box::new(tls.connect(peer_name, sock)?)),
+            None => Self::Plain(sock, Vec::new()),
+        })
+    }
+
+    /// The peer id proven by the client certificate on an incoming TLS channel.
+    /// Plaintext channels can't prove anything so they return None.
+    pub fn peer_identity(&self, peer_list: &PeerList) -> Option<PeerId> {
+        match self {
+            Self::TlsServer(stream) => tls::peer_identity(&stream.conn, peer_list),
+            _ => None,
+        }
+    }
+
+    /// Whether an admin client on this channel may run commands that change
+    /// anything: over TLS its certificate has to be issued to `admin_name`,
+    /// or to a host in the hostsfile if there's none. Plaintext channels
+    /// prove nothing, so anyone who can reach the port is trusted.
+    pub fn admin_trusted(&self, peer_list: &PeerList, admin_name: Option<&str>) -> bool {
+        match (self, admin_name) {
+            (Self::TlsServer(stream), Some(name)) => tls::is_issued_to(&stream.conn, name),
+            (Self::TlsServer(stream), None) => {
+                tls::peer_identity(&stream.conn, peer_list).is_some()
+            }
+            _ => true,
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        !matches!(self, Self::Plain(..))
+    }
+
+    /// True while some of what was written is still waiting for the socket
+    /// to have room, so the poll loop knows to wait for it to be writable
+    pub fn wants_write(&self) -> bool {
+        match self {
+            Self::Plain(_, pending) => !pending.is_empty(),
+            Self::TlsClient(s) => s.conn.wants_write(),
+            Self::TlsServer(s) => s.conn.wants_write(),
+        }
+    }
+
+    /// Address of the other end, for error messages
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Plain(s, _) => s.peer_addr(),
+            Self::TlsClient(s) => s.sock.peer_addr(),
+            Self::TlsServer(s) => s.sock.peer_addr(),
+        }
+        .ok()
+    }
+}
+
+/// An accepted channel plus what we've learned about it while reading
+pub struct Inbound {
+    pub chan: Channel,
+    pub frames: FrameReader,
+    // sender of the last good letter, so closed channels can be reported by id
+    pub peer: Option<PeerId>,
+    // wire version agreed on in the HELLO, None until it arrives
+    pub version: Option<u16>,
+    // admin clients come and go, their hangups aren't worth reporting
+    pub admin: bool,
+}
+
+impl Inbound {
+    /// Who's on the other end, for logging
+    pub fn who(&self) -> String {
+        match (self.peer, self.chan.peer_addr()) {
+            (Some(id), _) => format!("peer {id}"),
+            (None, Some(addr)) => addr.to_string(),
+            (None, None) => "unknown peer".to_string(),
+        }
+    }
+}
+
+impl From<Channel> for Inbound {
+    fn from(chan: Channel) -> Self {
+        Self {
+            chan,
+            frames: FrameReader::default(),
+            peer: None,
+            version: None,
+            admin: false,
+        }
+    }
+}
+
+impl Read for Channel {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Plain(s, _) => s.read(buf),
+            Self::TlsClient(s) => s.read(buf),
+            Self::TlsServer(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Channel {
+    // only buffers, `flush` does the sending
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Plain(_, pending) => pending.extend_from_slice(buf),
+            // the session holds on to plaintext until its handshake is done
+            Self::TlsClient(s) => s.conn.writer().write_all(buf)?,
+            Self::TlsServer(s) => s.conn.writer().write_all(buf)?,
+        }
+        Ok(buf.len())
+    }
+
+    /// Sends what the socket takes. One that would block isn't an error,
+    /// the rest goes once it's writable again
+    fn flush(&mut self) -> std::io::Result<()> {
+        let sent = match self {
+            Self::Plain(s, pending) => send_all(|buf| s.write(buf), pending),
+            Self::TlsClient(s) => tls::send(&mut s.conn, &mut s.sock),
+            Self::TlsServer(s) => tls::send(&mut s.conn, &mut s.sock),
+        };
+        match sent {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            sent => sent,
+        }
+    }
+}
+
+// writes out and drains `pending` until it's empty or `write` fails
+fn send_all(
+    mut write: impl FnMut(&[u8]) -> std::io::Result<usize>,
+    pending: &mut Vec<u8>,
+) -> std::io::Result<()> {
+    while !pending.is_empty() {
+        match write(pending) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                pending.drain(..n);
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+impl AsFd for Channel {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Self::Plain(s, _) => s.as_fd(),
+            Self::TlsClient(s) => s.sock.as_fd(),
+            Self::TlsServer(s) => s.sock.as_fd(),
+        }
+    }
+}
+
+// peer channels are nonblocking like the accepted ones, writes get queued
+// and the poll loop sends them and reads the answer to our HELLO
+fn connect_channel(to_send: &str, tls: Option<&TlsConfig>) -> Result<Channel, Failure> {
+    let sock = attempt_op(TcpStream::connect, Op::Connect, to_send, None)?;
+    sock.set_nonblocking(true)
+        .during(Op::Connect)
+        .map_err(|f| f.addr(to_send))?;
+    Channel::connect(sock, to_send, tls)
 }
 
 // Sets up a TCPListener to await connections from all peers
-pub fn bind_listener(hostname: &str) -> Result<TcpListener, Reasons> {
-    attempt_op(TcpListener::bind, hostname, None)
+pub fn bind_listener(hostname: &str) -> Result<TcpListener, Failure> {
+    attempt_op(TcpListener::bind, Op::Bind, hostname, None)
 }
 
 // creates a vector of listening and sending sockets
 // for each process. (connects like a spiderweb across the ring)
-pub fn make_channels(peer_list: &PeerList) -> Result<HashMap<usize, TcpStream>, Reasons> {
+// Nothing's said on them yet: over TLS a write would wait on a peer
+// that's still dialing everyone else instead of reading
+pub fn make_channels(
+    peer_list: &PeerList,
+    tls: Option<&TlsConfig>,
+) -> Result<HashMap<usize, Channel>, Failure> {
     let mut out = HashMap::new();
     for (id, peer_name) in peer_list.ids_and_names() {
-        let channel_sockets = connect_channel(peer_name)?;
+        let channel_sockets = connect_channel(peer_name, tls).map_err(|f| f.peer(id))?;
         out.insert(id, channel_sockets);
     }
     Ok(out)
 }
+
+/// Connects to a peer once, without retrying. For hosts a reloaded
+/// hostsfile added, which may not be up yet. Like the channels
+/// `make_channels` opens, it's nonblocking and our HELLO is up to the caller.
+pub fn dial(peer_name: &str, tls: Option<&TlsConfig>) -> Result<Channel, Failure> {
+    let addr = format!("{peer_name}:{PORT}");
+    let sock = TcpStream::connect(&addr)
+        .and_then(|sock| sock.set_nonblocking(true).map(|_| sock))
+        .during(Op::Connect)
+        .map_err(|f| f.addr(addr))?;
+    Channel::connect(sock, peer_name, tls)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, thread, time::Instant};
+
+    use nix::sys::socket::{setsockopt, sockopt};
+
+    use super::*;
+    use framing::{read_frame, write_frame, Frame};
+    use tls::TlsFiles;
+    use wire::{Codec, Parcel};
+
+    // an accepted channel and the client end of it, with small socket
+    // buffers so they fill up fast
+    fn pair() -> (Channel, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (sock, _) = listener.accept().unwrap();
+        setsockopt(&sock, sockopt::SndBuf, &65536).unwrap();
+        setsockopt(&client, sockopt::RcvBuf, &65536).unwrap();
+        (Channel::accept(sock, None).unwrap(), client)
+    }
+
+    #[test]
+    fn keeps_what_a_full_socket_wont_take() {
+        let (mut chan, mut client) = pair();
+        let frames: Vec<Vec<u8>> = (0..16).map(|n| vec![n; 60_000]).collect();
+        for frame in &frames {
+            write_frame(&mut chan, frame).unwrap();
+        }
+        assert!(chan.wants_write());
+
+        let reader = thread::spawn(move || {
+            (0..16)
+                .map(|_| read_frame(&mut client).unwrap())
+                .collect::<Vec<_>>()
+        });
+        while chan.wants_write() {
+            chan.flush().unwrap();
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(reader.join().unwrap(), frames);
+    }
+
+    #[test]
+    fn a_failed_write_is_reported_on_flush() {
+        let (mut chan, client) = pair();
+        drop(client);
+        let failed = (0..100).any(|_| {
+            thread::sleep(Duration::from_millis(1));
+            write_frame(&mut chan, &[0; 60_000]).is_err()
+        });
+        assert!(failed);
+    }
+
+    // certificates for hosts one and two, signed by the same test CA
+    fn tls(host: &str) -> TlsConfig {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/socketry/testdata");
+        TlsConfig::load(&TlsFiles {
+            cert: dir.join(format!("{host}.pem")),
+            key: dir.join(format!("{host}.key")),
+            ca: dir.join("ca.pem"),
+        })
+        .unwrap()
+    }
+
+    // dials `to` the way make_channels does, then says HELLO as `own_id`
+    fn dial_as(to: &TcpListener, name: &str, tls: &TlsConfig, own_id: PeerId) -> Channel {
+        let sock = TcpStream::connect(to.local_addr().unwrap()).unwrap();
+        sock.set_nonblocking(true).unwrap();
+        let mut chan = Channel::connect(sock, name, Some(tls)).unwrap();
+        wire::say_hello(&mut chan, own_id, Codec::Bincode).unwrap();
+        chan
+    }
+
+    #[test]
+    fn tls_peers_say_hello_before_either_reads() {
+        let path =
+            std::env::temp_dir().join(format!("prj3-socketry-tls-{}.txt", std::process::id()));
+        std::fs::write(&path, "one\ntwo").unwrap();
+        let peer_list = PeerList::load_as(path.clone(), "one".to_string()).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let configs = [tls("one"), tls("two")];
+        let listeners = [(); 2].map(|_| TcpListener::bind("127.0.0.1:0").unwrap());
+        // like every peer at startup, both dial and queue their HELLO before accepting
+        let mut outgoing = [
+            dial_as(&listeners[1], "two", &configs[0], 1),
+            dial_as(&listeners[0], "one", &configs[1], 2),
+        ];
+        let mut incoming = [0, 1].map(|i| {
+            let (sock, _) = listeners[i].accept().unwrap();
+            Channel::accept(sock, Some(&configs[i])).unwrap()
+        });
+
+        // then both drive their channels the way the poll loop does
+        let mut frames = [(); 4].map(|_| FrameReader::default());
+        let mut heard = [None; 2];
+        let mut answers = [None; 2];
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while answers.contains(&None) {
+            assert!(Instant::now() < deadline, "the handshakes stalled");
+            for (i, chan) in incoming.iter_mut().enumerate() {
+                chan.flush().unwrap();
+                frames[i].fill(chan);
+                if let Some(Frame::Whole(hello)) = frames[i].next_frame() {
+                    let Ok(Parcel::Hello(hello)) = wire::decode(&hello) else {
+                        panic!("expected a HELLO");
+                    };
+                    heard[i] = Some(hello.peer_id);
+                    wire::say_hello(chan, i + 1, Codec::Bincode).unwrap();
+                }
+            }
+            for (i, chan) in outgoing.iter_mut().enumerate() {
+                chan.flush().unwrap();
+                frames[i + 2].fill(chan);
+                if let Some(Frame::Whole(answer)) = frames[i + 2].next_frame() {
+                    answers[i] = Some(wire::hear_answer(&answer).unwrap());
+                }
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(heard, [Some(2), Some(1)]);
+        assert_eq!(answers, [Some(wire::VERSION); 2]);
+        // and the certificate two dialed with says it's two
+        assert_eq!(incoming[0].peer_identity(&peer_list), Some(2));
+    }
+}
@@ -0,0 +1,203 @@
+use std::io::{ErrorKind, Read, Write};
+
+/// Biggest letter we're willing to buffer. Anything claiming to be larger
+/// means the stream is garbage and can't be resynced.
+pub const MAX_FRAME: usize = 64 * 1024;
+const LEN_PREFIX: usize = 4;
+
+/// Writes one letter as a 4 byte big-endian length followed by the body
+pub fn write_frame(w: &mut impl Write, body: &[u8]) -> std::io::Result<()> {
+    let len = u32::try_from(body.len()).map_err(|_| ErrorKind::InvalidInput)?;
+    w.write_all(&len.to_be_bytes())?;
+    w.write_all(body)?;
+    w.flush()
+}
+
+/// Blocking counterpart to `FrameReader`, for clients that wait on one reply
+pub fn read_frame(r: &mut impl Read) -> std::io::Result<Vec<u8>> {
+    let mut prefix = [0; LEN_PREFIX];
+    r.read_exact(&mut prefix)?;
+    let len = u32::from_be_bytes(prefix) as usize;
+    if len > MAX_FRAME {
+        return Err(ErrorKind::InvalidData.into());
+    }
+    let mut body = vec![0; len];
+    r.read_exact(&mut body)?;
+    Ok(body)
+}
+
+pub enum Frame {
+    Whole(Vec<u8>),
+    // length prefix that was too large, the connection should be dropped
+    Oversized(usize),
+}
+
+/// What was left of the connection after draining it
+pub enum Drained {
+    Open,
+    Closed,
+    Failed(std::io::Error),
+}
+
+/// Reassembles frames out of a nonblocking stream, since a single read
+/// can hold half a letter or several of them.
+#[derive(Default)]
+pub struct FrameReader {
+    buf: Vec<u8>,
+}
+
+impl FrameReader {
+    /// Reads everything currently available on the stream into the buffer
+    pub fn fill(&mut self, r: &mut impl Read) -> Drained {
+        let mut chunk = [0; 4096];
+        loop {
+            match r.read(&mut chunk) {
+                Ok(0) => return Drained::Closed,
+                Ok(n) => self.push(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Drained::Open,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                // tls peers that vanish without a close_notify
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Drained::Closed,
+                Err(e) => return Drained::Failed(e),
+            }
+        }
+    }
+
+    /// Buffers bytes that were read some other way, e.g. by an async task
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Pops the next complete frame off the buffer, if there is one
+    pub fn next_frame(&mut self) -> Option<Frame> {
+        let prefix: [u8; LEN_PREFIX] = self.buf.get(..LEN_PREFIX)?.try_into().unwrap();
+        let len = u32::from_be_bytes(prefix) as usize;
+        if len > MAX_FRAME {
+            self.buf.clear();
+            return Some(Frame::Oversized(len));
+        }
+        if self.buf.len() < LEN_PREFIX + len {
+            return None;
+        }
+
+        let body = self.buf[LEN_PREFIX..LEN_PREFIX + len].to_vec();
+        self.buf.drain(..LEN_PREFIX + len);
+        Some(Frame::Whole(body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, io};
+
+    use super::*;
+
+    fn framed(bodies: &[&[u8]]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for body in bodies {
+            write_frame(&mut out, body).unwrap();
+        }
+        out
+    }
+
+    // hands out one read at a time, then would block
+    struct Trickle(VecDeque<io::Result<Vec<u8>>>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.pop_front() {
+                Some(Ok(bytes)) => {
+                    buf[..bytes.len()].copy_from_slice(&bytes);
+                    Ok(bytes.len())
+                }
+                Some(Err(e)) => Err(e),
+                None => Err(ErrorKind::WouldBlock.into()),
+            }
+        }
+    }
+
+    fn whole(frame: Option<Frame>) -> Vec<u8> {
+        match frame {
+            Some(Frame::Whole(body)) => body,
+            _ => panic!("expected a whole frame"),
+        }
+    }
+
+    #[test]
+    fn read_frame_reads_what_write_frame_wrote() {
+        let bytes = framed(&[b"hello", b""]);
+        let mut r = bytes.as_slice();
+        assert_eq!(read_frame(&mut r).unwrap(), b"hello");
+        assert_eq!(read_frame(&mut r).unwrap(), b"");
+        assert_eq!(
+            read_frame(&mut r).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn reassembles_frames_split_across_reads() {
+        let bytes = framed(&[b"first", b"second"]);
+        let mut frames = FrameReader::default();
+        frames.push(&bytes[..3]);
+        assert!(frames.next_frame().is_none());
+        frames.push(&bytes[3..7]);
+        assert!(frames.next_frame().is_none());
+        frames.push(&bytes[7..]);
+        assert_eq!(whole(frames.next_frame()), b"first");
+        assert_eq!(whole(frames.next_frame()), b"second");
+        assert!(frames.next_frame().is_none());
+    }
+
+    #[test]
+    fn fill_drains_until_it_would_block() {
+        let bytes = framed(&[b"one", b"two"]);
+        let (a, b) = bytes.split_at(5);
+        let mut stream = Trickle(VecDeque::from([Ok(a.to_vec()), Ok(b.to_vec())]));
+        let mut frames = FrameReader::default();
+        assert!(matches!(frames.fill(&mut stream), Drained::Open));
+        assert_eq!(whole(frames.next_frame()), b"one");
+        assert_eq!(whole(frames.next_frame()), b"two");
+    }
+
+    #[test]
+    fn fill_tells_a_hangup_from_a_failure() {
+        let mut frames = FrameReader::default();
+        let mut closed = Trickle(VecDeque::from([Ok(Vec::new())]));
+        assert!(matches!(frames.fill(&mut closed), Drained::Closed));
+        let mut no_notify = Trickle(VecDeque::from([Err(ErrorKind::UnexpectedEof.into())]));
+        assert!(matches!(frames.fill(&mut no_notify), Drained::Closed));
+        let mut reset = Trickle(VecDeque::from([
+            Err(ErrorKind::Interrupted.into()),
+            Err(ErrorKind::ConnectionReset.into()),
+        ]));
+        assert!(matches!(frames.fill(&mut reset), Drained::Failed(_)));
+    }
+
+    #[test]
+    fn takes_frames_up_to_max_frame() {
+        let body = vec![7; MAX_FRAME];
+        let bytes = framed(&[&body]);
+        let mut frames = FrameReader::default();
+        frames.push(&bytes);
+        assert_eq!(whole(frames.next_frame()).len(), MAX_FRAME);
+        assert_eq!(read_frame(&mut bytes.as_slice()).unwrap().len(), MAX_FRAME);
+    }
+
+    #[test]
+    fn drops_the_buffer_past_max_frame() {
+        let prefix = (MAX_FRAME as u32 + 1).to_be_bytes();
+        let mut frames = FrameReader::default();
+        frames.push(&prefix);
+        frames.push(b"garbage");
+        assert!(matches!(
+            frames.next_frame(),
+            Some(Frame::Oversized(len)) if len == MAX_FRAME + 1
+        ));
+        assert!(frames.next_frame().is_none());
+        assert_eq!(
+            read_frame(&mut prefix.as_slice()).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+}
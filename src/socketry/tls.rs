@@ -0,0 +1,166 @@
+use std::{
+    fs::File,
+    io::{BufReader, ErrorKind},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    ClientConfig, ClientConnection, ConnectionCommon, RootCertStore, ServerConfig,
+    ServerConnection, SideData, StreamOwned,
+};
+
+use crate::{
+    failures::{During, Failure, Op, Reasons},
+    hostsfile::PeerList,
+    state::PeerId,
+};
+
+pub type ClientStream = StreamOwned<ClientConnection, TcpStream>;
+pub type ServerStream = StreamOwned<ServerConnection, TcpStream>;
+
+/// Certificate files handed to us on the command line.
+/// Every peer presents `cert` both when dialing and when accepting,
+/// and only trusts peers whose certificate was signed by `ca`.
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub ca: PathBuf,
+}
+
+// both halves of a mutual TLS setup, built once and shared by every channel
+pub struct TlsConfig {
+    client: Arc<ClientConfig>,
+    server: Arc<ServerConfig>,
+}
+
+impl TlsConfig {
+    pub fn load(files: &TlsFiles) -> Result<Self, Failure> {
+        let certs = load_certs(&files.cert).during(Op::LoadCertificates(files.cert.clone()))?;
+        let key = load_key(&files.key).during(Op::LoadCertificates(files.key.clone()))?;
+
+        let ca_op = || Op::LoadCertificates(files.ca.clone());
+        let mut roots = RootCertStore::empty();
+        for ca in load_certs(&files.ca).during(ca_op())? {
+            roots.add(ca).map_err(Reasons::Tls).during(ca_op())?;
+        }
+        let roots = Arc::new(roots);
+
+        let client = ClientConfig::builder()
+            .with_root_certificates(Arc::clone(&roots))
+            .with_client_auth_cert(certs.clone(), key.clone_key())
+            .map_err(Reasons::Tls)
+            .during(Op::LoadCertificates(files.cert.clone()))?;
+
+        let verifier = WebPkiClientVerifier::builder(roots)
+            .build()
+            .map_err(|_| Reasons::BadCertificate)
+            .during(ca_op())?;
+        let server = ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs, key)
+            .map_err(Reasons::Tls)
+            .during(Op::LoadCertificates(files.cert.clone()))?;
+
+        Ok(Self {
+            client: Arc::new(client),
+            server: Arc::new(server),
+        })
+    }
+
+    /// Wraps a socket we dialed. The server's certificate has to be valid
+    /// for `peer_name`, so a peer can't answer for somebody else's hostname.
+    pub fn connect(&self, peer_name: &str, sock: TcpStream) -> Result<ClientStream, Failure> {
+        let server_name = ServerName::try_from(peer_name.to_string())
+            .map_err(|_| Reasons::BadCertificate)
+            .during(Op::Handshake)
+            .map_err(|f| f.addr(peer_name))?;
+        let mut conn = ClientConnection::new(Arc::clone(&self.client), server_name)
+            .map_err(Reasons::Tls)
+            .during(Op::Handshake)
+            .map_err(|f| f.addr(peer_name))?;
+        // a letter written to a channel is never cut short, see `Channel`
+        conn.set_buffer_limit(None);
+        // the handshake starts on the first flush and carries on as the
+        // answers are read, neither waits on a nonblocking socket
+        Ok(StreamOwned::new(conn, sock))
+    }
+
+    /// Wraps an accepted socket. The handshake is driven by the poll loop,
+    /// reading the channel when it's readable and flushing it when it's writable.
+    pub fn accept(&self, sock: TcpStream) -> Result<ServerStream, Failure> {
+        let mut conn = ServerConnection::new(Arc::clone(&self.server))
+            .map_err(Reasons::Tls)
+            .during(Op::Accept)?;
+        conn.set_buffer_limit(None);
+        Ok(StreamOwned::new(conn, sock))
+    }
+}
+
+/// Writes the records the session has ready to `sock`, until it's out of
+/// them or the socket fails, would block included
+pub fn send<S: SideData>(
+    conn: &mut ConnectionCommon<S>,
+    sock: &mut TcpStream,
+) -> std::io::Result<()> {
+    while conn.wants_write() {
+        match conn.write_tls(sock) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Finds which peer in the hostsfile the client certificate was issued to.
+/// None until the handshake has finished or if the cert matches nobody.
+pub fn peer_identity(conn: &ServerConnection, peer_list: &PeerList) -> Option<PeerId> {
+    let cert = conn.peer_certificates()?.first()?;
+    let end_entity = webpki::EndEntityCert::try_from(cert).ok()?;
+
+    peer_list.ids_and_names().find_map(|(id, name)| {
+        let name = ServerName::try_from(name.as_str()).ok()?;
+        end_entity
+            .verify_is_valid_for_subject_name(&name)
+            .is_ok()
+            .then_some(id)
+    })
+}
+
+/// Whether the client certificate was issued to `name`, the one certificate
+/// allowed to run admin commands when one is configured.
+pub fn is_issued_to(conn: &ServerConnection, name: &str) -> bool {
+    let Some(cert) = conn.peer_certificates().and_then(|certs| certs.first()) else {
+        return false;
+    };
+    let (Ok(end_entity), Ok(name)) = (
+        webpki::EndEntityCert::try_from(cert),
+        ServerName::try_from(name),
+    ) else {
+        return false;
+    };
+    end_entity.verify_is_valid_for_subject_name(&name).is_ok()
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Reasons> {
+    let mut reader = BufReader::new(File::open(path).map_err(Reasons::IO)?);
+    let certs = rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(Reasons::IO)?;
+    if certs.is_empty() {
+        return Err(Reasons::BadCertificate);
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, Reasons> {
+    let mut reader = BufReader::new(File::open(path).map_err(Reasons::IO)?);
+    rustls_pemfile::private_key(&mut reader)
+        .map_err(Reasons::IO)?
+        .ok_or(Reasons::BadCertificate)
+}
@@ -0,0 +1,671 @@
+//! What goes inside a frame: a small fixed header then a bincode body.
+//!
+//! ```text
+//! magic "P3" | version u16 BE | type u8 | body              version 1
+//! magic "P3" | version u16 BE | type u8 | codec u8 | body   version 2 on
+//! ```
+//!
+//! Every TCP connection opens with a HELLO carrying the sender's id and the
+//! range of versions it speaks. The other end answers with its own HELLO, or a
+//! REJECT if the ranges don't overlap, and from then on both speak the highest
+//! version they share. HELLO and REJECT have to be readable by every build, so
+//! their layout never changes between versions: a version 1 header and a
+//! bincode body, followed by the codec the sender writes its letters in.
+//! Version 1 peers never look past the body, and only read bincode letters.
+
+use std::{
+    fmt,
+    io::{Read, Write},
+    ops::RangeInclusive,
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    failures::{During, Failure, Op, Reasons},
+    socketry::framing::{read_frame, write_frame},
+    state::{
+        messaging::{Letter, Message, RoleKind},
+        PeerId,
+    },
+};
+
+pub const MAGIC: [u8; 2] = *b"P3";
+/// Oldest wire version this build can still read and write
+pub const MIN_VERSION: u16 = 1;
+/// The version this build speaks when the other end can too
+pub const VERSION: u16 = 10;
+const HEADER_LEN: usize = 5;
+// first version with a codec byte in the header
+const CODEC_VERSION: u16 = 2;
+// first version with the gossip messages and role, see `since`
+const GOSSIP_VERSION: u16 = 3;
+// first version with view-synchronous multicast
+const VIEWSYNC_VERSION: u16 = 4;
+// first version with totally ordered broadcast
+const ORDERED_VERSION: u16 = 5;
+// first version with causal broadcast
+const CAUSAL_VERSION: u16 = 6;
+/// First version with the lock messages and the REQs that change a lock
+pub const LOCKS_VERSION: u16 = 7;
+// first version with bully elections
+const ELECTION_VERSION: u16 = 8;
+// first version with the raft messages
+const RAFT_VERSION: u16 = 9;
+// first version that admits hosts the hostsfile doesn't list
+const ADMISSION_VERSION: u16 = 10;
+/// Last version that changed the Status in a REPORT. Fields are only ever
+/// appended, so older builds can read newer ones, but not the other way round
+pub const STATUS_VERSION: u16 = 7;
+
+// frame types. The type is in the header so a frame from a newer peer
+// can be skipped without having to decode its body
+const HELLO: u8 = 0;
+const REJECT: u8 = 1;
+// highest type a Message can have, see `kind`
+const LAST_KIND: u8 = 38;
+
+/// How letter bodies are serialized. Peers in a group can each pick their
+/// own, every build reads all of them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    Bincode,
+    /// readable in a packet capture, at several times the size
+    Json,
+}
+
+impl Codec {
+    fn byte(self) -> u8 {
+        match self {
+            Codec::Bincode => 0,
+            Codec::Json => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Self, Malformed> {
+        match byte {
+            0 => Ok(Codec::Bincode),
+            1 => Ok(Codec::Json),
+            _ => Err(Malformed::UnknownCodec(byte)),
+        }
+    }
+
+    fn serialize(self, buf: &mut Vec<u8>, letter: &Letter) -> Result<(), bincode::Error> {
+        match self {
+            Codec::Bincode => bincode::serialize_into(buf, letter),
+            Codec::Json => serde_json::to_writer(buf, letter)
+                .map_err(|e| bincode::ErrorKind::Custom(format!("can't write json: {e}")).into()),
+        }
+    }
+
+    fn deserialize(self, body: &[u8]) -> Result<Letter, Malformed> {
+        match self {
+            Codec::Bincode => bincode::deserialize(body).map_err(Malformed::Body),
+            Codec::Json => serde_json::from_slice(body).map_err(Malformed::Json),
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Codec::Bincode => write!(f, "bincode"),
+            Codec::Json => write!(f, "json"),
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bincode" => Ok(Codec::Bincode),
+            "json" => Ok(Codec::Json),
+            _ => Err(format!("unknown codec {s}, expected bincode or json")),
+        }
+    }
+}
+
+/// First frame on a connection, also the answer to it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Hello {
+    pub peer_id: PeerId,
+    pub min_version: u16,
+    pub max_version: u16,
+    // trails the bincode body, so it isn't part of the fixed layout.
+    // peers too old to send one only write bincode
+    #[serde(skip)]
+    pub codec: Codec,
+}
+
+impl Hello {
+    pub fn new(peer_id: PeerId, codec: Codec) -> Self {
+        Self {
+            peer_id,
+            min_version: MIN_VERSION,
+            max_version: VERSION,
+            codec,
+        }
+    }
+
+    pub fn versions(&self) -> RangeInclusive<u16> {
+        self.min_version..=self.max_version
+    }
+
+    /// Highest version both ends speak, None if they have none in common
+    pub fn negotiate(&self, theirs: &Hello) -> Option<u16> {
+        let highest = self.max_version.min(theirs.max_version);
+        (highest >= self.min_version.max(theirs.min_version)).then_some(highest)
+    }
+}
+
+/// A decoded frame
+#[derive(Debug)]
+pub enum Parcel {
+    Hello(Hello),
+    // the versions the rejecting end would have taken
+    Reject(Hello),
+    Letter(Letter),
+}
+
+/// Why a frame couldn't be decoded
+#[derive(Debug)]
+pub enum Malformed {
+    NoHeader,
+    Version(u16),
+    UnknownKind(u8),
+    UnknownCodec(u8),
+    Body(bincode::Error),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for Malformed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Malformed::NoHeader => write!(f, "no protocol header, sender predates versioning"),
+            Malformed::Version(v) => write!(
+                f,
+                "wire version {v}, this build speaks {MIN_VERSION}..={VERSION}"
+            ),
+            Malformed::UnknownKind(kind) => write!(f, "unknown message type {kind}"),
+            Malformed::UnknownCodec(codec) => write!(f, "unknown codec {codec}"),
+            Malformed::Body(e) => write!(f, "{e}"),
+            Malformed::Json(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// Header type of every message, never reuse or renumber these
+fn kind(message: &Message) -> u8 {
+    match message {
+        Message::REQ(_) => 2,
+        Message::JOIN => 3,
+        Message::OK { .. } => 4,
+        Message::NEWVIEW { .. } => 5,
+        Message::HEARTBEAT { .. } => 6,
+        Message::ACK { .. } => 7,
+        Message::LEAVE => 8,
+        Message::STATUS => 9,
+        Message::REPORT(_) => 10,
+        Message::KICK(_) => 11,
+        Message::VIEWS => 12,
+        Message::HISTORY(_) => 13,
+        Message::PING { .. } => 14,
+        Message::PINGREQ { .. } => 15,
+        Message::PINGACK { .. } => 16,
+        Message::MCAST(_) => 17,
+        Message::FLUSH { .. } => 18,
+        Message::FLUSHED { .. } => 19,
+        Message::SEND(_) => 20,
+        Message::SUBMIT(_) => 21,
+        Message::ORDERED(_) => 22,
+        Message::RESEND { .. } => 23,
+        Message::CAUSAL(_) => 24,
+        Message::CSEND(_) => 25,
+        Message::ACQUIRE(_) => 26,
+        Message::RELEASE(_) => 27,
+        Message::LOCKS(_) => 28,
+        Message::ELECTION => 29,
+        Message::ANSWER => 30,
+        Message::COORDINATOR => 31,
+        Message::REQUESTVOTE { .. } => 32,
+        Message::VOTE { .. } => 33,
+        Message::APPENDENTRIES { .. } => 34,
+        Message::APPENDED { .. } => 35,
+        Message::ADMIT { .. } => 36,
+        Message::ADMITTED { .. } => 37,
+        Message::HOSTS(_) => 38,
+    }
+}
+
+/// Oldest wire version that has `message`. A peer that agreed on an older
+/// one can't decode it, so it isn't sent there.
+pub fn since(message: &Message) -> u16 {
+    match (kind(message), message) {
+        (_, Message::REPORT(status)) if matches!(status.role, RoleKind::Gossip) => GOSSIP_VERSION,
+        (36.., _) => ADMISSION_VERSION,
+        (32.., _) => RAFT_VERSION,
+        (29.., _) => ELECTION_VERSION,
+        (_, Message::REQ(instr)) if !instr.op.changes_view() => LOCKS_VERSION,
+        (26.., _) => LOCKS_VERSION,
+        (24.., _) => CAUSAL_VERSION,
+        (21.., _) => ORDERED_VERSION,
+        (17.., _) => VIEWSYNC_VERSION,
+        (14.., _) => GOSSIP_VERSION,
+        _ => MIN_VERSION,
+    }
+}
+
+/// Fails if a peer that agreed on `version` couldn't read `message`
+pub fn check_version(version: u16, message: &Message) -> Result<(), Reasons> {
+    let since = since(message);
+    if version < since {
+        return Err(Reasons::Unsupported {
+            kind: kind_name(kind(message)),
+            version,
+            since,
+        });
+    }
+    Ok(())
+}
+
+/// Fails if a peer that agreed on `version` answers with a REPORT this build can't read
+pub fn check_report(version: u16) -> Result<(), Reasons> {
+    if version < STATUS_VERSION {
+        return Err(Reasons::Unsupported {
+            kind: "REPORT",
+            version,
+            since: STATUS_VERSION,
+        });
+    }
+    Ok(())
+}
+
+// name of a message type, for printing
+fn kind_name(kind: u8) -> &'static str {
+    const NAMES: [&str; LAST_KIND as usize + 1] = [
+        "HELLO",
+        "REJECT",
+        "REQ",
+        "JOIN",
+        "OK",
+        "NEWVIEW",
+        "HEARTBEAT",
+        "ACK",
+        "LEAVE",
+        "STATUS",
+        "REPORT",
+        "KICK",
+        "VIEWS",
+        "HISTORY",
+        "PING",
+        "PINGREQ",
+        "PINGACK",
+        "MCAST",
+        "FLUSH",
+        "FLUSHED",
+        "SEND",
+        "SUBMIT",
+        "ORDERED",
+        "RESEND",
+        "CAUSAL",
+        "CSEND",
+        "ACQUIRE",
+        "RELEASE",
+        "LOCKS",
+        "ELECTION",
+        "ANSWER",
+        "COORDINATOR",
+        "REQUESTVOTE",
+        "VOTE",
+        "APPENDENTRIES",
+        "APPENDED",
+        "ADMIT",
+        "ADMITTED",
+        "HOSTS",
+    ];
+    NAMES.get(kind as usize).copied().unwrap_or("unknown")
+}
+
+/// The fixed part of a frame, ahead of the body
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+    pub version: u16,
+    pub kind: u8,
+    // always bincode on HELLO, REJECT and version 1 letters
+    pub codec: Codec,
+}
+
+impl Header {
+    /// Reads the header off the front of `buf`, returning the body after it
+    pub fn parse(buf: &[u8]) -> Result<(Self, &[u8]), Malformed> {
+        if buf.len() < HEADER_LEN || buf[..2] != MAGIC {
+            return Err(Malformed::NoHeader);
+        }
+        let version = u16::from_be_bytes([buf[2], buf[3]]);
+        let kind = buf[4];
+        let body = &buf[HEADER_LEN..];
+        if kind <= REJECT || version < CODEC_VERSION {
+            let codec = Codec::Bincode;
+            return Ok((
+                Self {
+                    version,
+                    kind,
+                    codec,
+                },
+                body,
+            ));
+        }
+        let (&codec, body) = body.split_first().ok_or(Malformed::NoHeader)?;
+        let codec = Codec::from_byte(codec)?;
+        Ok((
+            Self {
+                version,
+                kind,
+                codec,
+            },
+            body,
+        ))
+    }
+
+    /// Name of the message type, for printing captured frames
+    pub fn kind_name(&self) -> &'static str {
+        kind_name(self.kind)
+    }
+
+    fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&MAGIC);
+        buf.extend_from_slice(&self.version.to_be_bytes());
+        buf.push(self.kind);
+        if self.kind > REJECT && self.version >= CODEC_VERSION {
+            buf.push(self.codec.byte());
+        }
+    }
+}
+
+/// Encodes a frame body to be sent at `version`, letters in `codec` if the
+/// version has a say in it
+pub fn encode(version: u16, codec: Codec, parcel: &Parcel) -> Result<Vec<u8>, bincode::Error> {
+    let (kind, hello) = match parcel {
+        Parcel::Hello(hello) => (HELLO, hello),
+        Parcel::Reject(hello) => (REJECT, hello),
+        Parcel::Letter(letter) => return encode_letter(version, codec, letter),
+    };
+    let mut buf = Vec::new();
+    Header {
+        version,
+        kind,
+        codec: Codec::Bincode,
+    }
+    .write(&mut buf);
+    bincode::serialize_into(&mut buf, hello)?;
+    buf.push(hello.codec.byte());
+    Ok(buf)
+}
+
+pub fn encode_letter(
+    version: u16,
+    codec: Codec,
+    letter: &Letter,
+) -> Result<Vec<u8>, bincode::Error> {
+    let codec = match version {
+        CODEC_VERSION.. => codec,
+        _ => Codec::Bincode,
+    };
+    let mut buf = Vec::new();
+    Header {
+        version,
+        kind: kind(letter.message()),
+        codec,
+    }
+    .write(&mut buf);
+    codec.serialize(&mut buf, letter)?;
+    Ok(buf)
+}
+
+/// Decodes a frame body, checking its header first
+pub fn decode(buf: &[u8]) -> Result<Parcel, Malformed> {
+    let (header, body) = Header::parse(buf)?;
+    match header.kind {
+        HELLO => decode_hello(body).map(Parcel::Hello),
+        REJECT => decode_hello(body).map(Parcel::Reject),
+        _ if !(MIN_VERSION..=VERSION).contains(&header.version) => {
+            Err(Malformed::Version(header.version))
+        }
+        kind if kind > LAST_KIND => Err(Malformed::UnknownKind(kind)),
+        _ => header.codec.deserialize(body).map(Parcel::Letter),
+    }
+}
+
+fn decode_hello(mut body: &[u8]) -> Result<Hello, Malformed> {
+    let mut hello: Hello = bincode::deserialize_from(&mut body).map_err(Malformed::Body)?;
+    if let Some(&codec) = body.first() {
+        hello.codec = Codec::from_byte(codec)?;
+    }
+    Ok(hello)
+}
+
+/// Opens a connection by introducing ourselves. The answer comes back on
+/// the same connection, `hear_answer` says what it means
+pub fn say_hello(w: &mut impl Write, peer_id: PeerId, codec: Codec) -> Result<(), Failure> {
+    let buf = encode(VERSION, codec, &Parcel::Hello(Hello::new(peer_id, codec)))
+        .map_err(Reasons::BadMessage)
+        .during(Op::Handshake)?;
+    write_frame(w, &buf).during(Op::Handshake)
+}
+
+/// The version to speak on a connection we opened, from the frame that
+/// answered our HELLO. A REJECT fails with the versions the other end speaks
+pub fn hear_answer(frame: &[u8]) -> Result<u16, Failure> {
+    // only the version range of ours matters
+    let ours = Hello::new(0, Codec::default());
+    match decode(frame)
+        .map_err(Reasons::BadFrame)
+        .during(Op::Handshake)?
+    {
+        Parcel::Hello(theirs) => ours
+            .negotiate(&theirs)
+            .ok_or_else(|| Failure::new(Op::Handshake, Reasons::UnexpectedReply)),
+        Parcel::Reject(theirs) => Err(Failure::new(
+            Op::Handshake,
+            Reasons::Incompatible {
+                min_version: theirs.min_version,
+                max_version: theirs.max_version,
+            },
+        )),
+        Parcel::Letter(_) => Err(Failure::new(Op::Handshake, Reasons::UnexpectedReply)),
+    }
+}
+
+/// Says HELLO and blocks for the answer, for clients that only talk to the one peer.
+/// Peers can't wait like this on each other, they all dial before any of them reads
+pub fn handshake(
+    chan: &mut (impl Read + Write),
+    peer_id: PeerId,
+    codec: Codec,
+) -> Result<u16, Failure> {
+    say_hello(chan, peer_id, codec)?;
+    let answer = read_frame(chan).during(Op::Handshake)?;
+    hear_answer(&answer)
+}
+
+/// One frame as a header line plus its body, pretty-printed as JSON
+/// whatever codec it came in
+pub fn describe(buf: &[u8]) -> String {
+    let header = match Header::parse(buf) {
+        Ok((header, _)) => header,
+        Err(e) => return format!("malformed frame: {e}"),
+    };
+    let line = format!(
+        "{} v{} {}, {} bytes",
+        header.kind_name(),
+        header.version,
+        header.codec,
+        buf.len()
+    );
+    let body = match decode(buf) {
+        Ok(Parcel::Letter(letter)) => serde_json::to_string_pretty(&letter),
+        Ok(Parcel::Hello(hello) | Parcel::Reject(hello)) => {
+            return format!("{line}\n{hello:#?}");
+        }
+        Err(e) => return format!("{line}\nmalformed: {e}"),
+    };
+    match body {
+        Ok(body) => format!("{line}\n{body}"),
+        Err(e) => format!("{line}\nunprintable: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(min_version: u16, max_version: u16) -> Hello {
+        Hello {
+            peer_id: 2,
+            min_version,
+            max_version,
+            codec: Codec::Json,
+        }
+    }
+
+    #[test]
+    fn negotiates_the_highest_shared_version() {
+        assert_eq!(hello(1, 4).negotiate(&hello(2, 7)), Some(4));
+        assert_eq!(hello(3, 3).negotiate(&hello(1, 9)), Some(3));
+        assert_eq!(hello(1, 2).negotiate(&hello(3, 4)), None);
+        assert_eq!(hello(5, 9).negotiate(&hello(1, 4)), None);
+    }
+
+    #[test]
+    fn hello_keeps_its_layout() {
+        let buf = encode(VERSION, Codec::Json, &Parcel::Hello(hello(1, 99))).unwrap();
+        // no codec byte between the header and the body, whatever the version
+        assert_eq!(buf[..2], MAGIC);
+        assert_eq!(buf[4], HELLO);
+        assert_eq!(buf[HEADER_LEN..HEADER_LEN + 8], 2u64.to_le_bytes());
+        let Parcel::Hello(decoded) = decode(&buf).unwrap() else {
+            panic!("expected a HELLO");
+        };
+        assert_eq!(decoded.versions(), 1..=99);
+        assert_eq!(decoded.codec, Codec::Json);
+
+        // peers from before codecs end the frame at the body
+        let Parcel::Hello(old) = decode(&buf[..buf.len() - 1]).unwrap() else {
+            panic!("expected a HELLO");
+        };
+        assert_eq!(old.codec, Codec::Bincode);
+    }
+
+    #[test]
+    fn letters_carry_the_codec_from_version_2() {
+        let letter: Letter = (3, Message::JOIN).into();
+        let v1 = encode_letter(MIN_VERSION, Codec::Json, &letter).unwrap();
+        let v2 = encode_letter(CODEC_VERSION, Codec::Json, &letter).unwrap();
+        assert_eq!(Header::parse(&v1).unwrap().0.codec, Codec::Bincode);
+        assert_eq!(Header::parse(&v2).unwrap().0.codec, Codec::Json);
+        for buf in [v1, v2] {
+            let Parcel::Letter(decoded) = decode(&buf).unwrap() else {
+                panic!("expected a letter");
+            };
+            assert_eq!(decoded.from_whom(), 3);
+            assert!(matches!(decoded.message(), Message::JOIN));
+        }
+    }
+
+    #[test]
+    fn refuses_what_it_cant_read() {
+        let letter: Letter = (3, Message::LEAVE).into();
+        let buf = encode_letter(VERSION, Codec::Bincode, &letter).unwrap();
+        assert!(matches!(decode(b"JOIN"), Err(Malformed::NoHeader)));
+
+        let mut newer = buf.clone();
+        newer[2..4].copy_from_slice(&(VERSION + 1).to_be_bytes());
+        assert!(matches!(decode(&newer), Err(Malformed::Version(v)) if v == VERSION + 1));
+
+        let mut unknown = buf.clone();
+        unknown[4] = LAST_KIND + 1;
+        assert!(matches!(decode(&unknown), Err(Malformed::UnknownKind(_))));
+        assert_eq!(Header::parse(&unknown).unwrap().0.kind_name(), "unknown");
+
+        let mut codec = buf;
+        codec[HEADER_LEN] = 9;
+        assert!(matches!(decode(&codec), Err(Malformed::UnknownCodec(9))));
+    }
+
+    #[test]
+    fn hears_the_answer_to_a_hello() {
+        let answer = |parcel| encode(MIN_VERSION, Codec::Bincode, &parcel).unwrap();
+        assert_eq!(
+            hear_answer(&answer(Parcel::Hello(hello(1, VERSION + 5)))).unwrap(),
+            VERSION
+        );
+        let rejected = hear_answer(&answer(Parcel::Reject(hello(90, 99)))).unwrap_err();
+        assert!(rejected.to_string().contains("wire versions 90..=99"));
+        let letter = encode_letter(VERSION, Codec::Bincode, &(1, Message::JOIN).into()).unwrap();
+        assert!(hear_answer(&letter).is_err());
+    }
+
+    #[test]
+    fn lock_reqs_need_the_locks_version() {
+        use crate::state::messaging::{Instruction, Operation};
+
+        let req = |op| {
+            Message::REQ(Instruction {
+                request_id: 1,
+                peer_id: 2,
+                view_id: 1,
+                op,
+            })
+        };
+        assert_eq!(since(&req(Operation::Add)), MIN_VERSION);
+        assert_eq!(since(&req(Operation::Acquire("a".into()))), LOCKS_VERSION);
+        assert_eq!(since(&Message::LOCKS(Default::default())), LOCKS_VERSION);
+        assert!(check_version(LOCKS_VERSION - 1, &req(Operation::Release("a".into()))).is_err());
+        assert!(check_version(LOCKS_VERSION, &req(Operation::Release("a".into()))).is_ok());
+    }
+
+    // what the other end already wrote, and what we write to it
+    struct Duplex {
+        incoming: std::io::Cursor<Vec<u8>>,
+        outgoing: Vec<u8>,
+    }
+
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.incoming.read(buf)
+        }
+    }
+
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.outgoing.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn handshake_says_hello_then_reads_the_answer() {
+        let mut incoming = Vec::new();
+        let theirs = encode(MIN_VERSION, Codec::Bincode, &Parcel::Hello(hello(1, 3))).unwrap();
+        write_frame(&mut incoming, &theirs).unwrap();
+        let mut chan = Duplex {
+            incoming: std::io::Cursor::new(incoming),
+            outgoing: Vec::new(),
+        };
+        assert_eq!(handshake(&mut chan, 7, Codec::Bincode).unwrap(), 3);
+
+        let ours = read_frame(&mut chan.outgoing.as_slice()).unwrap();
+        let Parcel::Hello(ours) = decode(&ours).unwrap() else {
+            panic!("expected a HELLO");
+        };
+        assert_eq!(ours.peer_id, 7);
+        assert_eq!(ours.versions(), MIN_VERSION..=VERSION);
+    }
+}
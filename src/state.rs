@@ -1,26 +1,111 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Display,
     io::Write,
-    thread::{self, sleep},
+    net::SocketAddrV4,
+    os::fd::{BorrowedFd, RawFd},
+    str::FromStr,
+    sync::Arc,
     time::{Duration, Instant},
-    usize,
 };
 
-use crate::{failures::Reasons, hostsfile::PeerList};
+use crate::{
+    admin::ADMIN_ID,
+    clock::Clock,
+    failures::{During, Failure, Op, Reasons},
+    group::{Config, Delivery, ViewChange, Views},
+    hostsfile::PeerList,
+    metrics::Metrics,
+    socketry::{
+        framing::write_frame,
+        wire::{self, Codec, Hello, Parcel},
+    },
+};
 
-mod lifecycle;
+pub mod alarm;
+mod causal;
+mod election;
+mod gossip;
+mod lease;
+pub mod lifecycle;
+mod locks;
 pub mod messaging;
+mod raft;
 mod roles;
+mod sequencer;
+mod viewsync;
 
-use lifecycle::{Heart, LifeCycle};
-use messaging::{Instruction, Letter, Message, Operation};
-use roles::Role;
+use causal::CausalOrder;
+use election::{Bully, Verdict};
+use gossip::Effects;
+use lifecycle::{Heart, LifeCycle, Tick, Timers};
+use locks::Locks;
+use messaging::{
+    Causal, Instruction, Letter, Liveness, Mcast, Message, Operation, RoleKind, Stage, Status,
+};
+use roles::{Following, Leading, Role};
+use viewsync::ViewSync;
 
 pub type PeerId = usize;
 pub type ViewId = u32;
 pub type RequestId = u32;
 pub const DEFAULT_LEADER_ID: usize = 1;
+
+/// How many members of the previous view have to OK a view change
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Membership {
+    /// every one of them, the group stalls while any member is unreachable
+    #[default]
+    Unanimous,
+    /// more than half, a minority side of a partition blocks until it heals
+    Majority,
+    /// no agreement and no leader: members probe each other SWIM style and
+    /// spread joins and failures on the probes, so views converge eventually
+    Gossip,
+    /// Raft: changes are entries in a replicated log, committed by a
+    /// majority and applied two steps at a time through a joint configuration.
+    /// Leaders are elected by term, with randomized timeouts
+    Raft,
+}
+
+/// How a new leader is picked when the one we follow stops heartbeating
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Election {
+    /// nobody takes over, the first peer in the hostsfile leads for good
+    #[default]
+    Fixed,
+    /// the Bully algorithm: the lowest id still heartbeating takes over
+    Bully,
+}
+
+impl Display for Election {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Election::Fixed => write!(f, "fixed"),
+            Election::Bully => write!(f, "bully"),
+        }
+    }
+}
+
+impl FromStr for Election {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed" => Ok(Election::Fixed),
+            "bully" => Ok(Election::Bully),
+            _ => Err(format!(
+                "unknown election strategy {s}, expected fixed or bully"
+            )),
+        }
+    }
+}
+
 const HEARTBEAT_PERIOD: Duration = Duration::from_secs(2);
+// how often we look for peers that stopped heartbeating
+const CHECK_PERIOD: Duration = Duration::from_millis(500);
+// how long a leaving follower waits for the leader to drop it from the view
+const LEAVE_TIMEOUT: Duration = Duration::from_secs(5);
 
 type Channels<W> = HashMap<usize, W>;
 // main state of each process
@@ -33,18 +118,504 @@ pub struct Data {
     view_id: ViewId,
     peer_list: PeerList,
     crash_delay: Option<Duration>,
+    // when we sent our LEAVE, and whether the leader has let us go
+    leaving: Option<Instant>,
+    left: bool,
+    metrics: Metrics,
+    // where installed views are published for the embedding application
+    views: Views,
+    membership: Membership,
+    // can't hear from a majority of the view, only in Majority mode
+    blocked: bool,
+    // when the leader sent the REQ it's currently collecting OKs for
+    round_started: Option<Instant>,
+    // newest leader epoch seen, stamped on everything we send
+    epoch: u64,
+    // wire version agreed with each peer in its HELLO
+    wire_versions: HashMap<PeerId, u16>,
+    // what our letters are written in, where the version allows it
+    codec: Codec,
+    // everything time dependent asks this instead of Instant::now
+    clock: Arc<dyn Clock>,
+    // group to send heartbeats to instead of each peer
+    multicast: Option<SocketAddrV4>,
+    // application messages multicast in the current view
+    viewsync: ViewSync,
+    // vector clock over the current view's members, for causal broadcast
+    causal: CausalOrder,
+    // named locks, committed through the REQ/OK round
+    locks: Locks,
+    // the election we'd run if the leader went quiet, None with Election::Fixed
+    election: Option<Bully>,
+    // a failure check ran since the transport last asked what to dial
+    dial_due: bool,
+    // hosts the hostsfile added that we have no channel to yet
+    undialed: HashSet<PeerId>,
+    // what a host the hostsfile doesn't list has to ADMIT with, None admits nobody
+    admission_token: Option<String>,
+    // sent our JOIN, once the start delay ran out or an admin asked for it
+    asked_to_join: bool,
+    // peers that REJECTed our HELLO or hung up before answering it. Their
+    // channel is dropped, and they're only dialed again once they say HELLO
+    refused: HashSet<PeerId>,
 }
 
 impl Data {
-    pub fn new(peer_list: PeerList, crash_delay: Option<u64>) -> Self {
-        let role = Role::new(peer_list.is_leader());
-        Self {
+    /// Sets up the first view and starts the failure checks on `timers`,
+    /// which also keep the LEAVE timeout going. Takes the crash delay,
+    /// membership mode, codec, clock, multicast group and admission token
+    /// out of `config`.
+    pub fn new(
+        peer_list: PeerList,
+        config: &Config,
+        metrics: Metrics,
+        views: Views,
+        timers: &mut impl Timers,
+    ) -> Result<Self, Failure> {
+        let role = Role::new(peer_list.is_leader(), config.membership, peer_list.id());
+        // gossiping peers start out on their own
+        let first = match role {
+            Role::Gossip(_) => peer_list.id(),
+            _ => DEFAULT_LEADER_ID,
+        };
+        metrics.set_view(1, 1);
+        timers.every(Tick::Check, CHECK_PERIOD, CHECK_PERIOD)?;
+        let mut data = Self {
             view_id: 1,
             status: LifeCycle::Born,
-            memberships: HashMap::from([(1, HashSet::from([DEFAULT_LEADER_ID]))]),
+            memberships: HashMap::from([(1, HashSet::from([first]))]),
             peer_list,
             role,
-            crash_delay: crash_delay.map(Duration::from_secs),
+            crash_delay: config.crash_delay,
+            leaving: None,
+            left: false,
+            metrics,
+            views,
+            membership: config.membership,
+            blocked: false,
+            round_started: None,
+            epoch: 0,
+            wire_versions: HashMap::new(),
+            codec: config.codec,
+            clock: config.clock.clone(),
+            multicast: config.multicast,
+            viewsync: ViewSync::default(),
+            causal: CausalOrder::default(),
+            locks: Locks::default(),
+            election: match config.election {
+                Election::Fixed => None,
+                Election::Bully => Some(Bully::default()),
+            },
+            dial_due: false,
+            undialed: HashSet::new(),
+            admission_token: config.admission_token.clone(),
+            asked_to_join: false,
+            refused: HashSet::new(),
+        };
+        data.publish_view();
+        Ok(data)
+    }
+
+    // tells the embedding application about the view we just installed,
+    // and gives the vector clock an entry for each of its members
+    fn publish_view(&mut self) {
+        self.causal.resize(&self.memberships[&self.view_id]);
+        self.views.install(ViewChange {
+            view_id: self.view_id,
+            members: self.memberships[&self.view_id].clone(),
+            leader: self.leader_id(),
+        });
+    }
+
+    pub fn peer_list(&self) -> &PeerList {
+        &self.peer_list
+    }
+
+    // hands an application message to the embedding application
+    fn deliver(&self, mcast: Mcast) {
+        self.views.deliver(Delivery {
+            view_id: mcast.view_id,
+            from: mcast.from,
+            payload: mcast.payload,
+        });
+    }
+
+    // a view was just installed, so is anything sent in it that beat the NEWVIEW here
+    fn start_view(&mut self) {
+        for mcast in self.viewsync.install(self.view_id) {
+            self.deliver(mcast);
+        }
+    }
+
+    // delivers what the FLUSHes for `view_id` turned up that we missed
+    fn deliver_flushed(&mut self, messages: Vec<Mcast>, view_id: ViewId) {
+        if view_id != self.view_id {
+            return;
+        }
+        for mcast in self.viewsync.flushed(messages, view_id) {
+            self.deliver(mcast);
+        }
+    }
+
+    fn is_member(&self) -> bool {
+        self.memberships
+            .get(&self.view_id)
+            .is_some_and(|members| members.contains(&self.peer_list.id()))
+    }
+
+    /// Sends an application message to the current view and delivers it here.
+    /// Asked while a view change is underway, it goes out in the next view.
+    pub fn multicast(&mut self, payload: Vec<u8>, outgoing_channels: &mut Channels<impl Write>) {
+        match self.role {
+            Role::Gossip(_) => {
+                self.log("not multicasting, gossip views aren't agreed on");
+                return;
+            }
+            Role::Raft(_) => {
+                self.log("not multicasting, raft views change without a flush");
+                return;
+            }
+            _ => {}
+        }
+        if !self.is_member() {
+            self.log("not multicasting, we're not in the view");
+            return;
+        }
+        let own_id = self.peer_list.id();
+        let Some(mcast) = self.viewsync.send(payload, own_id, self.view_id) else {
+            return;
+        };
+        let letter = self.letter(Message::MCAST(mcast.clone()));
+        let current_members = &self.memberships[&self.view_id];
+        for (&id, channel) in outgoing_channels
+            .iter_mut()
+            .filter(|(id, _)| **id != own_id && current_members.contains(id))
+        {
+            self.send_or_report(&letter, id, channel);
+        }
+        self.deliver(mcast);
+    }
+
+    /// Has the leader number `payload` and every member deliver it in that order.
+    /// The leader numbers its own right away, followers SUBMIT theirs.
+    pub fn submit(&mut self, payload: Vec<u8>, outgoing_channels: &mut Channels<impl Write>) {
+        if !self.is_member() {
+            self.log("not submitting, we're not in the view");
+            return;
+        }
+        let own_id = self.peer_list.id();
+        match self.role {
+            Role::Leader(ref mut lead) => {
+                let ordered = lead.sequencer.assign(own_id, payload);
+                self.views.sequence(ordered);
+            }
+            Role::Follower(ref follow) => {
+                let leader_id = follow.leader_id();
+                if let Some(channel) = self.channel(leader_id, outgoing_channels) {
+                    self.send_or_report(&self.letter(Message::SUBMIT(payload)), leader_id, channel);
+                }
+            }
+            Role::Gossip(_) => self.log("not submitting, there's no leader to order it"),
+            Role::Raft(_) => self.log("not submitting, raft only orders membership changes"),
+        }
+    }
+
+    // the leader forwards what it numbered and resends what members found
+    // missing, a follower asks for what it's missing
+    fn pass_ordered(&mut self, outgoing_channels: &mut Channels<impl Write>) {
+        let own_id = self.peer_list.id();
+        match self.role {
+            Role::Leader(ref mut lead) => {
+                let unsent = lead.sequencer.unsent();
+                let resends = lead.sequencer.take_resends();
+                let current_members = &self.memberships[&self.view_id];
+                for ordered in unsent {
+                    let letter = self.letter(Message::ORDERED(ordered));
+                    for (&id, channel) in outgoing_channels
+                        .iter_mut()
+                        .filter(|(id, _)| **id != own_id && current_members.contains(id))
+                    {
+                        self.send_or_report(&letter, id, channel);
+                    }
+                }
+                for (peer, missing) in resends {
+                    let Some(channel) = outgoing_channels.get_mut(&peer) else {
+                        continue;
+                    };
+                    for ordered in missing {
+                        self.send_or_report(&self.letter(Message::ORDERED(ordered)), peer, channel);
+                    }
+                }
+            }
+            Role::Follower(ref mut follow) => {
+                let leader_id = follow.leader_id();
+                if let Some(from_seq) = follow.ordered.take_gap() {
+                    self.log(format_args!("missing ordered messages from {from_seq} on"));
+                    if let Some(channel) = self.channel(leader_id, outgoing_channels) {
+                        self.send_or_report(
+                            &self.letter(Message::RESEND { from_seq }),
+                            leader_id,
+                            channel,
+                        );
+                    }
+                }
+            }
+            Role::Gossip(_) | Role::Raft(_) => {}
+        }
+    }
+
+    /// Sends an application message to the current view, stamped with our vector
+    /// clock. Everyone delivers it after whatever we had delivered before sending it.
+    pub fn send_causal(&mut self, payload: Vec<u8>, outgoing_channels: &mut Channels<impl Write>) {
+        if !self.is_member() {
+            self.log("not sending, we're not in the view");
+            return;
+        }
+        let own_id = self.peer_list.id();
+        let causal = self.causal.stamp(own_id, payload);
+        let letter = self.letter(Message::CAUSAL(causal.clone()));
+        let current_members = &self.memberships[&self.view_id];
+        for (&id, channel) in outgoing_channels
+            .iter_mut()
+            .filter(|(id, _)| **id != own_id && current_members.contains(id))
+        {
+            self.send_or_report(&letter, id, channel);
+        }
+        self.views.deliver_causal(causal);
+    }
+
+    // holds a causal message until we delivered everything its sender had
+    fn recv_causal(&mut self, causal: &Causal) {
+        for causal in self.causal.receive(causal.clone()) {
+            self.views.deliver_causal(causal);
+        }
+    }
+
+    /// Asks the leader to grant us the named lock, or to take it back.
+    /// Whoever holds it shows up in the LOCKS table once its round completes.
+    pub fn lock(
+        &mut self,
+        name: &str,
+        acquire: bool,
+        outgoing_channels: &mut Channels<impl Write>,
+    ) {
+        if !self.is_member() {
+            self.log("not locking, we're not in the view");
+            return;
+        }
+        match self.role {
+            Role::Leader(_) => self.queue_lock(self.peer_list.id(), name, acquire),
+            Role::Follower(ref follow) => {
+                let leader_id = follow.leader_id();
+                let message = match acquire {
+                    true => Message::ACQUIRE(name.to_string()),
+                    false => Message::RELEASE(name.to_string()),
+                };
+                if let Some(channel) = self.channel(leader_id, outgoing_channels) {
+                    self.send_or_report(&self.letter(message), leader_id, channel);
+                }
+            }
+            Role::Gossip(_) => self.log("not locking, there's no leader to grant it"),
+            Role::Raft(_) => self.log("not locking, raft only commits membership changes"),
+        }
+    }
+
+    // the leader queues a member's lock change. An Acquire of a held lock
+    // waits until it's released, a Release of a lock it doesn't hold is dropped
+    fn queue_lock(&mut self, from: PeerId, name: &str, acquire: bool) {
+        let own_id = self.peer_list.id();
+        if !self.memberships[&self.view_id].contains(&from) {
+            return;
+        }
+        // every member has to take the REQ, or the round never completes
+        let members = &self.memberships[&self.view_id];
+        if let Some(old) = members
+            .iter()
+            .find(|&&id| id != own_id && self.wire_version(id) < wire::LOCKS_VERSION)
+        {
+            self.log(format_args!(
+                "not locking {name}, peer {old} hasn't agreed on wire version {} yet",
+                wire::LOCKS_VERSION
+            ));
+            return;
+        }
+        if let Role::Leader(ref mut lead) = self.role {
+            let op = if acquire && self.locks.request(name, from) {
+                Operation::Acquire(name.to_string())
+            } else if !acquire && self.locks.holds(name, from) && !lead.is_releasing(from, name) {
+                Operation::Release(name.to_string())
+            } else {
+                return;
+            };
+            lead.push_request(from, self.view_id, op);
+            lead.acknowledge_ok(lead.latest_request(), own_id);
+        }
+    }
+
+    // the leader applies a lock change whose round completed, starts the
+    // grants it freed up and sends every member the new table
+    fn commit_lock(
+        &mut self,
+        op: &Operation,
+        peer_id: PeerId,
+        outgoing_channels: &mut Channels<impl Write>,
+    ) {
+        let mut grants: Vec<_> = self.locks.commit(op, peer_id).into_iter().collect();
+        // granted to a member that was deleted while its round was running
+        if !self.memberships[&self.view_id].contains(&peer_id) {
+            grants.extend(self.locks.drop_member(peer_id));
+        }
+        self.grant(grants);
+        self.send_locks(outgoing_channels);
+    }
+
+    // the leader hands freed locks to whoever waited for them next
+    fn grant(&mut self, grants: Vec<(String, PeerId)>) {
+        let own_id = self.peer_list.id();
+        if let Role::Leader(ref mut lead) = self.role {
+            for (name, peer) in grants {
+                lead.push_request(peer, self.view_id, Operation::Acquire(name));
+                lead.acknowledge_ok(lead.latest_request(), own_id);
+            }
+        }
+    }
+
+    fn send_locks(&self, outgoing_channels: &mut Channels<impl Write>) {
+        let held = self.locks.held().clone();
+        let own_id = self.peer_list.id();
+        let letter = self.letter(Message::LOCKS(held.clone()));
+        let current_members = &self.memberships[&self.view_id];
+        for (&id, channel) in outgoing_channels
+            .iter_mut()
+            .filter(|(id, _)| **id != own_id && current_members.contains(id))
+        {
+            self.send_or_report(&letter, id, channel);
+        }
+        self.views.publish_locks(held);
+    }
+
+    // the leader stopped heartbeating, run against the members outranking us
+    fn start_election(&mut self) {
+        let own_id = self.peer_list.id();
+        let LifeCycle::Living(_, ref prev_beats) = self.status else {
+            return;
+        };
+        let outranking = self.memberships[&self.view_id]
+            .iter()
+            .copied()
+            .filter(|&id| id < own_id && prev_beats.contains_key(&id))
+            .collect();
+        let now = self.clock.now();
+        let Some(ref mut bully) = self.election else {
+            return;
+        };
+        if bully.running() {
+            return;
+        }
+        match bully.start(now, outranking) {
+            Verdict::Won => self.take_over(),
+            _ => self.log("calling an election"),
+        }
+    }
+
+    // won an election: lead the view, tell the rest of it, and drop the old leader
+    fn take_over(&mut self) {
+        let own_id = self.peer_list.id();
+        let old_leader = match self.role {
+            Role::Follower(ref follow) => follow.leader_id(),
+            _ => return,
+        };
+        self.change_role(|role| match role {
+            Role::Follower(follow) => Role::Leader(Leading::succeed(follow)),
+            other => other,
+        });
+        let members = self.memberships[&self.view_id].clone();
+        if let Some(ref mut bully) = self.election {
+            bully.announce(members.iter().copied().filter(|&id| id != own_id));
+        }
+        self.log(format_args!("elected, taking over from peer {old_leader}"));
+        if let Role::Leader(ref mut lead) = self.role {
+            if members.contains(&old_leader) {
+                lead.push_request(old_leader, self.view_id, Operation::Delete);
+                lead.acknowledge_ok(lead.latest_request(), own_id);
+                if self.membership == Membership::Majority {
+                    lead.partitioned(old_leader);
+                }
+            }
+        }
+    }
+
+    // someone won an election. A leader that hears it was given up on steps down
+    fn follow(&mut self, leader_id: PeerId) {
+        match self.role {
+            Role::Follower(ref mut follow) if follow.leader_id() != leader_id => {
+                follow.set_leader(leader_id)
+            }
+            Role::Leader(_) if leader_id != self.peer_list.id() => {
+                self.change_role(|role| match role {
+                    Role::Leader(lead) => Role::Follower(Following::step_down(lead, leader_id)),
+                    other => other,
+                })
+            }
+            _ => return,
+        }
+        self.log(format_args!("peer {leader_id} is the leader now"));
+    }
+
+    // swaps our role for one made out of the old one
+    fn change_role(&mut self, change: impl FnOnce(Role) -> Role) {
+        let old = std::mem::replace(&mut self.role, Role::Leader(Leading::default()));
+        self.role = change(old);
+    }
+
+    fn recv_election(&mut self, letter: &Letter) {
+        let from = letter.from_whom();
+        let own_id = self.peer_list.id();
+        let now = self.clock.now();
+        let Some(ref mut bully) = self.election else {
+            self.log(format_args!(
+                "ignoring {:?} from peer {from}, we don't hold elections",
+                letter.message()
+            ));
+            return;
+        };
+        let leader_alive = match (&self.role, &self.status) {
+            (Role::Follower(follow), LifeCycle::Living(_, prev_beats)) => {
+                prev_beats.contains_key(&follow.leader_id())
+            }
+            _ => false,
+        };
+        match letter.message() {
+            // we outrank the sender, so we're in the running too unless our
+            // leader is still heartbeating. A leader that's only been missed
+            // tells everyone it's still here
+            Message::ELECTION if own_id < from => {
+                bully.answer(from);
+                if let Role::Leader(_) = self.role {
+                    let members = self.memberships[&self.view_id].iter().copied();
+                    bully.announce(members.filter(|&id| id != own_id));
+                } else if !leader_alive {
+                    self.start_election();
+                }
+            }
+            Message::ANSWER => bully.answered(now),
+            Message::COORDINATOR => {
+                bully.finish();
+                self.follow(from);
+            }
+            _ => {}
+        }
+    }
+
+    fn check_election(&mut self) {
+        let now = self.clock.now();
+        let Some(ref mut bully) = self.election else {
+            return;
+        };
+        match bully.check(now) {
+            Verdict::Won => self.take_over(),
+            Verdict::Restart => self.start_election(),
+            Verdict::Waiting => {}
         }
     }
 
@@ -52,23 +623,112 @@ impl Data {
     pub fn recv_message(&mut self, letter: &Letter) {
         //println!("recv: {:?}", letter);
 
+        // application messages carry their view or clock, not the leader's epoch
+        match letter.message() {
+            Message::MCAST(mcast) => {
+                if self.viewsync.receive(mcast, self.view_id) {
+                    self.deliver(mcast.clone());
+                }
+                return;
+            }
+            Message::CAUSAL(causal) => return self.recv_causal(causal),
+            // the old leader's epoch means nothing to an election for the next one
+            Message::ELECTION | Message::ANSWER | Message::COORDINATOR => {
+                return self.recv_election(letter)
+            }
+            _ => {}
+        }
+
+        // whatever a leader sent before it lost its lease is fenced off
+        if matches!(self.role, Role::Follower(_)) && letter.epoch() < self.epoch {
+            self.log(format_args!(
+                "rejecting {:?} from epoch {}, we're on {}",
+                letter.message(),
+                letter.epoch(),
+                self.epoch
+            ));
+            return;
+        }
+        self.see_epoch(letter);
+
         use messaging::Message as M;
         if let Role::Leader(ref mut lead) = self.role {
             match letter.message() {
                 M::JOIN => {
-                    lead.push_request(letter.from_whom(), self.view_id, Operation::Add);
-                    lead.acknowledge_ok(lead.latest_request(), self.peer_list.id());
+                    let from = letter.from_whom();
+                    // the leader adds hosts the hostsfile added itself, once it can send them the NEWVIEW
+                    if !self.memberships[&self.view_id].contains(&from)
+                        && !lead.is_adding(from)
+                        && !self.undialed.contains(&from)
+                    {
+                        lead.push_request(from, self.view_id, Operation::Add);
+                        lead.acknowledge_ok(lead.latest_request(), self.peer_list.id());
+                    }
                 }
                 M::OK { request_id, .. } => {
                     lead.acknowledge_ok(*request_id, letter.from_whom());
                 }
-                _ => unreachable!(),
+                M::FLUSH {
+                    request_id,
+                    messages,
+                } => {
+                    lead.collect_flush(*request_id, letter.from_whom(), messages.clone());
+                }
+                M::SUBMIT(payload) => {
+                    let from = letter.from_whom();
+                    if self.memberships[&self.view_id].contains(&from) {
+                        let ordered = lead.sequencer.assign(from, payload.clone());
+                        self.views.sequence(ordered);
+                    }
+                }
+                M::RESEND { from_seq } => {
+                    lead.sequencer.resend(letter.from_whom(), *from_seq);
+                }
+                M::ACQUIRE(name) => self.queue_lock(letter.from_whom(), name, true),
+                M::RELEASE(name) => self.queue_lock(letter.from_whom(), name, false),
+                M::LEAVE => {
+                    let leaver = letter.from_whom();
+                    let is_member = self
+                        .memberships
+                        .get(&self.view_id)
+                        .is_some_and(|members| members.contains(&leaver));
+                    if is_member && !lead.is_deleting(leaver) {
+                        lead.push_leave(leaver, self.view_id);
+                        lead.acknowledge_ok(lead.latest_request(), self.peer_list.id());
+                    }
+                }
+                other => self.log(format_args!(
+                    "ignoring {other:?} from peer {}, it's for followers",
+                    letter.from_whom()
+                )),
             }
         } else if let Role::Follower(ref mut follow) = self.role {
             match letter.message() {
                 M::REQ(instr) => {
-                    follow.push_instruction(*instr);
+                    follow.push_instruction(instr.clone());
+                }
+                M::FLUSHED { view_id, messages } => {
+                    self.deliver_flushed(messages.clone(), *view_id);
+                }
+                M::ORDERED(ordered) => {
+                    for ordered in follow.ordered.receive(ordered.clone()) {
+                        self.views.sequence(ordered);
+                    }
+                }
+                M::LOCKS(held) => {
+                    self.locks.replace(held.clone());
+                    self.views.publish_locks(held.clone());
+                }
+                // ids are only handed out by the leader we follow now
+                M::HOSTS(hosts)
+                    if letter.from_whom() == follow.leader_id() && letter.epoch() == self.epoch =>
+                {
+                    self.learn_hosts(hosts);
                 }
+                M::HOSTS(_) => self.log(format_args!(
+                    "ignoring HOSTS from peer {}, it isn't the leader we follow",
+                    letter.from_whom()
+                )),
                 M::NEWVIEW { view_id, members } => {
                     self.view_id = *view_id;
                     eprintln!(
@@ -79,39 +739,530 @@ impl Data {
                         members.iter().collect::<Vec<_>>()
                     );
                     self.memberships.insert(self.view_id, members.clone());
+                    self.metrics.set_view(self.view_id, members.len());
+                    self.publish_view();
+                    if self.leaving.is_some() && !members.contains(&self.peer_list.id()) {
+                        self.left = true;
+                    }
+                    self.start_view();
+                }
+                other => self.log(format_args!(
+                    "ignoring {other:?} from peer {}, we're not the leader",
+                    letter.from_whom()
+                )),
+            }
+        } else if let Role::Raft(ref mut raft) = self.role {
+            match letter.message() {
+                M::HOSTS(hosts) if raft.leader() == Some(letter.from_whom()) => {
+                    self.learn_hosts(hosts)
                 }
-                _ => unreachable!(),
+                M::HOSTS(_) => self.log(format_args!(
+                    "ignoring HOSTS from peer {}, it doesn't lead",
+                    letter.from_whom()
+                )),
+                message => raft.receive(letter.from_whom(), message, self.clock.now()),
             }
+        } else if let Role::Gossip(_) = self.role {
+            self.log(format_args!(
+                "ignoring {:?} from peer {}, we're gossiping",
+                letter.message(),
+                letter.from_whom()
+            ));
         }
     }
 
-    fn send_letter(&self, letter: &Letter, sender: &mut impl Write) -> Result<(), Reasons> {
+    // stamps a message with our id and epoch
+    fn letter(&self, message: Message) -> Letter {
+        (self.peer_list.id(), self.epoch, message).into()
+    }
+
+    /// Keeps up with the newest epoch. A leader that finds one newer
+    /// than its own was fenced and has to win the lease back.
+    fn see_epoch(&mut self, letter: &Letter) {
+        if letter.epoch() <= self.epoch {
+            return;
+        }
+        self.epoch = letter.epoch();
+        if let Role::Leader(ref mut lead) = self.role {
+            lead.lease.revoke();
+            self.log(format_args!(
+                "fenced: peer {} is on epoch {}",
+                letter.from_whom(),
+                self.epoch
+            ));
+        }
+    }
+
+    // a leader only changes the view while it holds the lease,
+    // there's nothing to renew it with before we're Living
+    fn has_lease(&self) -> bool {
+        match (&self.role, &self.status) {
+            (Role::Leader(lead), LifeCycle::Living(..)) => lead.lease.held(self.clock.now()),
+            _ => true,
+        }
+    }
+
+    // until it answers our HELLO a peer gets the oldest version we speak
+    fn wire_version(&self, peer_id: PeerId) -> u16 {
+        self.wire_versions
+            .get(&peer_id)
+            .copied()
+            .unwrap_or(wire::MIN_VERSION)
+    }
+
+    fn send_letter(
+        &self,
+        letter: &Letter,
+        to: PeerId,
+        sender: &mut impl Write,
+    ) -> Result<(), Failure> {
         //println!("send: {:?}", letter);
 
-        let encoded_buffer = bincode::serialize(&letter).map_err(|_| Reasons::BadMessage)?;
-        let _ = sender.write(&encoded_buffer).map_err(Reasons::IO)?;
-        Ok(())
+        let version = self.wire_version(to);
+        wire::check_version(version, letter.message())
+            .during(Op::Send)
+            .map_err(|f| f.peer(to))?;
+        let encoded_buffer = wire::encode_letter(version, self.codec, letter)
+            .map_err(Reasons::BadMessage)
+            .during(Op::Send)
+            .map_err(|f| f.peer(to))?;
+        write_frame(sender, &encoded_buffer)
+            .during(Op::Send)
+            .map_err(|f| f.peer(to))
+    }
+
+    // the channel to `to`, logged if there's none: the hostsfile can drop a
+    // host, and a peer that refused our HELLO loses its channel
+    fn channel<'c, W>(
+        &self,
+        to: PeerId,
+        outgoing_channels: &'c mut Channels<W>,
+    ) -> Option<&'c mut W> {
+        let channel = outgoing_channels.get_mut(&to);
+        if channel.is_none() {
+            self.log(format_args!("no channel to peer {to}, not sending"));
+        }
+        channel
+    }
+
+    /// Sends a letter to one peer, reporting instead of failing if that peer's
+    /// channel is broken so the rest of the group still gets served.
+    fn send_or_report(&self, letter: &Letter, to: PeerId, sender: &mut impl Write) {
+        if let Err(failure) = self.send_letter(letter, to, sender) {
+            self.log(failure);
+        }
+    }
+
+    /// Answers the HELLO that opened a connection with ours, or with a REJECT
+    /// if we share no wire version. Returns the version to speak on it.
+    pub fn answer_hello(&mut self, hello: &Hello, conn: &mut impl Write) -> Option<u16> {
+        let ours = Hello::new(self.peer_list.id(), self.codec);
+        let agreed = ours.negotiate(hello);
+        let reply = match agreed {
+            Some(version) => {
+                // a peer we gave up on is back, with a version we speak
+                if self.refused.remove(&hello.peer_id) {
+                    self.dial_due = true;
+                }
+                wire::encode(version, self.codec, &Parcel::Hello(ours))
+            }
+            None => {
+                let who = match hello.peer_id {
+                    ADMIN_ID => "an admin client".to_string(),
+                    id => format!("peer {id}"),
+                };
+                self.log(format_args!(
+                    "rejecting {who}: it speaks wire versions {:?}, we speak {:?}",
+                    hello.versions(),
+                    ours.versions()
+                ));
+                wire::encode(wire::MIN_VERSION, self.codec, &Parcel::Reject(ours))
+            }
+        };
+        let sent = reply
+            .map_err(Reasons::BadMessage)
+            .during(Op::Handshake)
+            .and_then(|buf| write_frame(conn, &buf).during(Op::Handshake));
+        if let Err(failure) = sent {
+            self.log(failure.peer(hello.peer_id));
+        }
+        agreed
+    }
+
+    /// Takes the answer to the HELLO that opened our channel to `peer_id`:
+    /// the version we write to it in from now on, or why it can't be used,
+    /// in which case the channel is dropped
+    pub fn answered<W: Write>(
+        &mut self,
+        peer_id: PeerId,
+        answer: Result<u16, Failure>,
+        outgoing_channels: &mut Channels<W>,
+    ) {
+        match answer {
+            Ok(version) => {
+                self.wire_versions.insert(peer_id, version);
+            }
+            Err(failure) => {
+                self.log(failure.peer(peer_id));
+                self.wire_versions.remove(&peer_id);
+                self.refused.insert(peer_id);
+                outgoing_channels.remove(&peer_id);
+            }
+        }
+    }
+
+    /// Carries out an admin command and answers it at `version` on the connection it came in on.
+    /// Clients that aren't `trusted` only get to look: anything that would change
+    /// the group is answered with our status and otherwise ignored.
+    pub fn answer_admin(
+        &mut self,
+        letter: &Letter,
+        admin: &mut impl Write,
+        version: u16,
+        trusted: bool,
+        outgoing_channels: &mut Channels<impl Write>,
+    ) {
+        use messaging::Message as M;
+        // ADMIT comes from hosts that aren't in the group yet, the token vouches for them
+        let looks = matches!(letter.message(), M::STATUS | M::VIEWS | M::ADMIT { .. });
+        let reply = match letter.message() {
+            _ if !trusted && !looks => {
+                self.log("refusing an admin command, the client's certificate isn't the admin's");
+                M::REPORT(Box::new(self.status()))
+            }
+            M::STATUS => M::REPORT(Box::new(self.status())),
+            M::VIEWS => {
+                let mut views: Vec<_> = self
+                    .memberships
+                    .iter()
+                    .map(|(&view_id, members)| (view_id, members.clone()))
+                    .collect();
+                views.sort_by_key(|(view_id, _)| *view_id);
+                M::HISTORY(views)
+            }
+            M::JOIN => {
+                if let Err(failure) = self.ask_to_join(outgoing_channels) {
+                    self.log(failure);
+                }
+                M::REPORT(Box::new(self.status()))
+            }
+            M::LEAVE => {
+                let status = self.status();
+                self.leave(outgoing_channels);
+                M::REPORT(Box::new(status))
+            }
+            M::KICK(peer_id) => {
+                self.kick(*peer_id);
+                M::REPORT(Box::new(self.status()))
+            }
+            M::SEND(payload) => {
+                self.multicast(payload.clone(), outgoing_channels);
+                M::REPORT(Box::new(self.status()))
+            }
+            M::SUBMIT(payload) => {
+                self.submit(payload.clone(), outgoing_channels);
+                M::REPORT(Box::new(self.status()))
+            }
+            M::CSEND(payload) => {
+                self.send_causal(payload.clone(), outgoing_channels);
+                M::REPORT(Box::new(self.status()))
+            }
+            M::ACQUIRE(name) => {
+                self.lock(name, true, outgoing_channels);
+                M::REPORT(Box::new(self.status()))
+            }
+            M::RELEASE(name) => {
+                self.lock(name, false, outgoing_channels);
+                M::REPORT(Box::new(self.status()))
+            }
+            M::ADMIT { name, token } => self
+                .admit(name, token, outgoing_channels)
+                .unwrap_or_else(|| M::REPORT(Box::new(self.status()))),
+            other => {
+                self.log(format_args!("ignoring admin letter {other:?}"));
+                return;
+            }
+        };
+
+        let letter = self.letter(reply);
+        let sent = wire::check_version(version, letter.message())
+            .and_then(|_| {
+                wire::encode_letter(version, self.codec, &letter).map_err(Reasons::BadMessage)
+            })
+            .during(Op::Send)
+            .and_then(|buf| write_frame(admin, &buf).during(Op::Send));
+        if let Err(failure) = sent {
+            self.log(failure);
+        }
+    }
+
+    /// Prints a status line in the same shape as the rest of our output
+    pub fn log(&self, message: impl Display) {
+        eprintln!(
+            "{{peer_id: {}, view_id: {}, leader: {}, message: \"{}\"}}",
+            self.peer_list.id(),
+            self.view_id,
+            self.leader_id(),
+            message
+        );
     }
 
     // member methods
-    pub fn ask_to_join(&self, outgoing_channels: &mut Channels<impl Write>) -> Result<(), Reasons> {
+    /// Sends our JOIN, only the first time it's asked for
+    pub fn ask_to_join(
+        &mut self,
+        outgoing_channels: &mut Channels<impl Write>,
+    ) -> Result<(), Failure> {
+        if std::mem::replace(&mut self.asked_to_join, true) {
+            return Ok(());
+        }
+        // gossiping peers announce themselves once they're heartbeating
+        if let Role::Gossip(ref mut gossip) = self.role {
+            gossip.join();
+        }
+        // raft peers don't know who leads yet, they ask every host
+        if let Role::Raft(ref mut raft) = self.role {
+            raft.join(
+                self.clock.now(),
+                self.peer_list.ids_and_names().map(|(id, _)| id),
+            );
+        }
         if let Role::Follower(ref follow) = self.role {
-            let parcel: Letter = (self.peer_list.id(), Message::JOIN).into();
-            self.send_letter(
-                &parcel,
-                outgoing_channels
-                    .get_mut(&follow.leader_id())
-                    .expect("Channel to leader"),
-            )?;
+            let parcel = self.letter(Message::JOIN);
+            let leader_id = follow.leader_id();
+            if let Some(channel) = self.channel(leader_id, outgoing_channels) {
+                self.send_letter(&parcel, leader_id, channel)?;
+            }
         }
 
         Ok(())
     }
 
+    /// Starts leaving the group. A follower in the current view asks the leader
+    /// to delete it and waits for the NEWVIEW, anyone else can just go.
+    pub fn leave(&mut self, outgoing_channels: &mut Channels<impl Write>) {
+        if self.leaving.is_some() {
+            return;
+        }
+        self.leaving = Some(self.clock.now());
+
+        let is_member = self.is_member();
+        match self.role {
+            Role::Follower(ref follow) if is_member => {
+                let leader_id = follow.leader_id();
+                self.log("leaving the group");
+                if let Some(channel) = self.channel(leader_id, outgoing_channels) {
+                    self.send_or_report(&self.letter(Message::LEAVE), leader_id, channel);
+                }
+            }
+            Role::Gossip(ref mut gossip) => {
+                let fx = gossip.leave();
+                self.log("leaving the group");
+                self.apply_gossip(fx);
+                self.left = true;
+            }
+            // with no leader to ask to commit it, we just go
+            Role::Raft(ref mut raft) if is_member => match raft.leave() {
+                true => self.log("leaving the group"),
+                false => self.left = true,
+            },
+            _ => self.left = true,
+        }
+    }
+
+    /// True once it's safe to exit: the leader removed us or stopped answering
+    pub fn has_left(&self) -> bool {
+        self.left
+            || self
+                .leaving
+                .is_some_and(|since| self.clock.elapsed(since) > LEAVE_TIMEOUT)
+    }
+
+    /// Stops heartbeating and prints the last view we saw
+    pub fn shutdown(&mut self, timers: &mut impl Timers) {
+        timers.cancel(Tick::Beat);
+        let members = self
+            .memberships
+            .get(&self.view_id)
+            .map(|m| m.iter().collect::<Vec<_>>())
+            .unwrap_or_default();
+        eprintln!(
+            "{{proc_id: {}, view_id: {}, leader: {}, memb_list: {:?}}}",
+            self.peer_list.id(),
+            self.view_id,
+            self.leader_id(),
+            members
+        );
+        self.log("exiting");
+    }
+
     // Leader methods //
 
+    // queues a delete for a member an admin asked to remove
+    fn kick(&mut self, peer_id: PeerId) {
+        if self.delete_member(peer_id) {
+            self.log(format_args!("admin kicked peer {peer_id}"));
+        }
+    }
+
+    // queues a delete for another member, if we lead and it isn't on its way
+    // out already. True if one was queued
+    fn delete_member(&mut self, peer_id: PeerId) -> bool {
+        let own_id = self.peer_list.id();
+        let is_member = self
+            .memberships
+            .get(&self.view_id)
+            .is_some_and(|members| members.contains(&peer_id));
+        if !is_member || peer_id == own_id {
+            return false;
+        }
+        match self.role {
+            Role::Leader(ref mut lead) if !lead.is_deleting(peer_id) => {
+                lead.push_request(peer_id, self.view_id, Operation::Delete);
+                lead.acknowledge_ok(lead.latest_request(), own_id);
+                true
+            }
+            Role::Raft(ref mut raft) if raft.is_leader() => {
+                raft.propose(peer_id, Operation::Delete);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // picks up hosts added to or removed from the hostsfile. The leader
+    // deletes removed members, and adds new hosts once it has dialed them
+    fn reload_hosts(&mut self) {
+        let reload = match self.peer_list.reload() {
+            Ok(Some(reload)) => reload,
+            Ok(None) => return,
+            Err(failure) => return self.log(format_args!("not reloading: {failure}")),
+        };
+        if let LifeCycle::Living(_, ref mut prev_beats) = self.status {
+            for id in &reload.removed {
+                prev_beats.remove(id);
+            }
+        }
+        self.update_heart();
+        for id in reload.added {
+            self.log(format_args!("hostsfile added peer {id}"));
+            self.undialed.insert(id);
+        }
+        for id in reload.removed {
+            self.log(format_args!("hostsfile removed peer {id}"));
+            self.undialed.remove(&id);
+            self.delete_member(id);
+        }
+    }
+
+    // a host the hostsfile removed loses its channel once it's out of the
+    // view, until then it may still have a REQ or NEWVIEW coming
+    fn close_removed(&self, outgoing_channels: &mut Channels<impl Write>) {
+        let members = &self.memberships[&self.view_id];
+        outgoing_channels.retain(|&id, _| {
+            let keep = self.peer_list.name(id).is_some() || members.contains(&id);
+            if !keep {
+                self.log(format_args!("closing the channel to removed peer {id}"));
+            }
+            keep
+        });
+    }
+
+    // beats to whatever hosts the peer list has now
+    fn update_heart(&mut self) {
+        if let LifeCycle::Living(ref mut heart, _) = self.status {
+            if let Err(failure) = heart.update(&self.peer_list) {
+                self.log(failure);
+            }
+        }
+    }
+
+    // gives a host the hostsfile doesn't list an id, if we lead and it
+    // has the token, and tells everyone we have a channel to about it.
+    // Adding it waits until we've dialed it, like a host the hostsfile added
+    fn admit(
+        &mut self,
+        name: &str,
+        token: &str,
+        outgoing_channels: &mut Channels<impl Write>,
+    ) -> Option<Message> {
+        let leads = match self.role {
+            Role::Leader(_) => true,
+            Role::Raft(ref raft) => raft.is_leader(),
+            Role::Follower(_) | Role::Gossip(_) => false,
+        };
+        if !leads || self.admission_token.as_deref() != Some(token) {
+            self.log(format_args!("not admitting {name}"));
+            return None;
+        }
+        let known = self.peer_list.admitted().len();
+        let peer_id = self.peer_list.admit(name);
+        let hosts = self.peer_list.admitted().clone();
+        if hosts.len() > known {
+            self.log(format_args!("admitted {name} as peer {peer_id}"));
+            if let Err(failure) = self.peer_list.persist() {
+                self.log(failure);
+            }
+            self.undialed.insert(peer_id);
+            self.update_heart();
+            let letter = self.letter(Message::HOSTS(hosts.clone()));
+            for (&id, channel) in outgoing_channels.iter_mut() {
+                self.send_or_report(&letter, id, channel);
+            }
+        }
+        Some(Message::ADMITTED { peer_id, hosts })
+    }
+
+    // hosts the leader admitted, dialed at the next failure check
+    fn learn_hosts(&mut self, hosts: &BTreeMap<PeerId, String>) {
+        let learned = self.peer_list.learn(hosts);
+        if learned.is_empty() {
+            return;
+        }
+        for &id in &learned {
+            self.log(format_args!("learned of admitted peer {id}"));
+            self.undialed.insert(id);
+        }
+        if let Err(failure) = self.peer_list.persist() {
+            self.log(failure);
+        }
+        self.update_heart();
+    }
+
+    /// Hosts in the hostsfile we have no channel to, for the transport to
+    /// dial. Only handed out once per failure check, so a host that isn't up
+    /// yet gets tried again every check rather than on every wakeup.
+    pub fn to_dial(&mut self, outgoing_channels: &Channels<impl Write>) -> Vec<(PeerId, String)> {
+        if !std::mem::take(&mut self.dial_due) {
+            return Vec::new();
+        }
+        self.peer_list
+            .ids_and_names()
+            .filter(|(id, _)| !outgoing_channels.contains_key(id) && !self.refused.contains(id))
+            .map(|(id, name)| (id, name.clone()))
+            .collect()
+    }
+
+    /// A channel to a host the hostsfile added is up, the leader adds it
+    pub fn dialed(&mut self, peer_id: PeerId) {
+        self.log(format_args!("connected to peer {peer_id}"));
+        self.undialed.remove(&peer_id);
+        let own_id = self.peer_list.id();
+        if self.memberships[&self.view_id].contains(&peer_id) {
+            return;
+        }
+        match self.role {
+            Role::Leader(ref mut lead) if !lead.is_adding(peer_id) => {
+                lead.push_request(peer_id, self.view_id, Operation::Add);
+                lead.acknowledge_ok(lead.latest_request(), own_id);
+            }
+            Role::Raft(ref mut raft) => raft.propose(peer_id, Operation::Add),
+            _ => {}
+        }
+    }
+
     // increments view_id and adds a new member to the list
-    fn push_new_view(&mut self, peer: PeerId, op: Operation) {
+    fn push_new_view(&mut self, peer: PeerId, op: Operation, dropped: &[PeerId]) {
         let mut prev_members = self
             .memberships
             .get(&self.view_id)
@@ -123,8 +1274,14 @@ impl Data {
         } else if let Operation::Delete = op {
             assert!(prev_members.remove(&peer));
         }
+        for id in dropped {
+            prev_members.remove(id);
+        }
         self.view_id += 1;
+        self.metrics.set_view(self.view_id, prev_members.len());
         self.memberships.insert(self.view_id, prev_members);
+        self.publish_view();
+        self.start_view();
     }
 
     // Performs all operations in the queue.
@@ -132,30 +1289,106 @@ impl Data {
     pub fn flush_instructions(
         &mut self,
         outgoing_channels: &mut Channels<impl Write>,
-    ) -> Result<(), Reasons> {
+        timers: &mut impl Timers,
+    ) -> Result<(), Failure> {
+        self.close_removed(outgoing_channels);
+        // whatever was held back during the last view change goes out in this view
+        for payload in self.viewsync.release() {
+            self.multicast(payload, outgoing_channels);
+        }
+        self.pass_ordered(outgoing_channels);
+        let election = self.election.as_mut().map(Bully::take_outbox);
+        for (to, message) in election.unwrap_or_default() {
+            if let Some(channel) = outgoing_channels.get_mut(&to) {
+                self.send_or_report(&self.letter(message), to, channel);
+            }
+        }
+        self.apply_raft(outgoing_channels);
+
         // Regular instruction flushing
+        let has_lease = self.has_lease();
         if let Role::Leader(ref mut lead) = self.role {
+            self.metrics.set_pending_requests(lead.pending_len());
             // pop an instruction of the queue after we've gotten all our confirmations
-            if let Some(Instruction { peer_id, op, .. }) =
-                lead.check_req_complete(&self.memberships)
+            if let Some(Instruction { peer_id, op, .. }) = lead
+                .check_req_complete(&self.memberships, self.membership)
+                .filter(|_| has_lease)
             {
-                self.push_new_view(peer_id, op);
-                self.update_views(outgoing_channels)?;
+                let started = self.round_started.take();
+                if op.changes_view() {
+                    let released = (matches!(op, Operation::Delete) && lead.release(peer_id))
+                        .then_some(peer_id);
+                    if let Some(started) = started {
+                        self.metrics.view_changed(self.clock.elapsed(started));
+                    }
+                    let (flushers, flushed) = lead.take_flushed();
+                    let left_view = self.view_id;
+                    // a majority is enough to change the view, but a member that
+                    // didn't FLUSH may have delivered what the union is missing,
+                    // so it can't move on with the rest. It's added back when heard from
+                    let dropped: Vec<PeerId> = match self.membership {
+                        Membership::Majority => self.memberships[&left_view]
+                            .iter()
+                            .copied()
+                            .filter(|id| *id != peer_id && !flushers.contains(id))
+                            .collect(),
+                        _ => Vec::new(),
+                    };
+                    for &id in &dropped {
+                        lead.partitioned(id);
+                    }
+                    for id in &dropped {
+                        self.log(format_args!("dropping peer {id}, it didn't FLUSH"));
+                    }
+                    self.deliver_flushed(flushed.clone(), left_view);
+                    let mut removed = dropped.clone();
+                    if matches!(op, Operation::Delete) {
+                        removed.push(peer_id);
+                    }
+                    self.push_new_view(peer_id, op, &dropped);
+                    self.update_views(outgoing_channels, released, (left_view, flushed))?;
+                    // whatever a removed member held goes to the next in line
+                    let mut held_any = false;
+                    for id in removed {
+                        held_any |= self.locks.held().values().any(|&h| h == id);
+                        let grants = self.locks.drop_member(id);
+                        self.grant(grants);
+                    }
+                    if held_any {
+                        self.send_locks(outgoing_channels);
+                    }
+                } else {
+                    self.commit_lock(&op, peer_id, outgoing_channels);
+                }
             }
         } else if let Role::Follower(ref mut follow) = self.role {
             let leader_id = follow.leader_id();
-            if let Some(ack_instr) = follow.send_ok() {
-                self.send_letter(
-                    &(
-                        self.peer_list.id(),
-                        Message::OK {
+            // a blocked follower holds its OKs until it can see a majority again
+            if let Some(ack_instr) = follow.send_ok().filter(|_| !self.blocked) {
+                // what we delivered in this view goes with the OK of a view change
+                if ack_instr.op.changes_view() {
+                    let messages = self.viewsync.flush();
+                    if let Some(channel) = self.channel(leader_id, outgoing_channels) {
+                        self.send_or_report(
+                            &self.letter(Message::FLUSH {
+                                request_id: ack_instr.request_id,
+                                messages,
+                            }),
+                            leader_id,
+                            channel,
+                        );
+                    }
+                }
+                if let Some(channel) = self.channel(leader_id, outgoing_channels) {
+                    self.send_or_report(
+                        &self.letter(Message::OK {
                             request_id: ack_instr.request_id,
                             view_id: ack_instr.view_id,
-                        },
-                    )
-                        .into(),
-                    outgoing_channels.get_mut(&leader_id).unwrap(),
-                )?;
+                        }),
+                        leader_id,
+                        channel,
+                    );
+                }
             }
         }
 
@@ -163,126 +1396,493 @@ impl Data {
         if let (LifeCycle::Born, Some(current_members)) =
             (&self.status, self.memberships.get(&self.view_id))
         {
-            // once we have all our members we need, we can start sending heartbeats
-            if self.peer_list.members_match_hosts(current_members) {
+            // once we have all our members we need, we can start sending heartbeats.
+            // gossiping peers find each other with those, so they start right away
+            let ready = match self.role {
+                Role::Gossip(ref gossip) => gossip.has_joined(),
+                _ => self.peer_list.members_match_hosts(current_members),
+            };
+            if ready {
+                let now = self.clock.now();
                 let prev_beats = self
                     .peer_list
                     .ids_and_names()
-                    .map(|(id, _)| (id, Instant::now()))
+                    .map(|(id, _)| (id, now))
                     .collect();
-                self.status = LifeCycle::Living(Heart::new(&self.peer_list)?, prev_beats);
-                // sleep to allow other processes to change their states
-                sleep(Duration::from_secs(1));
-
-                let LifeCycle::Living(ref mut heart, _) = &mut self.status else {
-                    unreachable!(); // just instanced this
-                };
-                let beat_stop = heart.start(HEARTBEAT_PERIOD);
-
+                let heart = Heart::new(&self.peer_list, self.metrics.clone(), self.multicast)?;
+                // wait a second to allow other processes to change their states
+                let settle = Duration::from_secs(1);
+                timers.every(Tick::Beat, settle, HEARTBEAT_PERIOD)?;
                 if let Some(dur) = self.crash_delay {
-                    let beat_stop = beat_stop;
-                    let (id, vid, lid) = (self.peer_list.id(), self.view_id, self.leader_id());
-                    thread::spawn(move || {
-                        sleep(dur);
-                        drop(beat_stop);
-                        eprintln!("{{peer_id: {id}, view_id: {vid}, leader: {lid}, message: \"crashing\"}}");
-                    });
-                } else {
-                    beat_stop.ignore();
+                    timers.once(Tick::Crash, settle + dur)?;
                 }
+                self.status = LifeCycle::Living(heart, prev_beats);
             }
         }
         Ok(())
     }
 
-    /// Returns the id of the current leader in the system
+    /// Returns the id of the current leader in the system.
+    /// Gossiping peers have none, the lowest member id stands in for our output.
+    /// Raft has none between terms, 0 stands in then.
     fn leader_id(&self) -> usize {
         match &self.role {
             Role::Leader(_) => self.peer_list.id(),
             Role::Follower(ref follow) => follow.leader_id(),
+            Role::Raft(raft) => raft.leader().unwrap_or(0),
+            Role::Gossip(_) => self
+                .memberships
+                .get(&self.view_id)
+                .and_then(|members| members.iter().min().copied())
+                .unwrap_or(self.peer_list.id()),
+        }
+    }
+
+    /// Everything an admin STATUS query reports about this process
+    pub fn status(&self) -> Status {
+        let (role, pending_requests, ack_queue) = match &self.role {
+            Role::Leader(lead) => (RoleKind::Leader, lead.pending(), Vec::new()),
+            Role::Follower(follow) => (RoleKind::Follower, Vec::new(), follow.queued()),
+            Role::Gossip(_) => (RoleKind::Gossip, Vec::new(), Vec::new()),
+            Role::Raft(raft) if raft.is_leader() => (RoleKind::Leader, Vec::new(), Vec::new()),
+            Role::Raft(_) => (RoleKind::Follower, Vec::new(), Vec::new()),
+        };
+        let (stage, heartbeat_ages) = match &self.status {
+            LifeCycle::Born => (Stage::Born, Vec::new()),
+            LifeCycle::Living(_, prev_beats) => {
+                let mut ages: Vec<_> = prev_beats
+                    .iter()
+                    .map(|(&id, &prev)| (id, self.clock.elapsed(prev)))
+                    .collect();
+                ages.sort_by_key(|(id, _)| *id);
+                (Stage::Living, ages)
+            }
+        };
+
+        Status {
+            peer_id: self.peer_list.id(),
+            role,
+            stage,
+            view_id: self.view_id,
+            members: self
+                .memberships
+                .get(&self.view_id)
+                .cloned()
+                .unwrap_or_default(),
+            leader_id: self.leader_id(),
+            pending_requests,
+            ack_queue,
+            heartbeat_ages,
+            blocked: self.blocked,
+            ordered: match &self.role {
+                Role::Leader(lead) => lead.sequencer.latest(),
+                Role::Follower(follow) => follow.ordered.delivered(),
+                Role::Gossip(_) | Role::Raft(_) => 0,
+            },
+            clock: self.causal.clock().clone(),
+            locks: self.locks.held().clone(),
+            // raft's term does the epoch's job
+            epoch: match &self.role {
+                Role::Raft(raft) => raft.term(),
+                _ => self.epoch,
+            },
+            lease: match &self.role {
+                Role::Leader(lead) => lead.lease.remaining(self.clock.now()),
+                Role::Follower(_) | Role::Gossip(_) | Role::Raft(_) => None,
+            },
         }
     }
 
-    /// If in the living stage, will poll its heart to check
-    /// for heartbeats from its peers
-    pub fn validate_peers(&mut self) -> Result<(), Reasons> {
+    /// Heartbeat sockets to wait on, there are none until we're Living
+    pub fn heartbeat_fds(&self) -> Vec<BorrowedFd<'_>> {
+        match self.status {
+            LifeCycle::Living(ref heart, _) => heart.fds().collect(),
+            LifeCycle::Born => Vec::new(),
+        }
+    }
+
+    /// Same sockets as `heartbeat_fds`, for transports that read them on their own
+    #[cfg(feature = "tokio")]
+    pub fn heartbeat_sockets(&self) -> Vec<&std::net::UdpSocket> {
+        match self.status {
+            LifeCycle::Living(ref heart, _) => heart.sockets().collect(),
+            LifeCycle::Born => Vec::new(),
+        }
+    }
+
+    /// Reads the heartbeats and ACKs waiting on `fd`, one of `heartbeat_fds`
+    pub fn recv_heartbeats(&mut self, fd: RawFd) {
+        let letters = match self.status {
+            LifeCycle::Living(ref heart, _) => heart.recv(fd),
+            LifeCycle::Born => return,
+        };
+        for letter in letters {
+            self.recv_datagram(&letter);
+        }
+    }
+
+    /// Handles a letter from a heartbeat socket, a HEARTBEAT or an ACK of one of ours
+    pub fn recv_datagram(&mut self, letter: &Letter) {
+        if let LifeCycle::Born = self.status {
+            return;
+        }
+        // a multicast group loops our own beats back, and
+        // could be shared with peers that aren't in our hostsfile
+        let from = letter.from_whom();
+        if !self.peer_list.ids_and_names().any(|(id, _)| id == from) {
+            return;
+        }
+        if let Role::Gossip(ref mut gossip) = self.role {
+            let now = self.clock.now();
+            let fx = gossip.receive(from, letter.message(), now);
+            if let LifeCycle::Living(_, ref mut prev_beats) = self.status {
+                prev_beats.insert(from, now);
+            }
+            self.apply_gossip(fx);
+            return;
+        }
+        self.see_epoch(letter);
+        match *letter.message() {
+            Message::HEARTBEAT { beat } => self.record_heartbeat(letter.from_whom(), beat),
+            Message::ACK { beat } => self.record_ack(letter.from_whom(), beat),
+            ref other => self.log(format_args!("ignoring datagram {other:?}")),
+        }
+    }
+
+    /// Notes that a peer is still alive
+    fn record_heartbeat(&mut self, from: PeerId, beat: u64) {
+        let ack = self.letter(Message::ACK { beat });
+        let LifeCycle::Living(ref heart, ref mut prev_beats) = self.status else {
+            return;
+        };
+        let now = self.clock.now();
+        let last = prev_beats.insert(from, now);
+        self.metrics
+            .heartbeat_received(from, last.map(|prev| now - prev));
+        // the leader's lease runs on these, a leader on an old
+        // epoch gets one too so it finds out it was fenced
+        if let Role::Follower(ref follow) = self.role {
+            if from == follow.leader_id() {
+                heart.send_to(from, &ack);
+            }
+        }
+
+        // a peer we dropped as unreachable is back, the partition healed
+        let own_id = self.peer_list.id();
+        let is_member = self.memberships[&self.view_id].contains(&from);
+        if let Role::Leader(ref mut lead) = self.role {
+            if !is_member && lead.rejoin(from) {
+                lead.push_request(from, self.view_id, Operation::Add);
+                lead.acknowledge_ok(lead.latest_request(), own_id);
+                self.log(format_args!(
+                    "peer {from} is reachable again, adding it back"
+                ));
+            }
+        }
+    }
+
+    // counts an ACK towards the lease, a new lease starts a new epoch
+    fn record_ack(&mut self, from: PeerId, beat: u64) {
+        let members = &self.memberships[&self.view_id];
+        if let Role::Leader(ref mut lead) = self.role {
+            if lead.lease.ack(beat, from, members, self.clock.now()) {
+                self.epoch += 1;
+                self.log(format_args!("holding the lease for epoch {}", self.epoch));
+            }
+        }
+    }
+
+    /// Does whatever `tick` was scheduled for
+    pub fn on_tick(&mut self, tick: Tick, timers: &mut impl Timers) {
+        if tick == Tick::Check {
+            self.reload_hosts();
+            self.dial_due = true;
+        }
+        match tick {
+            Tick::Beat if matches!(self.role, Role::Gossip(_)) => {
+                let now = self.clock.now();
+                let hosts: Vec<_> = self.peer_list.ids_and_names().map(|(id, _)| id).collect();
+                if let Role::Gossip(ref mut gossip) = self.role {
+                    let fx = gossip.probe(now, hosts.into_iter());
+                    self.apply_gossip(fx);
+                }
+            }
+            Tick::Check if matches!(self.role, Role::Gossip(_)) => {
+                let now = self.clock.now();
+                if let Role::Gossip(ref mut gossip) = self.role {
+                    let fx = gossip.check(now);
+                    self.apply_gossip(fx);
+                }
+            }
+            Tick::Beat => {
+                // only the leader's beats get ACKed, followers leave theirs at 0
+                let beat = match self.role {
+                    Role::Leader(ref mut lead) => lead.lease.next_beat(self.clock.now()),
+                    _ => 0,
+                };
+                if beat != 0 {
+                    self.record_ack(self.peer_list.id(), beat);
+                }
+                let letter = self.letter(Message::HEARTBEAT { beat });
+                if let LifeCycle::Living(ref heart, _) = self.status {
+                    heart.beat(&letter);
+                }
+            }
+            Tick::Check if matches!(self.role, Role::Raft(_)) => {
+                self.validate_peers();
+                let now = self.clock.now();
+                let hosts: Vec<_> = self.peer_list.ids_and_names().map(|(id, _)| id).collect();
+                if let Role::Raft(ref mut raft) = self.role {
+                    raft.tick(now, hosts.into_iter());
+                }
+            }
+            Tick::Check => {
+                self.validate_peers();
+                self.check_lease();
+                self.check_election();
+            }
+            Tick::Crash => {
+                timers.cancel(Tick::Beat);
+                match self.role {
+                    Role::Gossip(ref mut gossip) => gossip.crash(),
+                    Role::Raft(ref mut raft) => raft.crash(),
+                    _ => {}
+                }
+                self.log("crashing");
+            }
+        }
+    }
+
+    /// If in the living stage, checks every member we expect
+    /// heartbeats from is still sending them
+    fn validate_peers(&mut self) {
         let lid = self.leader_id();
         let current_members = self.memberships.get(&self.view_id).unwrap();
-        if let LifeCycle::Living(ref mut heart, ref mut prev_beats) = &mut self.status {
-            let now = Instant::now();
-            let mut rm = Vec::new();
-            for (&id, &prev) in prev_beats
+        let LifeCycle::Living(_, ref mut prev_beats) = &mut self.status else {
+            return;
+        };
+        let now = self.clock.now();
+        let mut rm = Vec::new();
+        for (&id, &prev) in prev_beats
+            .iter()
+            .filter(|(id, _)| current_members.contains(id))
+        {
+            let diff = { now - prev }.as_secs();
+            if diff > { HEARTBEAT_PERIOD * 2 }.as_secs() {
+                eprintln!(
+                    "{{peer_id: {}, view_id: {}, leader: {}, message:\"peer {} unreachable\"}}",
+                    self.peer_list.id(),
+                    self.view_id,
+                    lid,
+                    id,
+                );
+                self.metrics.suspected(id);
+                rm.push(id);
+            }
+        }
+        for rmid in &rm {
+            prev_beats.remove(rmid);
+        }
+
+        if self.membership == Membership::Majority {
+            // ourselves plus whoever is still heartbeating
+            let reachable = 1 + current_members
                 .iter()
-                .filter(|(id, _)| current_members.contains(id))
-            {
-                let diff = { now - prev }.as_secs();
-                if diff > { HEARTBEAT_PERIOD * 2 }.as_secs() {
-                    eprintln!(
-                        "{{peer_id: {}, view_id: {}, leader: {}, message:\"peer {} unreachable\"}}",
-                        self.peer_list.id(),
-                        self.view_id,
-                        lid,
-                        id,
-                    );
-                    rm.push(id);
+                .filter(|id| prev_beats.contains_key(id))
+                .count();
+            let blocked = reachable * 2 <= current_members.len();
+            if blocked != self.blocked {
+                self.blocked = blocked;
+                self.metrics.set_blocked(blocked);
+                if blocked {
+                    self.log(format_args!(
+                        "blocked: only {reachable} of {} members reachable",
+                        current_members.len()
+                    ));
+                } else {
+                    self.log("unblocked: a majority is reachable again");
                 }
             }
+            // the other side has the majority, it decides who stays
+            if blocked {
+                return;
+            }
+        }
+
+        if let Role::Follower(ref follow) = self.role {
+            if self.election.is_some() && rm.contains(&follow.leader_id()) {
+                self.start_election();
+            }
+        }
 
+        if let Role::Raft(ref mut raft) = self.role {
             for rmid in rm {
-                if let Role::Leader(ref mut lead) = self.role {
-                    lead.push_request(rmid, self.view_id, Operation::Delete);
-                    lead.acknowledge_ok(lead.latest_request(), lid);
+                raft.propose(rmid, Operation::Delete);
+            }
+            return;
+        }
+
+        if let Role::Leader(ref mut lead) = self.role {
+            for rmid in rm {
+                if lead.is_deleting(rmid) {
+                    continue;
+                }
+                lead.push_request(rmid, self.view_id, Operation::Delete);
+                lead.acknowledge_ok(lead.latest_request(), lid);
+                if self.membership == Membership::Majority {
+                    lead.partitioned(rmid);
                 }
-                prev_beats.remove(&rmid);
             }
+        }
+    }
 
-            if let Some(letter) = heart.check_heartbeat() {
-                assert!(matches!(letter.message(), Message::HEARTBEAT));
-                let from = letter.from_whom();
-                prev_beats.insert(from, now);
+    // sends what the gossip wants sent and installs a view if the members changed
+    fn apply_gossip(&mut self, fx: Effects) {
+        if let LifeCycle::Living(ref heart, _) = self.status {
+            for (to, message) in fx.send {
+                heart.send_to(to, &self.letter(message));
+            }
+        }
+        if fx.changes.is_empty() {
+            return;
+        }
+        for &(peer, liveness) in &fx.changes {
+            match liveness {
+                Liveness::Alive => self.log(format_args!("peer {peer} joined")),
+                Liveness::Suspect => {
+                    self.metrics.suspected(peer);
+                    self.log(format_args!("suspecting peer {peer}"));
+                }
+                Liveness::Dead => self.log(format_args!("peer {peer} unreachable")),
+                Liveness::Left => self.log(format_args!("peer {peer} left")),
+            }
+        }
+        let Role::Gossip(ref gossip) = self.role else {
+            return;
+        };
+        let members = gossip.members();
+        if self.memberships.get(&self.view_id) == Some(&members) {
+            return;
+        }
+        self.view_id += 1;
+        self.metrics.set_view(self.view_id, members.len());
+        self.memberships.insert(self.view_id, members);
+        eprintln!(
+            "{{proc_id: {}, view_id: {}, leader: {}, memb_list: {:?}}}",
+            self.peer_list.id(),
+            self.view_id,
+            self.leader_id(),
+            self.memberships[&self.view_id].iter().collect::<Vec<_>>()
+        );
+        self.publish_view();
+    }
+
+    // sends what raft wants sent and installs a view for each configuration
+    // it committed. Everyone commits the same ones in the same order, so the
+    // view ids agree without a NEWVIEW
+    fn apply_raft(&mut self, outgoing_channels: &mut Channels<impl Write>) {
+        let Role::Raft(ref mut raft) = self.role else {
+            return;
+        };
+        let outbox = raft.take_outbox();
+        let committed = raft.take_committed();
+        let leads = raft.is_leader();
+        for (to, message) in outbox {
+            if let Some(channel) = outgoing_channels.get_mut(&to) {
+                self.send_or_report(&self.letter(message), to, channel);
+            }
+        }
+        let admitted = self.peer_list.admitted();
+        if leads && !committed.is_empty() && !admitted.is_empty() {
+            let hosts = self.letter(Message::HOSTS(admitted.clone()));
+            let members = &committed[committed.len() - 1];
+            for (&id, channel) in outgoing_channels
+                .iter_mut()
+                .filter(|(id, _)| members.contains(id))
+            {
+                self.send_or_report(&hosts, id, channel);
+            }
+        }
+        for members in committed {
+            self.view_id += 1;
+            self.metrics.set_view(self.view_id, members.len());
+            if self.leaving.is_some() && !members.contains(&self.peer_list.id()) {
+                self.left = true;
+            }
+            self.memberships.insert(self.view_id, members);
+            eprintln!(
+                "{{proc_id: {}, view_id: {}, leader: {}, memb_list: {:?}}}",
+                self.peer_list.id(),
+                self.view_id,
+                self.leader_id(),
+                self.memberships[&self.view_id].iter().collect::<Vec<_>>()
+            );
+            self.publish_view();
+        }
+    }
+
+    fn check_lease(&mut self) {
+        if let Role::Leader(ref mut lead) = self.role {
+            if lead.lease.expire(self.clock.now()) {
+                self.log("lease expired, holding view changes until a majority ACKs");
             }
         }
-        Ok(())
     }
 
     pub fn proceed_reqs(
         &mut self,
         outgoing_channels: &mut Channels<impl Write>,
-    ) -> Result<(), Reasons> {
+    ) -> Result<(), Failure> {
+        let has_lease = self.has_lease();
         if let Role::Leader(ref mut lead) = self.role {
             // check if lead isnt waiting for any reqs
             // check if we have one ready to send
             // send out reqs
-            if lead.can_proceed() {
+            if lead.can_proceed() && !self.blocked && has_lease {
                 let msg = lead.start_req();
-                let letter: Letter = (self.peer_list.id(), Message::REQ(msg)).into();
+                if msg.op.changes_view() {
+                    let own_id = self.peer_list.id();
+                    lead.collect_flush(msg.request_id, own_id, self.viewsync.flush());
+                }
+                self.round_started = Some(self.clock.now());
+                let letter = self.letter(Message::REQ(msg));
 
                 let current_members = self.memberships.get(&self.view_id).unwrap();
-                for (_, channel) in outgoing_channels
+                for (&id, channel) in outgoing_channels
                     .iter_mut()
                     .filter(|(id, _)| current_members.contains(id))
                 {
-                    self.send_letter(&letter, channel)?;
+                    self.send_or_report(&letter, id, channel);
                 }
             }
         }
         Ok(())
     }
 
+    /// Sends the current view to its members, plus `released` if a peer
+    /// that asked to LEAVE was just dropped and is waiting to hear about it.
+    /// Members of the view being left get the union of its FLUSHes first.
     pub fn update_views(
         &self,
         outgoing_channels: &mut Channels<impl Write>,
-    ) -> Result<(), Reasons> {
+        released: Option<PeerId>,
+        (left_view, flushed): (ViewId, Vec<Mcast>),
+    ) -> Result<(), Failure> {
         if let Role::Leader(_) = self.role {
             let current_members = self.memberships.get(&self.view_id).unwrap();
-            let letter = (
-                self.peer_list.id(),
-                Message::NEWVIEW {
-                    view_id: self.view_id,
-                    members: current_members.clone(),
-                },
-            )
-                .into();
+            let letter = self.letter(Message::NEWVIEW {
+                view_id: self.view_id,
+                members: current_members.clone(),
+            });
+            let previous = &self.memberships[&left_view];
+            let union = self.letter(Message::FLUSHED {
+                view_id: left_view,
+                messages: flushed,
+            });
+            // so members that joined since know how to reach admitted hosts
+            let admitted = self.peer_list.admitted();
+            let hosts =
+                (!admitted.is_empty()).then(|| self.letter(Message::HOSTS(admitted.clone())));
 
             eprintln!(
                 "{{proc_id: {}, view_id: {}, leader: {0}, memb_list: {:?}}}",
@@ -291,13 +1891,344 @@ impl Data {
                 current_members.iter().collect::<Vec<_>>()
             );
 
-            for (_, channel) in outgoing_channels
+            for (&id, channel) in outgoing_channels
                 .iter_mut()
-                .filter(|(id, _)| current_members.contains(id))
+                .filter(|(id, _)| current_members.contains(id) || released == Some(**id))
             {
-                self.send_letter(&letter, channel)?;
+                if previous.contains(&id) {
+                    self.send_or_report(&union, id, channel);
+                }
+                if let Some(hosts) = &hosts {
+                    self.send_or_report(hosts, id, channel);
+                }
+                self.send_or_report(&letter, id, channel);
             }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use super::*;
+    use crate::clock::ManualClock;
+
+    // remembers what was scheduled, the test fires the ticks itself
+    #[derive(Default)]
+    struct FakeTimers(HashMap<Tick, Option<Duration>>);
+
+    impl Timers for FakeTimers {
+        fn every(&mut self, tick: Tick, _: Duration, period: Duration) -> Result<(), Failure> {
+            self.0.insert(tick, Some(period));
+            Ok(())
+        }
+
+        fn once(&mut self, tick: Tick, _: Duration) -> Result<(), Failure> {
+            self.0.insert(tick, None);
+            Ok(())
+        }
+
+        fn cancel(&mut self, tick: Tick) {
+            self.0.remove(&tick);
+        }
+    }
+
+    // every test is its own loopback address, so their heartbeat sockets don't clash
+    fn peer_list(test: &str, hosts: &[&str], hostname: &str) -> PeerList {
+        let path: PathBuf =
+            std::env::temp_dir().join(format!("prj3-state-{test}-{}.txt", std::process::id()));
+        std::fs::write(&path, hosts.join("\n")).unwrap();
+        let peer_list = PeerList::load_as(path.clone(), hostname.to_string()).unwrap();
+        std::fs::remove_file(path).unwrap();
+        peer_list
+    }
+
+    fn data(peer_list: PeerList, config: &Config, timers: &mut FakeTimers) -> Data {
+        Data::new(
+            peer_list,
+            config,
+            Metrics::default(),
+            Views::default(),
+            timers,
+        )
+        .unwrap()
+    }
+
+    fn config(clock: &ManualClock) -> Config {
+        let mut config = Config::new("unused");
+        config.membership = Membership::Majority;
+        config.clock = Arc::new(clock.clone());
+        config
+    }
+
+    // a leader of three that every host already joined, so it heartbeats
+    fn living_leader(test: &str, config: &Config, timers: &mut FakeTimers) -> Data {
+        let hosts = [".1", ".2", ".3"].map(|host| format!("127.0.{test}{host}"));
+        let hosts = hosts.each_ref().map(String::as_str);
+        let mut data = data(peer_list(test, &hosts, hosts[0]), config, timers);
+        data.memberships.insert(1, HashSet::from([1, 2, 3]));
+        data.flush_instructions(&mut Channels::<Vec<u8>>::new(), timers)
+            .unwrap();
+        assert!(matches!(data.status, LifeCycle::Living(..)));
+        data
+    }
+
+    fn channels() -> Channels<Vec<u8>> {
+        HashMap::from([(2, Vec::new()), (3, Vec::new())])
+    }
+
+    #[test]
+    fn schedules_the_failure_checks() {
+        let clock = ManualClock::new();
+        let mut timers = FakeTimers::default();
+        living_leader("31", &config(&clock), &mut timers);
+        assert_eq!(timers.0[&Tick::Check], Some(CHECK_PERIOD));
+        assert_eq!(timers.0[&Tick::Beat], Some(HEARTBEAT_PERIOD));
+    }
+
+    #[test]
+    fn deletes_a_peer_that_stops_heartbeating() {
+        let clock = ManualClock::new();
+        let mut timers = FakeTimers::default();
+        let mut data = living_leader("32", &config(&clock), &mut timers);
+        let mut channels = channels();
+
+        // peer 3 keeps ACKing and heartbeating, peer 2 has gone quiet
+        for beat in 1..=2 {
+            data.on_tick(Tick::Beat, &mut timers);
+            data.recv_datagram(&(3, Message::ACK { beat }).into());
+            data.recv_datagram(&(3, Message::HEARTBEAT { beat: 0 }).into());
+            data.on_tick(Tick::Check, &mut timers);
+            assert!(data.status().pending_requests.is_empty());
+            clock.advance(HEARTBEAT_PERIOD + Duration::from_millis(500));
+        }
+        data.on_tick(Tick::Beat, &mut timers);
+        data.recv_datagram(&(3, Message::HEARTBEAT { beat: 0 }).into());
+        data.on_tick(Tick::Check, &mut timers);
+
+        let pending = data.status().pending_requests;
+        assert_eq!(pending.len(), 1);
+        let instruction = &pending[0].instruction;
+        assert_eq!(instruction.peer_id, 2);
+        assert!(matches!(instruction.op, Operation::Delete));
+
+        data.proceed_reqs(&mut channels).unwrap();
+        assert!(!channels[&3].is_empty());
+        // a follower FLUSHes before it OKs a view change
+        let flush = Message::FLUSH {
+            request_id: instruction.request_id,
+            messages: Vec::new(),
+        };
+        data.recv_message(&(3, data.epoch, flush).into());
+        let ok = Message::OK {
+            request_id: instruction.request_id,
+            view_id: 1,
+        };
+        data.recv_message(&(3, data.epoch, ok).into());
+        data.flush_instructions(&mut channels, &mut timers).unwrap();
+        assert_eq!(data.view_id, 2);
+        assert_eq!(data.memberships[&2], HashSet::from([1, 3]));
+    }
+
+    #[test]
+    fn lease_lapses_without_acks() {
+        let clock = ManualClock::new();
+        let mut timers = FakeTimers::default();
+        let mut data = living_leader("33", &config(&clock), &mut timers);
+
+        data.on_tick(Tick::Beat, &mut timers);
+        data.recv_datagram(&(2, Message::ACK { beat: 1 }).into());
+        assert_eq!(data.status().lease, Some(lease::LEASE));
+        assert!(data.has_lease());
+
+        clock.advance(lease::LEASE);
+        data.on_tick(Tick::Check, &mut timers);
+        assert_eq!(data.status().lease, None);
+        assert!(!data.has_lease());
+        // check_lease already took it, it only lapses once
+        let Role::Leader(ref mut lead) = data.role else {
+            unreachable!()
+        };
+        assert!(!lead.lease.expire(clock.now()));
+
+        // nothing goes out until a majority ACKs again
+        let mut channels = channels();
+        lead.push_request(2, 1, Operation::Delete);
+        data.proceed_reqs(&mut channels).unwrap();
+        assert!(channels.values().all(Vec::is_empty));
+    }
+
+    #[test]
+    fn leave_gives_up_on_a_silent_leader() {
+        let clock = ManualClock::new();
+        let mut timers = FakeTimers::default();
+        let hosts = ["127.0.34.1", "127.0.34.2"];
+        let mut data = data(
+            peer_list("34", &hosts, hosts[1]),
+            &config(&clock),
+            &mut timers,
+        );
+        data.memberships.insert(1, HashSet::from([1, 2]));
+        let mut channels = HashMap::from([(1, Vec::new())]);
+
+        data.leave(&mut channels);
+        assert!(!channels[&1].is_empty());
+        assert!(!data.has_left());
+
+        clock.advance(LEAVE_TIMEOUT);
+        data.on_tick(Tick::Check, &mut timers);
+        assert!(!data.has_left());
+        clock.advance(Duration::from_millis(1));
+        assert!(data.has_left());
+    }
+
+    #[test]
+    fn blocks_without_a_majority() {
+        let clock = ManualClock::new();
+        let mut timers = FakeTimers::default();
+        let mut data = living_leader("36", &config(&clock), &mut timers);
+
+        // only peer 2 is left out: two of three is still a majority
+        clock.advance(HEARTBEAT_PERIOD * 3);
+        data.recv_datagram(&(3, Message::HEARTBEAT { beat: 0 }).into());
+        data.on_tick(Tick::Check, &mut timers);
+        assert!(!data.blocked);
+
+        // now peer 3 went quiet too, the deletes wait for the partition to heal
+        clock.advance(HEARTBEAT_PERIOD * 3);
+        data.on_tick(Tick::Check, &mut timers);
+        assert!(data.status().blocked);
+        let pending = data.status().pending_requests;
+        assert!(pending.iter().all(|p| p.instruction.peer_id == 2));
+
+        data.recv_datagram(&(3, Message::HEARTBEAT { beat: 0 }).into());
+        data.on_tick(Tick::Check, &mut timers);
+        assert!(!data.status().blocked);
+    }
+
+    #[test]
+    fn locks_wait_for_every_member_to_speak_the_locks_version() {
+        let clock = ManualClock::new();
+        let mut timers = FakeTimers::default();
+        let mut data = living_leader("46", &config(&clock), &mut timers);
+        let mut channels = channels();
+
+        data.wire_versions.insert(2, wire::LOCKS_VERSION);
+        data.wire_versions.insert(3, wire::LOCKS_VERSION - 1);
+        data.lock("a", true, &mut channels);
+        assert!(data.status().pending_requests.is_empty());
+
+        data.wire_versions.insert(3, wire::LOCKS_VERSION);
+        data.lock("a", true, &mut channels);
+        let pending = data.status().pending_requests;
+        assert!(matches!(&pending[0].instruction.op, Operation::Acquire(name) if name == "a"));
+    }
+
+    #[test]
+    fn only_the_leader_we_follow_hands_out_ids() {
+        let clock = ManualClock::new();
+        let mut timers = FakeTimers::default();
+        let hosts = ["127.0.50.1", "127.0.50.2", "127.0.50.3"];
+        let mut data = data(
+            peer_list("50", &hosts, hosts[1]),
+            &config(&clock),
+            &mut timers,
+        );
+        let hosts = |admitted: &[(PeerId, &str)]| {
+            Message::HOSTS(
+                admitted
+                    .iter()
+                    .map(|&(id, name)| (id, name.to_string()))
+                    .collect(),
+            )
+        };
+
+        // peer 3 doesn't lead, whatever it says about ids is dropped
+        data.recv_message(&(3, data.epoch, hosts(&[(1001, "intruder")])).into());
+        assert_eq!(data.peer_list.name(1001), None);
+
+        // the leader can't rename a host we know, only add new ones
+        data.recv_message(&(1, data.epoch, hosts(&[(2, "x"), (1001, "guest")])).into());
+        assert_eq!(data.peer_list.name(2), Some("127.0.50.2"));
+        assert_eq!(data.peer_list.name(1001), Some("guest"));
+        data.recv_message(&(1, data.epoch, hosts(&[(1001, "intruder")])).into());
+        assert_eq!(data.peer_list.name(1001), Some("guest"));
+        assert!(data.undialed.contains(&1001));
+
+        let path =
+            std::env::temp_dir().join(format!("prj3-state-50-{}.txt.admitted", std::process::id()));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn majority_drops_a_member_that_didnt_flush() {
+        let clock = ManualClock::new();
+        let mut timers = FakeTimers::default();
+        let hosts = [".1", ".2", ".3", ".4", ".5"].map(|host| format!("127.0.43{host}"));
+        let hosts = hosts.each_ref().map(String::as_str);
+        let mut data = data(
+            peer_list("43", &hosts, hosts[0]),
+            &config(&clock),
+            &mut timers,
+        );
+        data.memberships.insert(1, HashSet::from([1, 2, 3, 4, 5]));
+        let mut channels: Channels<Vec<u8>> = (2..=5).map(|id| (id, Vec::new())).collect();
+        data.flush_instructions(&mut channels, &mut timers).unwrap();
+        data.on_tick(Tick::Beat, &mut timers);
+        for id in [2, 3] {
+            data.recv_datagram(&(id, Message::ACK { beat: 1 }).into());
+        }
+
+        let Role::Leader(ref mut lead) = data.role else {
+            unreachable!()
+        };
+        lead.push_request(5, 1, Operation::Delete);
+        lead.acknowledge_ok(lead.latest_request(), 1);
+        data.proceed_reqs(&mut channels).unwrap();
+        let request_id = data.status().pending_requests[0].instruction.request_id;
+
+        // 2 and 3 FLUSH and OK, that's a majority. 4 never answers
+        let mcast = Mcast {
+            from: 2,
+            seq: 0,
+            view_id: 1,
+            payload: b"only 2 and 3 have it".to_vec(),
+        };
+        for id in [2, 3] {
+            let flush = Message::FLUSH {
+                request_id,
+                messages: vec![mcast.clone()],
+            };
+            data.recv_message(&(id, data.epoch, flush).into());
+            let ok = Message::OK {
+                request_id,
+                view_id: 1,
+            };
+            data.recv_message(&(id, data.epoch, ok).into());
+        }
+        data.flush_instructions(&mut channels, &mut timers).unwrap();
+        assert_eq!(data.view_id, 2);
+        assert_eq!(data.memberships[&2], HashSet::from([1, 2, 3]));
+
+        // once 4 is heard from again it's added back
+        data.recv_datagram(&(4, Message::HEARTBEAT { beat: 0 }).into());
+        let pending = data.status().pending_requests;
+        assert_eq!(pending[0].instruction.peer_id, 4);
+        assert!(matches!(pending[0].instruction.op, Operation::Add));
+    }
+
+    #[test]
+    fn crash_stops_the_heartbeats() {
+        let clock = ManualClock::new();
+        let mut timers = FakeTimers::default();
+        let mut config = config(&clock);
+        config.crash_delay = Some(Duration::from_secs(3));
+        let mut data = living_leader("35", &config, &mut timers);
+        assert_eq!(timers.0[&Tick::Crash], None);
+
+        data.on_tick(Tick::Crash, &mut timers);
+        assert!(!timers.0.contains_key(&Tick::Beat));
+    }
+}
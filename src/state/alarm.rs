@@ -0,0 +1,113 @@
+use std::{
+    os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd},
+    time::Duration,
+};
+
+use nix::sys::{
+    time::TimeSpec,
+    timer::Expiration,
+    timerfd::{ClockId, TimerFd, TimerFlags, TimerSetTimeFlags},
+};
+
+use crate::failures::{During, Failure, Op};
+
+use super::lifecycle::{Tick, Timers};
+
+/// A timerfd the main loop polls alongside the sockets, so timed work
+/// happens on the main thread instead of in timer threads.
+pub struct Alarm(TimerFd);
+
+impl Alarm {
+    pub fn new() -> Result<Self, Failure> {
+        TimerFd::new(ClockId::CLOCK_MONOTONIC, TimerFlags::TFD_NONBLOCK)
+            .map(Self)
+            .map_err(std::io::Error::from)
+            .during(Op::Timer)
+    }
+
+    /// Fires after `delay`, then every `period`
+    pub fn every(&self, delay: Duration, period: Duration) -> Result<(), Failure> {
+        self.arm(Expiration::IntervalDelayed(
+            TimeSpec::from(delay),
+            TimeSpec::from(period),
+        ))
+    }
+
+    /// Fires once after `delay`
+    pub fn once(&self, delay: Duration) -> Result<(), Failure> {
+        self.arm(Expiration::OneShot(TimeSpec::from(delay)))
+    }
+
+    fn arm(&self, expiration: Expiration) -> Result<(), Failure> {
+        self.0
+            .set(expiration, TimerSetTimeFlags::empty())
+            .map_err(std::io::Error::from)
+            .during(Op::Timer)
+    }
+
+    pub fn cancel(&self) {
+        let _ = self.0.unset();
+    }
+
+    /// True if `fd` is this alarm and it went off. Clears it so it
+    /// stops showing up as readable.
+    pub fn fired(&self, fd: RawFd) -> bool {
+        fd == self.0.as_fd().as_raw_fd() && self.0.wait().is_ok()
+    }
+}
+
+impl AsFd for Alarm {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
+
+/// One alarm per `Tick`, for the poll loop
+pub struct Alarms {
+    beat: Alarm,
+    check: Alarm,
+    crash: Alarm,
+}
+
+impl Alarms {
+    pub fn new() -> Result<Self, Failure> {
+        Ok(Self {
+            beat: Alarm::new()?,
+            check: Alarm::new()?,
+            crash: Alarm::new()?,
+        })
+    }
+
+    fn alarm(&self, tick: Tick) -> &Alarm {
+        match tick {
+            Tick::Beat => &self.beat,
+            Tick::Check => &self.check,
+            Tick::Crash => &self.crash,
+        }
+    }
+
+    pub fn fds(&self) -> [BorrowedFd<'_>; 3] {
+        [self.beat.as_fd(), self.check.as_fd(), self.crash.as_fd()]
+    }
+
+    /// Which tick went off, if `fd` is one of ours
+    pub fn fired(&self, fd: RawFd) -> Option<Tick> {
+        [Tick::Beat, Tick::Check, Tick::Crash]
+            .into_iter()
+            .find(|&tick| self.alarm(tick).fired(fd))
+    }
+}
+
+impl Timers for Alarms {
+    fn every(&mut self, tick: Tick, delay: Duration, period: Duration) -> Result<(), Failure> {
+        self.alarm(tick).every(delay, period)
+    }
+
+    fn once(&mut self, tick: Tick, delay: Duration) -> Result<(), Failure> {
+        self.alarm(tick).once(delay)
+    }
+
+    fn cancel(&mut self, tick: Tick) {
+        self.alarm(tick).cancel();
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::{BTreeMap, HashSet};
+
+use super::messaging::Causal;
+use super::PeerId;
+
+// causal broadcast. The vector clock has an entry per member of the view
+// we're in, counting what we delivered from each of them, and a message
+// waits until everything its sender had delivered when it sent it is
+// delivered here too.
+//
+// A member we only just started tracking (it joined, or we did) has no
+// count yet, the first message we get from it sets it. Dependencies on
+// messages sent before both of us were in the same view aren't waited for,
+// they're never going to arrive here.
+#[derive(Default, Debug)]
+pub struct CausalOrder {
+    clock: BTreeMap<PeerId, u64>,
+    // members we haven't heard from since we started tracking them
+    unknown: HashSet<PeerId>,
+    held: Vec<Causal>,
+}
+
+impl CausalOrder {
+    /// Stamps `payload` with our clock, counting it as delivered here
+    pub fn stamp(&mut self, own_id: PeerId, payload: Vec<u8>) -> Causal {
+        self.unknown.remove(&own_id);
+        *self.clock.entry(own_id).or_default() += 1;
+        Causal {
+            from: own_id,
+            clock: self.clock.clone(),
+            payload,
+        }
+    }
+
+    /// Holds `causal` until its dependencies are met and returns whatever
+    /// can be delivered now, in an order that respects them
+    pub fn receive(&mut self, causal: Causal) -> Vec<Causal> {
+        self.held.push(causal);
+        let mut ready = Vec::new();
+        while let Some(i) = self.held.iter().position(|c| self.deliverable(c)) {
+            let causal = self.held.swap_remove(i);
+            self.unknown.remove(&causal.from);
+            self.clock.insert(causal.from, causal.clock[&causal.from]);
+            ready.push(causal);
+        }
+        ready
+    }
+
+    fn deliverable(&self, causal: &Causal) -> bool {
+        let sent = causal.clock.get(&causal.from).copied().unwrap_or_default();
+        let next = if self.unknown.contains(&causal.from) {
+            true
+        } else {
+            match self.clock.get(&causal.from) {
+                Some(&delivered) => sent == delivered + 1,
+                // not in our view yet, maybe we haven't installed the one it's in
+                None => false,
+            }
+        };
+        next && causal.clock.iter().all(|(peer, &count)| {
+            *peer == causal.from || self.clock.get(peer).is_none_or(|&seen| count <= seen)
+        })
+    }
+
+    /// Gives the clock one entry per member of the view just installed.
+    /// Whatever was held from members that are gone is dropped.
+    pub fn resize(&mut self, members: &HashSet<PeerId>) {
+        let departed: HashSet<_> = self
+            .clock
+            .keys()
+            .chain(&self.unknown)
+            .filter(|peer| !members.contains(peer))
+            .copied()
+            .collect();
+        self.held.retain(|c| !departed.contains(&c.from));
+        self.clock.retain(|peer, _| members.contains(peer));
+        self.unknown.retain(|peer| members.contains(peer));
+        for &peer in members {
+            if !self.clock.contains_key(&peer) {
+                self.unknown.insert(peer);
+            }
+        }
+    }
+
+    pub fn clock(&self) -> &BTreeMap<PeerId, u64> {
+        &self.clock
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(members: &[PeerId]) -> CausalOrder {
+        let mut order = CausalOrder::default();
+        order.resize(&members.iter().copied().collect());
+        order
+    }
+
+    fn payloads(delivered: &[Causal]) -> Vec<&[u8]> {
+        delivered.iter().map(|c| c.payload.as_slice()).collect()
+    }
+
+    #[test]
+    fn waits_for_what_the_sender_had_delivered() {
+        let (mut a, mut b, mut c) = (member(&[1, 2, 3]), member(&[1, 2, 3]), member(&[1, 2, 3]));
+        // everyone has heard from 1 and 2 once, so their counts are known
+        let a0 = a.stamp(1, b"a0".to_vec());
+        let b0 = b.stamp(2, b"b0".to_vec());
+        b.receive(a0.clone());
+        c.receive(a0);
+        c.receive(b0);
+
+        let a1 = a.stamp(1, b"a1".to_vec());
+        b.receive(a1.clone());
+        let b1 = b.stamp(2, b"b1".to_vec());
+
+        // b1 is an answer to a1, so it waits for it
+        assert!(c.receive(b1).is_empty());
+        assert_eq!(payloads(&c.receive(a1)), [b"a1", b"b1"]);
+        assert_eq!(c.clock(), &BTreeMap::from([(1, 2), (2, 2)]));
+    }
+
+    #[test]
+    fn delivers_each_senders_messages_in_order() {
+        let (mut a, mut c) = (member(&[1, 3]), member(&[1, 3]));
+        let first = a.stamp(1, b"1".to_vec());
+        c.receive(first);
+        let second = a.stamp(1, b"2".to_vec());
+        let third = a.stamp(1, b"3".to_vec());
+        assert!(c.receive(third).is_empty());
+        assert_eq!(payloads(&c.receive(second)), [b"2", b"3"]);
+    }
+
+    #[test]
+    fn a_new_member_starts_wherever_its_first_message_is() {
+        let mut a = member(&[1]);
+        for n in 0..4 {
+            a.stamp(1, vec![n]);
+        }
+        // we only started tracking 1 now, its first four are from before
+        let mut c = member(&[1, 3]);
+        assert_eq!(c.receive(a.stamp(1, b"5".to_vec())).len(), 1);
+        assert_eq!(c.clock()[&1], 5);
+    }
+
+    #[test]
+    fn ignores_senders_outside_the_view() {
+        let mut a = member(&[1, 2]);
+        let mut c = member(&[2, 3]);
+        assert!(c.receive(a.stamp(1, b"early".to_vec())).is_empty());
+        // installing a view with 1 in it lets the held message through with the next
+        c.resize(&HashSet::from([1, 2, 3]));
+        assert_eq!(c.receive(a.stamp(1, b"next".to_vec())).len(), 2);
+    }
+
+    #[test]
+    fn drops_what_departed_members_sent() {
+        let (mut a, mut c) = (member(&[1, 3]), member(&[1, 3]));
+        c.receive(a.stamp(1, b"1".to_vec()));
+        a.stamp(1, b"lost".to_vec());
+        assert!(c.receive(a.stamp(1, b"3".to_vec())).is_empty());
+        c.resize(&HashSet::from([3]));
+        assert!(c.held.is_empty());
+        assert!(!c.clock().contains_key(&1));
+    }
+}
@@ -0,0 +1,101 @@
+use std::time::{Duration, Instant};
+
+use super::messaging::Message;
+use super::{PeerId, HEARTBEAT_PERIOD};
+
+// how long an ELECTION waits for someone outranking us to ANSWER
+const ANSWER_TIMEOUT: Duration = HEARTBEAT_PERIOD;
+// how long an ANSWER holds us off before we start over without a COORDINATOR
+const COORDINATOR_TIMEOUT: Duration = Duration::from_secs(3 * HEARTBEAT_PERIOD.as_secs());
+
+/// What a Bully election needs us to do next
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Waiting,
+    // nobody outranking us answered, we're the leader
+    Won,
+    // someone answered but never announced itself
+    Restart,
+}
+
+// the Bully algorithm. Lower ids outrank higher ones, the same order the
+// hostsfile picks the first leader in. Whoever notices the leader is gone
+// sends ELECTION to the members outranking it that are still heartbeating.
+// Any of them ANSWERs and runs its own, and whoever hears no ANSWER
+// announces itself with COORDINATOR.
+#[derive(Default, Debug)]
+pub struct Bully {
+    // when our ELECTION went out
+    started: Option<Instant>,
+    // when someone outranking us answered it
+    answered: Option<Instant>,
+    outbox: Vec<(PeerId, Message)>,
+}
+
+impl Bully {
+    pub fn running(&self) -> bool {
+        self.started.is_some() || self.answered.is_some()
+    }
+
+    /// Sends ELECTION to `outranking`, unless an election is already running.
+    /// Won right away if nobody outranks us.
+    pub fn start(&mut self, now: Instant, outranking: Vec<PeerId>) -> Verdict {
+        if self.running() {
+            return Verdict::Waiting;
+        }
+        if outranking.is_empty() {
+            return Verdict::Won;
+        }
+        for peer in outranking {
+            self.outbox.push((peer, Message::ELECTION));
+        }
+        self.started = Some(now);
+        Verdict::Waiting
+    }
+
+    /// Tells a member we outrank that we're alive, it can stand down
+    pub fn answer(&mut self, to: PeerId) {
+        self.outbox.push((to, Message::ANSWER));
+    }
+
+    pub fn answered(&mut self, now: Instant) {
+        if self.running() {
+            self.started = None;
+            self.answered = Some(now);
+        }
+    }
+
+    /// Queues our COORDINATOR for the rest of the view and ends the election
+    pub fn announce(&mut self, to: impl Iterator<Item = PeerId>) {
+        self.outbox
+            .extend(to.map(|peer| (peer, Message::COORDINATOR)));
+        self.finish();
+    }
+
+    pub fn finish(&mut self) {
+        self.started = None;
+        self.answered = None;
+    }
+
+    pub fn check(&mut self, now: Instant) -> Verdict {
+        if self
+            .started
+            .is_some_and(|since| now.saturating_duration_since(since) > ANSWER_TIMEOUT)
+        {
+            self.finish();
+            return Verdict::Won;
+        }
+        if self
+            .answered
+            .is_some_and(|since| now.saturating_duration_since(since) > COORDINATOR_TIMEOUT)
+        {
+            self.finish();
+            return Verdict::Restart;
+        }
+        Verdict::Waiting
+    }
+
+    pub fn take_outbox(&mut self) -> Vec<(PeerId, Message)> {
+        std::mem::take(&mut self.outbox)
+    }
+}
@@ -0,0 +1,436 @@
+use std::{
+    collections::{HashMap, HashSet},
+    hash::{BuildHasher, RandomState},
+    time::{Duration, Instant},
+};
+
+use super::messaging::{Liveness, Message, Rumor};
+use super::{PeerId, CHECK_PERIOD, HEARTBEAT_PERIOD};
+
+// a probe that isn't answered directly in this long goes out indirectly too
+const ACK_TIMEOUT: Duration = CHECK_PERIOD;
+// how long a suspect gets to refute it before it's declared dead
+const SUSPECT_TIMEOUT: Duration = HEARTBEAT_PERIOD.saturating_mul(3);
+// members asked to probe a target that didn't answer us
+const INDIRECT_PROBES: usize = 3;
+// most rumors piggybacked on one message, the rest wait for the next
+const MAX_RUMORS: usize = 8;
+
+#[derive(Debug)]
+struct Member {
+    incarnation: u64,
+    // when we started suspecting it
+    suspected: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Probe {
+    target: PeerId,
+    seq: u64,
+    sent: Instant,
+    indirect: bool,
+}
+
+/// What `Data` has to do after the gossip was told something
+#[derive(Default, Debug)]
+pub struct Effects {
+    pub send: Vec<(PeerId, Message)>,
+    // Alive when a member joined, Suspect, or why one was removed
+    pub changes: Vec<(PeerId, Liveness)>,
+}
+
+// SWIM: every period probe one member, ask a few others to probe it
+// if it doesn't answer, and spread what we learn on the probes themselves
+#[derive(Debug)]
+pub struct Gossiping {
+    own_id: PeerId,
+    incarnation: u64,
+    // everyone else we believe is in the group
+    members: HashMap<PeerId, Member>,
+    // incarnation each removed peer was at, news about it that isn't newer is stale
+    gone: HashMap<PeerId, u64>,
+    // K: who the news is about
+    // V: (the news, how many more messages to piggyback it on)
+    rumors: HashMap<PeerId, (Rumor, u32)>,
+    // members still to probe this round, shuffled when a round starts
+    order: Vec<PeerId>,
+    probe: Option<Probe>,
+    // K: seq of a PING sent on someone else's behalf
+    // V: (who asked, the seq they asked with, when)
+    relays: HashMap<u64, (PeerId, u64, Instant)>,
+    seq: u64,
+    joined: bool,
+    introduced: bool,
+    crashed: bool,
+    random: RandomState,
+}
+
+impl Gossiping {
+    pub fn new(own_id: PeerId) -> Self {
+        Self {
+            own_id,
+            incarnation: 0,
+            members: HashMap::new(),
+            gone: HashMap::new(),
+            rumors: HashMap::new(),
+            order: Vec::new(),
+            probe: None,
+            relays: HashMap::new(),
+            seq: 0,
+            joined: false,
+            introduced: false,
+            crashed: false,
+            random: RandomState::new(),
+        }
+    }
+
+    pub fn join(&mut self) {
+        self.joined = true;
+    }
+
+    pub fn has_joined(&self) -> bool {
+        self.joined
+    }
+
+    /// Stops probing and answering, like the process died
+    pub fn crash(&mut self) {
+        self.crashed = true;
+    }
+
+    /// Everyone we believe is in the group, us included
+    pub fn members(&self) -> HashSet<PeerId> {
+        self.members.keys().copied().chain([self.own_id]).collect()
+    }
+
+    /// Starts the next probe. The first one goes to every peer in the
+    /// hostsfile instead, to announce ourselves.
+    pub fn probe(&mut self, now: Instant, hosts: impl Iterator<Item = PeerId>) -> Effects {
+        let mut fx = Effects::default();
+        if self.crashed {
+            return fx;
+        }
+        if !self.introduced {
+            self.introduced = true;
+            for peer in hosts {
+                let seq = self.next_seq();
+                let rumors = vec![self.own_rumor(Liveness::Alive)];
+                fx.send.push((peer, Message::PING { seq, rumors }));
+            }
+            return fx;
+        }
+
+        // a probe that went the whole period unanswered means a suspect
+        if let Some(Probe { target, .. }) = self.probe.take() {
+            if let Some(member) = self.members.get(&target) {
+                let rumor = Rumor {
+                    peer: target,
+                    incarnation: member.incarnation,
+                    liveness: Liveness::Suspect,
+                };
+                self.hear(rumor, now, &mut fx);
+            }
+        }
+
+        let Some(target) = self.next_target() else {
+            return fx;
+        };
+        let seq = self.next_seq();
+        self.probe = Some(Probe {
+            target,
+            seq,
+            sent: now,
+            indirect: false,
+        });
+        let rumors = self.piggyback();
+        fx.send.push((target, Message::PING { seq, rumors }));
+        fx
+    }
+
+    /// Probes indirectly when the direct one timed out, and declares
+    /// suspects that never refuted it dead
+    pub fn check(&mut self, now: Instant) -> Effects {
+        let mut fx = Effects::default();
+        if self.crashed {
+            return fx;
+        }
+        self.relays
+            .retain(|_, (_, _, asked)| now - *asked < HEARTBEAT_PERIOD);
+
+        if let Some(probe) = self
+            .probe
+            .as_mut()
+            .filter(|p| !p.indirect && now - p.sent >= ACK_TIMEOUT)
+        {
+            probe.indirect = true;
+            let (target, seq) = (probe.target, probe.seq);
+            let mut helpers: Vec<_> = self
+                .members
+                .keys()
+                .copied()
+                .filter(|&id| id != target)
+                .collect();
+            self.shuffle(&mut helpers);
+            for helper in helpers.into_iter().take(INDIRECT_PROBES) {
+                let rumors = self.piggyback();
+                fx.send.push((
+                    helper,
+                    Message::PINGREQ {
+                        seq,
+                        target,
+                        rumors,
+                    },
+                ));
+            }
+        }
+
+        let expired: Vec<_> = self
+            .members
+            .iter()
+            .filter(|(_, m)| {
+                m.suspected
+                    .is_some_and(|since| now - since >= SUSPECT_TIMEOUT)
+            })
+            .map(|(&id, m)| Rumor {
+                peer: id,
+                incarnation: m.incarnation,
+                liveness: Liveness::Dead,
+            })
+            .collect();
+        for rumor in expired {
+            self.hear(rumor, now, &mut fx);
+        }
+        fx
+    }
+
+    /// Handles a PING, PINGREQ or PINGACK from `from`
+    pub fn receive(&mut self, from: PeerId, message: &Message, now: Instant) -> Effects {
+        let mut fx = Effects::default();
+        if self.crashed {
+            return fx;
+        }
+        let (Message::PING { rumors, .. }
+        | Message::PINGREQ { rumors, .. }
+        | Message::PINGACK { rumors, .. }) = message
+        else {
+            return fx;
+        };
+        let stranger = !self.members.contains_key(&from);
+        for &rumor in rumors {
+            self.hear(rumor, now, &mut fx);
+        }
+        // it's talking to us but we removed it, tell it so it can refute
+        let removed = self.gone.get(&from).map(|&incarnation| Rumor {
+            peer: from,
+            incarnation,
+            liveness: Liveness::Dead,
+        });
+
+        match *message {
+            Message::PING { seq, .. } => {
+                // someone new gets everything we know, not just the latest news
+                let mut rumors = if stranger {
+                    self.everything()
+                } else {
+                    self.piggyback()
+                };
+                rumors.extend(removed);
+                let target = self.own_id;
+                fx.send.push((
+                    from,
+                    Message::PINGACK {
+                        seq,
+                        target,
+                        rumors,
+                    },
+                ));
+            }
+            Message::PINGREQ { seq, target, .. } => {
+                let relay_seq = self.next_seq();
+                self.relays.insert(relay_seq, (from, seq, now));
+                let rumors = self.piggyback();
+                fx.send.push((
+                    target,
+                    Message::PING {
+                        seq: relay_seq,
+                        rumors,
+                    },
+                ));
+            }
+            Message::PINGACK { seq, target, .. } => {
+                if self.probe.as_ref().is_some_and(|p| p.seq == seq) {
+                    self.probe = None;
+                } else if let Some((asker, their_seq, _)) = self.relays.remove(&seq) {
+                    let rumors = self.piggyback();
+                    fx.send.push((
+                        asker,
+                        Message::PINGACK {
+                            seq: their_seq,
+                            target,
+                            rumors,
+                        },
+                    ));
+                }
+            }
+            _ => unreachable!(),
+        }
+        fx
+    }
+
+    /// Tells every member we're leaving, there's no one to wait for
+    pub fn leave(&mut self) -> Effects {
+        let mut fx = Effects::default();
+        if self.crashed {
+            return fx;
+        }
+        let peers: Vec<_> = self.members.keys().copied().collect();
+        for peer in peers {
+            let seq = self.next_seq();
+            let rumors = vec![self.own_rumor(Liveness::Left)];
+            fx.send.push((peer, Message::PING { seq, rumors }));
+        }
+        fx
+    }
+
+    // applies one piece of news, keeping it going if it was news to us
+    fn hear(&mut self, rumor: Rumor, now: Instant, fx: &mut Effects) {
+        let Rumor {
+            peer,
+            incarnation,
+            liveness,
+        } = rumor;
+        if peer == self.own_id {
+            // refute it with an incarnation nobody can have seen yet
+            if matches!(liveness, Liveness::Suspect | Liveness::Dead)
+                && incarnation >= self.incarnation
+            {
+                self.incarnation = incarnation + 1;
+                self.spread(self.own_rumor(Liveness::Alive));
+            }
+            return;
+        }
+        if let Some(&removed_at) = self.gone.get(&peer) {
+            if incarnation <= removed_at || liveness != Liveness::Alive {
+                return;
+            }
+            self.gone.remove(&peer);
+        }
+
+        let news = match (self.members.get_mut(&peer), liveness) {
+            (None, Liveness::Alive) => {
+                self.members.insert(
+                    peer,
+                    Member {
+                        incarnation,
+                        suspected: None,
+                    },
+                );
+                true
+            }
+            (None, Liveness::Dead | Liveness::Left) => {
+                self.gone.insert(peer, incarnation);
+                false
+            }
+            (None, Liveness::Suspect) => false,
+            (Some(member), Liveness::Alive) if incarnation > member.incarnation => {
+                member.incarnation = incarnation;
+                member.suspected = None;
+                self.spread(rumor);
+                return;
+            }
+            (Some(member), Liveness::Suspect)
+                if incarnation > member.incarnation
+                    || (incarnation == member.incarnation && member.suspected.is_none()) =>
+            {
+                member.incarnation = incarnation;
+                member.suspected = Some(now);
+                true
+            }
+            (Some(member), Liveness::Dead | Liveness::Left)
+                if incarnation >= member.incarnation =>
+            {
+                self.members.remove(&peer);
+                self.gone.insert(peer, incarnation);
+                self.order.retain(|&id| id != peer);
+                if self.probe.as_ref().is_some_and(|p| p.target == peer) {
+                    self.probe = None;
+                }
+                true
+            }
+            _ => false,
+        };
+        if news {
+            self.spread(rumor);
+            fx.changes.push((peer, liveness));
+        }
+    }
+
+    fn own_rumor(&self, liveness: Liveness) -> Rumor {
+        Rumor {
+            peer: self.own_id,
+            incarnation: self.incarnation,
+            liveness,
+        }
+    }
+
+    // queues news to piggyback, replacing anything older about the same peer
+    fn spread(&mut self, rumor: Rumor) {
+        // enough sends to reach everyone with high probability, a few times log(n)
+        let sends = 3 * (usize::BITS - (self.members.len() + 1).leading_zeros());
+        self.rumors.insert(rumor.peer, (rumor, sends));
+    }
+
+    // the news sent the fewest times so far
+    fn piggyback(&mut self) -> Vec<Rumor> {
+        let mut freshest: Vec<_> = self.rumors.values().copied().collect();
+        freshest.sort_by_key(|(_, left)| std::cmp::Reverse(*left));
+        freshest.truncate(MAX_RUMORS);
+        for (rumor, _) in &freshest {
+            if let Some((_, left)) = self.rumors.get_mut(&rumor.peer) {
+                *left -= 1;
+                if *left == 0 {
+                    self.rumors.remove(&rumor.peer);
+                }
+            }
+        }
+        freshest.into_iter().map(|(rumor, _)| rumor).collect()
+    }
+
+    // the whole member list, for a peer that just introduced itself
+    fn everything(&self) -> Vec<Rumor> {
+        self.members
+            .iter()
+            .map(|(&peer, m)| Rumor {
+                peer,
+                incarnation: m.incarnation,
+                liveness: match m.suspected {
+                    Some(_) => Liveness::Suspect,
+                    None => Liveness::Alive,
+                },
+            })
+            .chain([self.own_rumor(Liveness::Alive)])
+            .collect()
+    }
+
+    // round robin through the members in a fresh random order each round
+    fn next_target(&mut self) -> Option<PeerId> {
+        if self.order.is_empty() {
+            let mut order: Vec<_> = self.members.keys().copied().collect();
+            self.shuffle(&mut order);
+            self.order = order;
+        }
+        self.order.pop()
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    // Fisher-Yates, seeded per process by std's hasher keys
+    fn shuffle(&self, ids: &mut [PeerId]) {
+        for i in (1..ids.len()).rev() {
+            let j = self.random.hash_one((self.seq, i)) as usize % (i + 1);
+            ids.swap(i, j);
+        }
+    }
+}
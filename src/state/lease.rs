@@ -0,0 +1,173 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use super::{PeerId, HEARTBEAT_PERIOD};
+
+/// How long a majority of ACKs for one beat lets the leader act, counted from
+/// when that beat went out
+pub const LEASE: Duration = HEARTBEAT_PERIOD.saturating_mul(2);
+
+// the leader's right to change the view, renewed by ACKed HEARTBEATs
+#[derive(Default, Debug)]
+pub struct Lease {
+    beat: u64,
+    // K: beat number
+    // V: (when it was sent, members that ACKed it)
+    unanswered: HashMap<u64, (Instant, HashSet<PeerId>)>,
+    until: Option<Instant>,
+}
+
+impl Lease {
+    /// Numbers the next HEARTBEAT, sent at `now`, and starts collecting ACKs for it
+    pub fn next_beat(&mut self, now: Instant) -> u64 {
+        self.beat += 1;
+        // a beat this old can't extend the lease past now anymore
+        self.unanswered.retain(|_, (sent, _)| now - *sent < LEASE);
+        self.unanswered.insert(self.beat, (now, HashSet::new()));
+        self.beat
+    }
+
+    /// Counts `from`'s ACK of `beat`. True if that gave a majority of `members`
+    /// and the lease wasn't held before, so the leader starts a new epoch.
+    pub fn ack(
+        &mut self,
+        beat: u64,
+        from: PeerId,
+        members: &HashSet<PeerId>,
+        now: Instant,
+    ) -> bool {
+        let Some((sent, acks)) = self.unanswered.get_mut(&beat) else {
+            return false;
+        };
+        acks.insert(from);
+        if members.intersection(acks).count() * 2 <= members.len() {
+            return false;
+        }
+
+        let until = *sent + LEASE;
+        // older beats can only renew it for less
+        self.unanswered.retain(|&b, _| b > beat);
+        let was_held = self.held(now);
+        self.until = Some(until);
+        !was_held
+    }
+
+    pub fn held(&self, now: Instant) -> bool {
+        self.until.is_some_and(|until| now < until)
+    }
+
+    /// Time left on the lease at `now`, None if it isn't held
+    pub fn remaining(&self, now: Instant) -> Option<Duration> {
+        self.until
+            .and_then(|until| until.checked_duration_since(now))
+    }
+
+    /// True (once) if the lease ran out since the last call
+    pub fn expire(&mut self, now: Instant) -> bool {
+        let lapsed = self.until.is_some() && !self.held(now);
+        if lapsed {
+            self.until = None;
+        }
+        lapsed
+    }
+
+    /// Gives the lease up, a peer is already on a newer epoch
+    pub fn revoke(&mut self) {
+        self.until = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members() -> HashSet<PeerId> {
+        HashSet::from([1, 2, 3, 4, 5])
+    }
+
+    #[test]
+    fn held_once_a_majority_acks() {
+        let start = Instant::now();
+        let mut lease = Lease::default();
+        let beat = lease.next_beat(start);
+        assert!(!lease.ack(beat, 1, &members(), start));
+        assert!(!lease.ack(beat, 2, &members(), start));
+        // the ack that makes a majority starts a new epoch, later ones don't
+        assert!(lease.ack(beat, 3, &members(), start));
+        assert!(!lease.ack(beat, 4, &members(), start));
+        assert!(lease.held(start));
+        assert_eq!(lease.remaining(start), Some(LEASE));
+    }
+
+    #[test]
+    fn runs_from_when_the_beat_went_out() {
+        let start = Instant::now();
+        let mut lease = Lease::default();
+        let beat = lease.next_beat(start);
+        let late = start + Duration::from_secs(1);
+        for from in 1..=3 {
+            lease.ack(beat, from, &members(), late);
+        }
+        assert_eq!(lease.remaining(late), Some(LEASE - Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn acks_from_outside_the_view_dont_count() {
+        let start = Instant::now();
+        let mut lease = Lease::default();
+        let beat = lease.next_beat(start);
+        for from in [1, 7, 8, 9] {
+            assert!(!lease.ack(beat, from, &members(), start));
+        }
+        assert!(!lease.held(start));
+    }
+
+    #[test]
+    fn expires_once() {
+        let start = Instant::now();
+        let mut lease = Lease::default();
+        assert!(!lease.expire(start));
+        let beat = lease.next_beat(start);
+        for from in 1..=3 {
+            lease.ack(beat, from, &members(), start);
+        }
+        assert!(!lease.expire(start + LEASE / 2));
+        assert!(lease.expire(start + LEASE));
+        assert!(!lease.expire(start + LEASE));
+        assert!(!lease.held(start + LEASE));
+    }
+
+    #[test]
+    fn old_beats_cant_renew_it() {
+        let start = Instant::now();
+        let mut lease = Lease::default();
+        let first = lease.next_beat(start);
+        let second = lease.next_beat(start + HEARTBEAT_PERIOD);
+        for from in 1..=3 {
+            lease.ack(second, from, &members(), start + HEARTBEAT_PERIOD);
+        }
+        // the second beat's majority dropped the first one
+        for from in 1..=3 {
+            assert!(!lease.ack(first, from, &members(), start + HEARTBEAT_PERIOD));
+        }
+        assert_eq!(lease.remaining(start + HEARTBEAT_PERIOD), Some(LEASE));
+        // and one sent a lease ago is forgotten by the next beat
+        let mut lease = Lease::default();
+        let stale = lease.next_beat(start);
+        lease.next_beat(start + LEASE);
+        assert!(!lease.ack(stale, 1, &HashSet::from([1]), start + LEASE));
+    }
+
+    #[test]
+    fn revoked_leases_are_gone() {
+        let start = Instant::now();
+        let mut lease = Lease::default();
+        let beat = lease.next_beat(start);
+        lease.ack(beat, 1, &HashSet::from([1]), start);
+        lease.revoke();
+        assert!(!lease.held(start));
+        assert!(!lease.expire(start + LEASE));
+    }
+}
@@ -1,100 +1,83 @@
 use std::{
     collections::HashMap,
-    os::fd::{AsFd, AsRawFd},
-    sync::{
-        mpsc::{channel, Receiver},
-        Arc,
-    },
-    thread::spawn,
+    net::SocketAddrV4,
+    os::fd::{BorrowedFd, RawFd},
     time::{Duration, Instant},
 };
 
-use chrono::TimeDelta;
-use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
-use timer::{Guard, Timer};
-
 use crate::{
-    failures::Reasons,
+    failures::Failure,
     hostsfile::{Broadcaster, PeerList},
-    Letter, Message,
+    metrics::Metrics,
+    Letter,
 };
 
 use super::PeerId;
 
+/// Timed work `Data` needs done, each transport decides how to wait for it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Tick {
+    // send a HEARTBEAT to everyone
+    Beat,
+    // look for peers that stopped heartbeating
+    Check,
+    // the --crash-delay ran out
+    Crash,
+}
+
+/// Schedules `Tick`s for `Data`, the transport hands them back through `Data::on_tick`
+/// Ticks only say when to look, what's overdue is measured on `Config::clock`,
+/// so with a `ManualClock` whoever drives `Data` fires them as it likes.
+pub trait Timers {
+    /// `tick` fires after `delay`, then every `period`
+    fn every(&mut self, tick: Tick, delay: Duration, period: Duration) -> Result<(), Failure>;
+    /// `tick` fires once after `delay`
+    fn once(&mut self, tick: Tick, delay: Duration) -> Result<(), Failure>;
+    fn cancel(&mut self, tick: Tick);
+}
+
 // sends stuff real fast real easy
 pub struct Heart {
-    broadcaster: Arc<Broadcaster>,
-    rec: Receiver<Letter>,
-    timer: Option<Timer>,
+    broadcaster: Broadcaster,
 }
 
 impl Heart {
-    pub fn new(peer_list: &PeerList) -> Result<Self, Reasons> {
-        let broadcaster = Arc::new(peer_list.make_broadcaster()?);
-        let (tx, rec) = channel::<Letter>();
-
-        let bc = Arc::clone(&broadcaster);
-        // NOTE: this thread seems to be running just fine
-        spawn(move || {
-            let mut poll_fds: Vec<PollFd> =
-                bc.0.iter()
-                    .map(|(_, s)| PollFd::new(s.as_fd(), PollFlags::POLLIN))
-                    .collect();
-            let tx = tx;
-
-            loop {
-                if let Ok(events) = poll(&mut poll_fds, PollTimeout::NONE) {
-                    if events == 0 {
-                        continue;
-                    }
-                }
-                for pfd in poll_fds.iter().filter(|p| {
-                    p.revents()
-                        .unwrap_or(PollFlags::empty())
-                        .contains(PollFlags::POLLIN)
-                }) {
-                    let sock =
-                        bc.0.iter()
-                            .find(|(_, s)| s.as_raw_fd() == pfd.as_fd().as_raw_fd())
-                            .expect("Socket should exist");
-
-                    let mut buf = [0; 1024];
-                    let bytes_read = sock.1.recv(&mut buf).expect("Successful Read");
-
-                    if let Ok(letter) = bincode::deserialize::<Letter>(&buf[..bytes_read]) {
-                        tx.send(letter).expect("Channel couldn't send");
-                    }
-                }
-            }
-        });
-
+    pub fn new(
+        peer_list: &PeerList,
+        metrics: Metrics,
+        multicast: Option<SocketAddrV4>,
+    ) -> Result<Self, Failure> {
         Ok(Heart {
-            broadcaster,
-            rec,
-            timer: None,
+            broadcaster: peer_list.make_broadcaster(metrics, multicast)?,
         })
     }
 
-    pub fn start(&mut self, repeat: Duration) -> Guard {
-        assert!(self.timer.is_none());
-        let bc = Arc::clone(&self.broadcaster);
+    pub fn beat(&self, letter: &Letter) {
+        self.broadcaster.beat(letter);
+    }
 
-        let timer = Timer::new();
-        let out = timer.schedule_repeating(
-            TimeDelta::from_std(repeat).expect("Should be small time period"),
-            move || {
-                bc.beat();
-            },
-        );
+    pub fn send_to(&self, to: PeerId, letter: &Letter) {
+        self.broadcaster.send_to(to, letter);
+    }
 
-        self.timer = Some(timer);
+    /// Beats to the hosts in a reloaded hostsfile
+    pub fn update(&mut self, peer_list: &PeerList) -> Result<(), Failure> {
+        self.broadcaster.update(peer_list)
+    }
+
+    /// Every heartbeat socket, for the main loop to poll
+    pub fn fds(&self) -> impl Iterator<Item = BorrowedFd<'_>> {
+        self.broadcaster.fds()
+    }
 
-        out
+    #[cfg(feature = "tokio")]
+    pub fn sockets(&self) -> impl Iterator<Item = &std::net::UdpSocket> {
+        self.broadcaster.sockets().into_iter()
     }
 
-    /// Polls for heartbeats from peers
-    pub fn check_heartbeat(&mut self) -> Option<Letter> {
-        self.rec.try_recv().ok()
+    /// The heartbeats that arrived on `fd`, empty if it isn't ours
+    pub fn recv(&self, fd: RawFd) -> Vec<Letter> {
+        self.broadcaster.recv(fd)
     }
 }
 
@@ -0,0 +1,95 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+use super::messaging::Operation;
+use super::PeerId;
+
+// named locks. Every member keeps the committed table, the leader sends it
+// out whenever a REQ/OK round changes it. Only the leader queues waiters
+// and decides who's granted a lock next.
+#[derive(Default, Debug)]
+pub struct Locks {
+    // K: lock name
+    // V: the member holding it
+    held: BTreeMap<String, PeerId>,
+    // members waiting for each lock, first come first served
+    waiting: HashMap<String, VecDeque<PeerId>>,
+    // locks with an Acquire going through its round
+    granting: HashSet<String>,
+}
+
+impl Locks {
+    pub fn held(&self) -> &BTreeMap<String, PeerId> {
+        &self.held
+    }
+
+    /// Takes the table the leader sent
+    pub fn replace(&mut self, held: BTreeMap<String, PeerId>) {
+        self.held = held;
+    }
+
+    /// True if `name` can be granted to `peer` right away. Otherwise it
+    /// waits its turn, and comes back out of a later `commit`.
+    pub fn request(&mut self, name: &str, peer: PeerId) -> bool {
+        if self.held.get(name) == Some(&peer) {
+            return false;
+        }
+        if !self.held.contains_key(name) && !self.granting.contains(name) {
+            self.granting.insert(name.to_string());
+            return true;
+        }
+        let queue = self.waiting.entry(name.to_string()).or_default();
+        if !queue.contains(&peer) {
+            queue.push_back(peer);
+        }
+        false
+    }
+
+    pub fn holds(&self, name: &str, peer: PeerId) -> bool {
+        self.held.get(name) == Some(&peer)
+    }
+
+    /// Applies an Acquire or Release whose round just completed. Returns the
+    /// next grant to start, if a released lock had someone waiting.
+    pub fn commit(&mut self, op: &Operation, peer: PeerId) -> Option<(String, PeerId)> {
+        match op {
+            Operation::Acquire(name) => {
+                self.granting.remove(name);
+                self.held.insert(name.clone(), peer);
+                None
+            }
+            Operation::Release(name) if self.holds(name, peer) => {
+                self.held.remove(name);
+                self.next(name)
+            }
+            _ => None,
+        }
+    }
+
+    /// Lets go of everything a member held or waited for, it's no longer in
+    /// the view. Returns the grants to start for the locks it freed.
+    pub fn drop_member(&mut self, peer: PeerId) -> Vec<(String, PeerId)> {
+        for queue in self.waiting.values_mut() {
+            queue.retain(|&waiter| waiter != peer);
+        }
+        let freed: Vec<_> = self
+            .held
+            .iter()
+            .filter(|(_, &holder)| holder == peer)
+            .map(|(name, _)| name.clone())
+            .collect();
+        freed
+            .into_iter()
+            .filter_map(|name| {
+                self.held.remove(&name);
+                self.next(&name)
+            })
+            .collect()
+    }
+
+    // the next waiter for a free lock, if there's one
+    fn next(&mut self, name: &str) -> Option<(String, PeerId)> {
+        let waiter = self.waiting.get_mut(name)?.pop_front()?;
+        self.granting.insert(name.to_string());
+        Some((name.to_string(), waiter))
+    }
+}
@@ -1,13 +1,25 @@
-use std::collections::HashSet;
+use std::{
+    collections::{BTreeMap, HashSet},
+    time::Duration,
+};
 
 use serde::{Deserialize, Serialize};
-#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Operation {
     Add,
     Delete,
+    // the named lock goes to or is given up by the instruction's peer
+    Acquire(String),
+    Release(String),
+}
+impl Operation {
+    /// Add and Delete install a new view, the lock operations don't
+    pub fn changes_view(&self) -> bool {
+        matches!(self, Operation::Add | Operation::Delete)
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Instruction {
     pub request_id: u32,
     pub peer_id: usize,
@@ -15,8 +27,10 @@ pub struct Instruction {
     pub op: Operation,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
+    // every variant needs its own type number in `socketry::wire::kind`
     // Part 1
     REQ(Instruction),
     JOIN,
@@ -30,24 +44,263 @@ pub enum Message {
     },
 
     // Part 2
-    HEARTBEAT,
+    // numbered so followers can ACK the leader's beats to renew its lease
+    HEARTBEAT {
+        beat: u64,
+    },
+    ACK {
+        beat: u64,
+    },
+
+    // follower asking the leader to be deleted from the view before it exits
+    LEAVE,
+
+    // admin commands, answered on the same connection they came in on.
+    // an admin LEAVE makes the receiving peer leave, KICK asks the leader to delete a member
+    STATUS,
+    REPORT(Box<Status>),
+    KICK(usize),
+    VIEWS,
+    HISTORY(Vec<(u32, HashSet<usize>)>),
+
+    // gossip mode probes, over the heartbeat sockets. each one carries
+    // whatever membership news the sender is still spreading.
+    // PINGREQ asks the receiver to probe `target` on the sender's behalf
+    PING {
+        seq: u64,
+        rumors: Vec<Rumor>,
+    },
+    PINGREQ {
+        seq: u64,
+        target: usize,
+        rumors: Vec<Rumor>,
+    },
+    PINGACK {
+        seq: u64,
+        target: usize,
+        rumors: Vec<Rumor>,
+    },
+
+    // view synchronous multicast. members FLUSH what they delivered in the
+    // view to the leader before OKing a change, and the leader sends the union
+    // back as FLUSHED right before the NEWVIEW. SEND is the admin command
+    MCAST(Mcast),
+    FLUSH {
+        request_id: u32,
+        messages: Vec<Mcast>,
+    },
+    FLUSHED {
+        view_id: u32,
+        messages: Vec<Mcast>,
+    },
+    SEND(Vec<u8>),
+
+    // totally ordered broadcast. members (and admins) SUBMIT to the leader,
+    // which numbers each one and forwards it as ORDERED. a member that finds
+    // a gap in the numbers asks for everything from `from_seq` on
+    SUBMIT(Vec<u8>),
+    ORDERED(Ordered),
+    RESEND {
+        from_seq: u64,
+    },
+
+    // causal broadcast, straight to every member of the view.
+    // CSEND is the admin command
+    CAUSAL(Causal),
+    CSEND(Vec<u8>),
+
+    // lock service. members (and admins, on a member's behalf) ask the leader
+    // to ACQUIRE or RELEASE a named lock, it runs each change through a REQ/OK
+    // round and sends every member the resulting table as LOCKS
+    ACQUIRE(String),
+    RELEASE(String),
+    LOCKS(BTreeMap<String, usize>),
+
+    // Bully election, see `state::Election`. ELECTION goes to every member
+    // outranking the sender, which ANSWERs it, and the winner tells the
+    // view it's the leader with COORDINATOR
+    ELECTION,
+    ANSWER,
+    COORDINATOR,
+
+    // Raft, see `state::Membership::Raft`. JOIN and LEAVE go to whoever
+    // leads, and it puts the change in the log it sends with APPENDENTRIES
+    REQUESTVOTE {
+        term: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    VOTE {
+        term: u64,
+        granted: bool,
+    },
+    APPENDENTRIES {
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    },
+    APPENDED {
+        term: u64,
+        success: bool,
+        // on failure, where the leader should try again from
+        match_index: u64,
+    },
+
+    // admission of hosts the hostsfile doesn't list. A guest sends ADMIT
+    // as an admin letter, the leader answers ADMITTED with the id it gave
+    // it and every admitted host, and sends members the hosts as HOSTS
+    ADMIT {
+        name: String,
+        token: String,
+    },
+    ADMITTED {
+        peer_id: usize,
+        hosts: BTreeMap<usize, String>,
+    },
+    HOSTS(BTreeMap<usize, String>),
+}
+
+/// An application message, delivered only to members of the view it was sent in
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Mcast {
+    pub from: usize,
+    pub view_id: u32,
+    pub seq: u64,
+    pub payload: Vec<u8>,
+}
+
+/// An application message numbered by the leader. Every member delivers
+/// them in `seq` order, starting from 1.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Ordered {
+    pub seq: u64,
+    pub from: usize,
+    pub payload: Vec<u8>,
+}
+
+/// An application message stamped with its sender's vector clock: how many
+/// messages from each member of its view it had delivered, this one included
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Causal {
+    pub from: usize,
+    pub clock: BTreeMap<usize, u64>,
+    pub payload: Vec<u8>,
+}
+
+/// One entry in the Raft log
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    pub term: u64,
+    pub step: Step,
+}
+
+/// Each membership change takes two entries: the joint configuration, which
+/// needs a majority of the old members and of the new ones, then the new
+/// configuration on its own
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Step {
+    // what a new leader appends, so it has an entry of its own term to commit
+    Noop,
+    Joint(usize, Operation),
+    Final(usize, Operation),
+}
+
+/// What a gossiping peer believes about one member
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Liveness {
+    Alive,
+    Suspect,
+    // stopped answering probes, even indirect ones
+    Dead,
+    Left,
+}
+
+/// One piece of membership news. Only the member itself raises its
+/// incarnation, to refute being suspected, so a higher one is always newer.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Rumor {
+    pub peer: usize,
+    pub incarnation: u64,
+    pub liveness: Liveness,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum RoleKind {
+    Leader,
+    Follower,
+    Gossip,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum Stage {
+    Born,
+    Living,
+}
+
+/// A request the leader is still collecting OKs for
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingRequest {
+    pub instruction: Instruction,
+    pub oks: HashSet<usize>,
+}
+
+/// Snapshot of what a process thinks the world looks like
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Status {
+    pub peer_id: usize,
+    pub role: RoleKind,
+    pub stage: Stage,
+    pub view_id: u32,
+    pub members: HashSet<usize>,
+    pub leader_id: usize,
+    // highest leader epoch this process has seen
+    pub epoch: u64,
+    // time left on the leader's lease, None on followers or once it lapsed
+    pub lease: Option<Duration>,
+    // only filled in on the leader
+    pub pending_requests: Vec<PendingRequest>,
+    // only filled in on followers
+    pub ack_queue: Vec<Instruction>,
+    // time since the last heartbeat from each peer, empty while Born
+    pub heartbeat_ages: Vec<(usize, Duration)>,
+    // can't reach a majority of the view, only ever set in Majority mode
+    pub blocked: bool,
+    // seq of the last totally ordered message delivered
+    pub ordered: u64,
+    // causal messages delivered from each member we've heard from
+    pub clock: BTreeMap<usize, u64>,
+    // every lock held, by whom
+    pub locks: BTreeMap<String, usize>,
 }
 
 // Need this because as far as I know there isn't a way to get the from
 // id over a TCP connection
 // also it's a letter bc it's a message with an address :-)
+// the epoch is the sender's leader epoch, the fencing token followers check
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Letter(usize, Message);
+pub struct Letter(usize, u64, Message);
+// letters from outside the group (admin clients) carry no epoch
 impl From<(usize, Message)> for Letter {
     fn from(value: (usize, Message)) -> Self {
-        Self(value.0, value.1)
+        Self(value.0, 0, value.1)
+    }
+}
+impl From<(usize, u64, Message)> for Letter {
+    fn from(value: (usize, u64, Message)) -> Self {
+        Self(value.0, value.1, value.2)
     }
 }
 impl Letter {
+    #[allow(clippy::wrong_self_convention)]
     pub fn from_whom(&self) -> usize {
         self.0
     }
+    pub fn epoch(&self) -> u64 {
+        self.1
+    }
     pub fn message(&self) -> &Message {
-        &self.1
+        &self.2
     }
 }
@@ -0,0 +1,775 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    hash::{BuildHasher, RandomState},
+    time::{Duration, Instant},
+};
+
+use super::messaging::{Entry, Message, Operation, Step};
+use super::{PeerId, DEFAULT_LEADER_ID, HEARTBEAT_PERIOD};
+
+// a follower that hears nothing from a leader for this long, plus up to as
+// long again picked at random, stands for election
+const ELECTION_TIMEOUT: Duration = HEARTBEAT_PERIOD;
+// a peer outside the configuration asks to JOIN again this often
+const JOIN_RETRY: Duration = HEARTBEAT_PERIOD;
+// most entries sent in one APPENDENTRIES
+const MAX_ENTRIES: usize = 64;
+
+#[derive(Debug)]
+enum State {
+    Follower,
+    Candidate {
+        votes: HashSet<PeerId>,
+    },
+    Leader {
+        // K: voter
+        // V: index of the next entry to send it
+        next: HashMap<PeerId, u64>,
+        // K: voter
+        // V: highest entry known to be in its log
+        matched: HashMap<PeerId, u64>,
+        // changes waiting for the one in the log to be committed
+        queued: VecDeque<(PeerId, Operation)>,
+    },
+}
+
+// Raft, for membership only. The log holds membership changes, each as a
+// joint configuration (a majority of the old members and of the new ones
+// needed) followed by the new one on its own. Configurations take effect as
+// soon as they're in the log, and a view is installed each time one is
+// committed, so every member numbers the views the same.
+#[derive(Debug)]
+pub struct Rafting {
+    own_id: PeerId,
+    term: u64,
+    voted_for: Option<PeerId>,
+    // entry i is at log[i - 1]
+    log: Vec<Entry>,
+    commit: u64,
+    applied: u64,
+    state: State,
+    leader: Option<PeerId>,
+    // when we last heard from a leader or started an election
+    heard: Option<Instant>,
+    timeout: Duration,
+    // when we last asked to JOIN, until we're in the configuration
+    joining: Option<Instant>,
+    crashed: bool,
+    outbox: Vec<(PeerId, Message)>,
+    // the members of each configuration committed, to install as views
+    committed: Vec<HashSet<PeerId>>,
+    random: RandomState,
+}
+
+impl Rafting {
+    pub fn new(own_id: PeerId) -> Self {
+        let mut raft = Self {
+            own_id,
+            term: 0,
+            voted_for: None,
+            log: Vec::new(),
+            commit: 0,
+            applied: 0,
+            state: State::Follower,
+            leader: None,
+            heard: None,
+            timeout: ELECTION_TIMEOUT,
+            joining: None,
+            crashed: false,
+            outbox: Vec::new(),
+            committed: Vec::new(),
+            random: RandomState::new(),
+        };
+        raft.reset_timeout();
+        raft
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn leader(&self) -> Option<PeerId> {
+        self.leader
+    }
+
+    pub fn is_leader(&self) -> bool {
+        matches!(self.state, State::Leader { .. })
+    }
+
+    /// Stops ticking and answering, like the process died
+    pub fn crash(&mut self) {
+        self.crashed = true;
+    }
+
+    pub fn take_outbox(&mut self) -> Vec<(PeerId, Message)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// The members of every configuration committed since the last call, in order
+    pub fn take_committed(&mut self) -> Vec<HashSet<PeerId>> {
+        std::mem::take(&mut self.committed)
+    }
+
+    /// Asks every host to add us, until one that leads does
+    pub fn join(&mut self, now: Instant, hosts: impl Iterator<Item = PeerId>) {
+        if self.voters().contains(&self.own_id) {
+            return;
+        }
+        self.joining = Some(now);
+        let own_id = self.own_id;
+        self.outbox.extend(
+            hosts
+                .filter(|&id| id != own_id)
+                .map(|id| (id, Message::JOIN)),
+        );
+    }
+
+    /// Asks the leader to take us out of the configuration. False if
+    /// there's nobody to ask, and we can just go.
+    pub fn leave(&mut self) -> bool {
+        match self.leader {
+            // a lone member has nobody left to commit its removal
+            Some(leader) if leader == self.own_id && self.voters().len() > 1 => {
+                self.propose(self.own_id, Operation::Delete);
+                true
+            }
+            Some(leader) => {
+                self.outbox.push((leader, Message::LEAVE));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Queues a membership change, if we lead. Changes go into the log one
+    /// at a time, and ones that change nothing are dropped.
+    pub fn propose(&mut self, peer_id: PeerId, op: Operation) {
+        let State::Leader { ref mut queued, .. } = self.state else {
+            return;
+        };
+        if !queued
+            .iter()
+            .any(|(id, queued_op)| *id == peer_id && same_change(queued_op, &op))
+        {
+            queued.push_back((peer_id, op));
+        }
+        self.next_change();
+        self.replicate();
+    }
+
+    /// Runs the election timer, or sends the followers whatever they're
+    /// missing, an empty APPENDENTRIES keeping them from standing
+    pub fn tick(&mut self, now: Instant, hosts: impl Iterator<Item = PeerId>) {
+        if self.crashed {
+            return;
+        }
+        let heard = *self.heard.get_or_insert(now);
+        if self.is_leader() {
+            self.replicate();
+            return;
+        }
+        let (old, new) = self.config();
+        let is_voter = old.contains(&self.own_id) || new.is_some_and(|n| n.contains(&self.own_id));
+        if !is_voter {
+            if self
+                .joining
+                .is_some_and(|since| now.saturating_duration_since(since) > JOIN_RETRY)
+            {
+                self.join(now, hosts);
+            }
+            return;
+        }
+        // nobody else to hear from, no point waiting
+        let alone = self.voters().len() == 1;
+        if alone || now.saturating_duration_since(heard) > self.timeout {
+            self.stand(now);
+        }
+    }
+
+    /// Takes in a Raft letter from a peer
+    pub fn receive(&mut self, from: PeerId, message: &Message, now: Instant) {
+        if self.crashed {
+            return;
+        }
+        match *message {
+            Message::JOIN => {
+                self.propose(from, Operation::Add);
+            }
+            Message::LEAVE => {
+                self.propose(from, Operation::Delete);
+            }
+            Message::REQUESTVOTE {
+                term,
+                last_log_index,
+                last_log_term,
+            } => {
+                self.request_vote(from, term, last_log_index, last_log_term, now);
+            }
+            Message::VOTE { term, granted } => {
+                self.see_term(term);
+                self.vote(from, term, granted)
+            }
+            Message::APPENDENTRIES {
+                term,
+                prev_log_index,
+                prev_log_term,
+                ref entries,
+                leader_commit,
+            } => self.append_entries(
+                from,
+                term,
+                (prev_log_index, prev_log_term),
+                entries,
+                leader_commit,
+                now,
+            ),
+            Message::APPENDED {
+                term,
+                success,
+                match_index,
+            } => {
+                self.see_term(term);
+                self.appended(from, term, success, match_index)
+            }
+            _ => {}
+        }
+    }
+
+    // a newer term means whoever we were following or running against lost
+    fn see_term(&mut self, term: u64) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.state = State::Follower;
+            self.leader = None;
+        }
+    }
+
+    // stands for election in the next term
+    fn stand(&mut self, now: Instant) {
+        self.term += 1;
+        self.voted_for = Some(self.own_id);
+        self.leader = None;
+        self.heard = Some(now);
+        self.reset_timeout();
+        self.state = State::Candidate {
+            votes: HashSet::from([self.own_id]),
+        };
+        let (last_log_index, last_log_term) = self.last_entry();
+        for peer in self.voters() {
+            if peer != self.own_id {
+                self.outbox.push((
+                    peer,
+                    Message::REQUESTVOTE {
+                        term: self.term,
+                        last_log_index,
+                        last_log_term,
+                    },
+                ));
+            }
+        }
+        self.vote(self.own_id, self.term, true)
+    }
+
+    fn request_vote(
+        &mut self,
+        from: PeerId,
+        term: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+        now: Instant,
+    ) {
+        // someone removed from the configuration can't depose a leader we still hear from
+        let leader_alive = self.is_leader()
+            || (self.leader.is_some()
+                && self
+                    .heard
+                    .is_some_and(|heard| now.saturating_duration_since(heard) < ELECTION_TIMEOUT));
+        if leader_alive {
+            return;
+        }
+        self.see_term(term);
+        let up_to_date = (last_log_term, last_log_index) >= {
+            let (index, term) = self.last_entry();
+            (term, index)
+        };
+        let granted =
+            term == self.term && up_to_date && self.voted_for.is_none_or(|voted| voted == from);
+        if granted {
+            self.voted_for = Some(from);
+            self.heard = Some(now);
+        }
+        self.outbox.push((
+            from,
+            Message::VOTE {
+                term: self.term,
+                granted,
+            },
+        ));
+    }
+
+    fn vote(&mut self, from: PeerId, term: u64, granted: bool) {
+        let State::Candidate { ref mut votes } = self.state else {
+            return;
+        };
+        if term != self.term || !granted {
+            return;
+        }
+        votes.insert(from);
+        let votes = votes.clone();
+        if !self.has_quorum(&votes) {
+            return;
+        }
+        self.leader = Some(self.own_id);
+        self.state = State::Leader {
+            next: HashMap::new(),
+            matched: HashMap::new(),
+            queued: VecDeque::new(),
+        };
+        // an entry of our own term, so the ones before it can commit
+        self.log.push(Entry {
+            term: self.term,
+            step: Step::Noop,
+        });
+        self.replicate();
+        self.advance_commit()
+    }
+
+    fn append_entries(
+        &mut self,
+        from: PeerId,
+        term: u64,
+        (prev_log_index, prev_log_term): (u64, u64),
+        entries: &[Entry],
+        leader_commit: u64,
+        now: Instant,
+    ) {
+        self.see_term(term);
+        if term < self.term {
+            self.outbox.push((
+                from,
+                Message::APPENDED {
+                    term: self.term,
+                    success: false,
+                    match_index: 0,
+                },
+            ));
+            return;
+        }
+        self.state = State::Follower;
+        self.leader = Some(from);
+        self.heard = Some(now);
+
+        let matches = prev_log_index == 0
+            || self
+                .entry(prev_log_index)
+                .is_some_and(|entry| entry.term == prev_log_term);
+        if !matches {
+            // try again from the end of our log, or one further back
+            let hint = prev_log_index.saturating_sub(1).min(self.log.len() as u64);
+            self.outbox.push((
+                from,
+                Message::APPENDED {
+                    term: self.term,
+                    success: false,
+                    match_index: hint,
+                },
+            ));
+            return;
+        }
+        for (index, entry) in (prev_log_index + 1..).zip(entries) {
+            match self.entry(index) {
+                Some(ours) if ours.term == entry.term => continue,
+                // a conflicting entry and everything after it was never committed
+                Some(_) => self.log.truncate(index as usize - 1),
+                None => {}
+            }
+            self.log.push(entry.clone());
+        }
+        let match_index = prev_log_index + entries.len() as u64;
+        if leader_commit > self.commit {
+            self.commit = leader_commit.min(match_index);
+        }
+        if self.voters().contains(&self.own_id) {
+            self.joining = None;
+        }
+        self.outbox.push((
+            from,
+            Message::APPENDED {
+                term: self.term,
+                success: true,
+                match_index,
+            },
+        ));
+        self.apply()
+    }
+
+    fn appended(&mut self, from: PeerId, term: u64, success: bool, match_index: u64) {
+        let State::Leader {
+            ref mut next,
+            ref mut matched,
+            ..
+        } = self.state
+        else {
+            return;
+        };
+        if term != self.term {
+            return;
+        }
+        if success {
+            let known = matched.entry(from).or_default();
+            *known = (*known).max(match_index);
+            next.insert(from, match_index + 1);
+            self.advance_commit()
+        } else {
+            let back = next.entry(from).or_insert(1);
+            *back = (match_index + 1).min(back.saturating_sub(1)).max(1);
+        }
+    }
+
+    // commits the highest entry of our term a quorum has, and applies it
+    fn advance_commit(&mut self) {
+        let State::Leader { ref matched, .. } = self.state else {
+            return;
+        };
+        let mut commit = self.commit;
+        for index in self.commit + 1..=self.log.len() as u64 {
+            if self.log[index as usize - 1].term != self.term {
+                continue;
+            }
+            let have: HashSet<_> = matched
+                .iter()
+                .filter(|(_, &m)| m >= index)
+                .map(|(&id, _)| id)
+                .chain([self.own_id])
+                .collect();
+            if self.has_quorum(&have) {
+                commit = index;
+            }
+        }
+        self.commit = commit;
+        self.apply();
+        if self.is_leader() {
+            self.next_change();
+        }
+    }
+
+    // queues a view for each configuration committed since the last call
+    fn apply(&mut self) {
+        while self.applied < self.commit {
+            self.applied += 1;
+            if let Step::Final(..) = self.log[self.applied as usize - 1].step {
+                let (members, _) = self.config_upto(self.applied);
+                // a leader that removed itself is done leading,
+                // once the rest know the change is committed
+                if self.is_leader() && !members.contains(&self.own_id) {
+                    self.replicate();
+                    self.state = State::Follower;
+                    self.leader = None;
+                }
+                self.committed.push(members);
+            }
+        }
+    }
+
+    // the leader starts the next membership change: the new configuration on
+    // its own once the joint one is committed, or the next one queued once
+    // nothing is left in flight
+    fn next_change(&mut self) {
+        let last_config = self
+            .log
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, entry)| !matches!(entry.step, Step::Noop))
+            .map(|(i, entry)| (i as u64 + 1, entry.step.clone()));
+        let step = match last_config {
+            Some((index, Step::Joint(peer_id, op))) if index <= self.commit => {
+                Step::Final(peer_id, op)
+            }
+            Some((index, _)) if index > self.commit => return,
+            _ => {
+                let (members, _) = self.config();
+                let State::Leader { ref mut queued, .. } = self.state else {
+                    return;
+                };
+                let next = loop {
+                    match queued.pop_front() {
+                        Some((peer_id, Operation::Add)) if !members.contains(&peer_id) => {
+                            break Some((peer_id, Operation::Add))
+                        }
+                        Some((peer_id, Operation::Delete)) if members.contains(&peer_id) => {
+                            break Some((peer_id, Operation::Delete))
+                        }
+                        Some(_) => continue,
+                        None => break None,
+                    }
+                };
+                let Some((peer_id, op)) = next else {
+                    return;
+                };
+                Step::Joint(peer_id, op)
+            }
+        };
+        self.log.push(Entry {
+            term: self.term,
+            step,
+        });
+        // nobody else has to agree, a lone leader commits right away
+        self.advance_commit();
+    }
+
+    // sends each voter what it's missing, from the last entry it has on
+    fn replicate(&mut self) {
+        let voters = self.voters();
+        let State::Leader { ref mut next, .. } = self.state else {
+            return;
+        };
+        let len = self.log.len() as u64;
+        for peer in voters {
+            if peer == self.own_id {
+                continue;
+            }
+            let from = *next.entry(peer).or_insert(len + 1);
+            let prev_log_index = from - 1;
+            let prev_log_term = match prev_log_index {
+                0 => 0,
+                i => self.log[i as usize - 1].term,
+            };
+            let entries = self.log[prev_log_index as usize..]
+                .iter()
+                .take(MAX_ENTRIES)
+                .cloned()
+                .collect();
+            self.outbox.push((
+                peer,
+                Message::APPENDENTRIES {
+                    term: self.term,
+                    prev_log_index,
+                    prev_log_term,
+                    entries,
+                    leader_commit: self.commit,
+                },
+            ));
+        }
+    }
+
+    /// The latest configuration in the log: the members, and while a change
+    /// is halfway through, the members it's changing to
+    fn config(&self) -> (HashSet<PeerId>, Option<HashSet<PeerId>>) {
+        self.config_upto(self.log.len() as u64)
+    }
+
+    // the configuration as of entry `index`. Everyone starts out with the
+    // first host on its own, the same first view as every other mode
+    fn config_upto(&self, index: u64) -> (HashSet<PeerId>, Option<HashSet<PeerId>>) {
+        let mut members = HashSet::from([DEFAULT_LEADER_ID]);
+        let mut joint = None;
+        for entry in &self.log[..index as usize] {
+            let (peer_id, op, is_final) = match entry.step {
+                Step::Noop => continue,
+                Step::Joint(peer_id, ref op) => (peer_id, op, false),
+                Step::Final(peer_id, ref op) => (peer_id, op, true),
+            };
+            let mut changed = members.clone();
+            match op {
+                Operation::Add => changed.insert(peer_id),
+                _ => changed.remove(&peer_id),
+            };
+            if is_final {
+                members = changed;
+                joint = None;
+            } else {
+                joint = Some(changed);
+            }
+        }
+        (members, joint)
+    }
+
+    // everyone in the old or new configuration
+    fn voters(&self) -> HashSet<PeerId> {
+        let (old, new) = self.config();
+        old.into_iter().chain(new.into_iter().flatten()).collect()
+    }
+
+    // a majority of the configuration, of both halves while it's joint
+    fn has_quorum(&self, have: &HashSet<PeerId>) -> bool {
+        let majority =
+            |members: &HashSet<PeerId>| members.intersection(have).count() * 2 > members.len();
+        let (old, new) = self.config();
+        majority(&old) && new.as_ref().is_none_or(majority)
+    }
+
+    fn entry(&self, index: u64) -> Option<&Entry> {
+        index.checked_sub(1).and_then(|i| self.log.get(i as usize))
+    }
+
+    // (index, term) of the last entry, (0, 0) while the log is empty
+    fn last_entry(&self) -> (u64, u64) {
+        (self.log.len() as u64, self.log.last().map_or(0, |e| e.term))
+    }
+
+    fn reset_timeout(&mut self) {
+        let jitter = self.random.hash_one((self.own_id, self.term)) % 1000;
+        self.timeout = ELECTION_TIMEOUT + ELECTION_TIMEOUT * jitter as u32 / 1000;
+    }
+}
+
+fn same_change(a: &Operation, b: &Operation) -> bool {
+    matches!(
+        (a, b),
+        (Operation::Add, Operation::Add) | (Operation::Delete, Operation::Delete)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(term: u64, step: Step) -> Entry {
+        Entry { term, step }
+    }
+
+    fn with_log(own_id: PeerId, term: u64, log: Vec<Entry>) -> Rafting {
+        let mut raft = Rafting::new(own_id);
+        raft.term = term;
+        raft.log = log;
+        raft
+    }
+
+    fn append(raft: &mut Rafting, term: u64, prev: (u64, u64), entries: Vec<Entry>) -> (bool, u64) {
+        let message = Message::APPENDENTRIES {
+            term,
+            prev_log_index: prev.0,
+            prev_log_term: prev.1,
+            entries,
+            leader_commit: 0,
+        };
+        raft.receive(1, &message, Instant::now());
+        match raft.take_outbox().pop() {
+            Some((
+                1,
+                Message::APPENDED {
+                    success,
+                    match_index,
+                    ..
+                },
+            )) => (success, match_index),
+            other => panic!("expected an APPENDED, got {other:?}"),
+        }
+    }
+
+    fn acked(leader: &mut Rafting, from: PeerId, match_index: u64) {
+        let message = Message::APPENDED {
+            term: leader.term,
+            success: true,
+            match_index,
+        };
+        leader.receive(from, &message, Instant::now());
+    }
+
+    #[test]
+    fn refuses_entries_that_dont_follow_its_log() {
+        let mut follower = with_log(2, 1, vec![entry(1, Step::Noop)]);
+        // the leader thinks entry 1 is from term 2
+        assert_eq!(append(&mut follower, 2, (1, 2), vec![]), (false, 0));
+        // or that there's an entry 3, when there's only 1
+        assert_eq!(append(&mut follower, 2, (3, 2), vec![]), (false, 1));
+        assert_eq!(follower.log.len(), 1);
+        assert_eq!(
+            append(&mut follower, 2, (1, 1), vec![entry(2, Step::Noop)]),
+            (true, 2)
+        );
+    }
+
+    #[test]
+    fn replaces_conflicting_entries_and_whatever_follows() {
+        let mut follower = with_log(
+            2,
+            1,
+            vec![
+                entry(1, Step::Noop),
+                entry(1, Step::Joint(3, Operation::Add)),
+                entry(1, Step::Final(3, Operation::Add)),
+            ],
+        );
+        assert_eq!(
+            append(&mut follower, 2, (1, 1), vec![entry(2, Step::Noop)]),
+            (true, 2)
+        );
+        assert_eq!(follower.log.len(), 2);
+        assert_eq!(follower.log[1].term, 2);
+        assert_eq!(follower.config(), (HashSet::from([1]), None));
+    }
+
+    #[test]
+    fn keeps_entries_it_already_has() {
+        let log = vec![
+            entry(1, Step::Noop),
+            entry(1, Step::Joint(2, Operation::Add)),
+        ];
+        let mut follower = with_log(2, 1, log.clone());
+        // a late resend of the first entry alone
+        assert_eq!(
+            append(&mut follower, 1, (0, 0), log[..1].to_vec()),
+            (true, 1)
+        );
+        assert_eq!(follower.log.len(), 2);
+    }
+
+    #[test]
+    fn a_joint_configuration_needs_both_majorities() {
+        let raft = with_log(
+            1,
+            1,
+            vec![
+                entry(1, Step::Joint(2, Operation::Add)),
+                entry(1, Step::Final(2, Operation::Add)),
+                entry(1, Step::Joint(3, Operation::Add)),
+            ],
+        );
+        assert_eq!(
+            raft.config(),
+            (HashSet::from([1, 2]), Some(HashSet::from([1, 2, 3])))
+        );
+        assert!(!raft.has_quorum(&HashSet::from([1])));
+        // 2 of the new three, but only 1 of the old two
+        assert!(!raft.has_quorum(&HashSet::from([1, 3])));
+        assert!(raft.has_quorum(&HashSet::from([1, 2])));
+
+        let removing = with_log(
+            1,
+            1,
+            vec![
+                entry(1, Step::Final(2, Operation::Add)),
+                entry(1, Step::Final(3, Operation::Add)),
+                entry(1, Step::Joint(3, Operation::Delete)),
+            ],
+        );
+        // 2 of the old three, but only 1 of the new two
+        assert!(!removing.has_quorum(&HashSet::from([1, 3])));
+        assert!(removing.has_quorum(&HashSet::from([1, 2])));
+    }
+
+    #[test]
+    fn adds_a_member_in_two_steps() {
+        let now = Instant::now();
+        let mut leader = Rafting::new(1);
+        // alone in the configuration, it leads without waiting
+        leader.tick(now, [1, 2].into_iter());
+        assert!(leader.is_leader());
+        assert_eq!(leader.commit, 1);
+
+        leader.receive(2, &Message::JOIN, now);
+        assert!(matches!(leader.log[1].step, Step::Joint(2, Operation::Add)));
+        // the joint configuration needs 2 as well
+        assert_eq!(leader.commit, 1);
+        acked(&mut leader, 2, 2);
+        assert_eq!(leader.commit, 2);
+        assert!(matches!(leader.log[2].step, Step::Final(2, Operation::Add)));
+        assert!(leader.take_committed().is_empty());
+
+        acked(&mut leader, 2, 3);
+        assert_eq!(leader.take_committed(), [HashSet::from([1, 2])]);
+    }
+}
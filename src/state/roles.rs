@@ -1,8 +1,12 @@
 use std::collections::{HashMap, HashSet};
 
-use crate::{Instruction, Operation};
+use super::messaging::{Instruction, Mcast, Operation, PendingRequest};
+use super::sequencer::{InOrder, Sequencer};
 
-use super::{PeerId, RequestId, ViewId, DEFAULT_LEADER_ID};
+use super::gossip::Gossiping;
+use super::lease::Lease;
+use super::raft::Rafting;
+use super::{Membership, PeerId, RequestId, ViewId, DEFAULT_LEADER_ID};
 
 // stuff only a true leader would need! 👑
 #[derive(Default, Debug)]
@@ -12,8 +16,27 @@ pub struct Leading {
     // K: request_id
     // V: (peer id to add, confirmed Oks)
     pending_requests: HashMap<RequestId, (PeerId, ViewId, HashSet<PeerId>, Operation)>,
+    // peers that sent LEAVE, they still get told about the view that drops them
+    leaving: HashSet<PeerId>,
+    // peers deleted for going quiet in Majority mode, added back when heard from
+    unreachable: HashSet<PeerId>,
+    // K: (sender, seq)
+    // V: the FLUSHes for the request we're waiting on, merged
+    flushed: HashMap<(PeerId, u64), Mcast>,
+    // who those FLUSHes came from
+    flushers: HashSet<PeerId>,
+    pub lease: Lease,
+    pub sequencer: Sequencer,
 }
 impl Leading {
+    /// A follower elected to lead, it carries on the ordered log it delivered
+    pub fn succeed(follow: Following) -> Self {
+        Self {
+            sequencer: Sequencer::resume(follow.ordered.into_log()),
+            ..Self::default()
+        }
+    }
+
     pub fn latest_request(&self) -> RequestId {
         self.requests_count
     }
@@ -35,9 +58,93 @@ impl Leading {
             });
     }
 
+    pub fn pending_len(&self) -> usize {
+        self.pending_requests.len()
+    }
+
+    /// True if an Release of `name` by this peer is already queued or in flight
+    pub fn is_releasing(&self, peer_id: PeerId, name: &str) -> bool {
+        self.pending_requests.values().any(|(id, _, _, op)| {
+            *id == peer_id && matches!(op, Operation::Release(n) if n == name)
+        })
+    }
+
+    /// Copies out every request still waiting on OKs, oldest first
+    pub fn pending(&self) -> Vec<PendingRequest> {
+        let mut out: Vec<_> = self
+            .pending_requests
+            .iter()
+            .map(
+                |(&request_id, (peer_id, view_id, oks, op))| PendingRequest {
+                    instruction: Instruction {
+                        request_id,
+                        peer_id: *peer_id,
+                        view_id: *view_id,
+                        op: op.clone(),
+                    },
+                    oks: oks.clone(),
+                },
+            )
+            .collect();
+        out.sort_by_key(|p| p.instruction.request_id);
+        out
+    }
+
+    /// Queues the delete for a peer that asked to LEAVE
+    pub fn push_leave(&mut self, peer_id: PeerId, view_id: ViewId) {
+        self.leaving.insert(peer_id);
+        self.push_request(peer_id, view_id, Operation::Delete);
+    }
+
+    /// True (once) if this peer left on its own and should see its removal
+    pub fn release(&mut self, peer_id: PeerId) -> bool {
+        self.leaving.remove(&peer_id)
+    }
+
+    /// Remembers that this peer is being deleted for going quiet
+    pub fn partitioned(&mut self, peer_id: PeerId) {
+        self.unreachable.insert(peer_id);
+    }
+
+    /// True (once) if this peer was deleted for going quiet and should be added back
+    pub fn rejoin(&mut self, peer_id: PeerId) -> bool {
+        !self.is_adding(peer_id) && self.unreachable.remove(&peer_id)
+    }
+
+    /// True if a request to add this peer is already queued or in flight
+    pub fn is_adding(&self, peer_id: PeerId) -> bool {
+        self.pending_requests
+            .values()
+            .any(|(id, _, _, op)| *id == peer_id && matches!(op, Operation::Add))
+    }
+
+    /// True if a request to delete this peer is already queued or in flight
+    pub fn is_deleting(&self, peer_id: PeerId) -> bool {
+        self.pending_requests
+            .values()
+            .any(|(id, _, _, op)| *id == peer_id && matches!(op, Operation::Delete))
+    }
+
     /// Check if the leader is not waiting on confirmations from another request
     pub fn can_proceed(&self) -> bool {
-        self.waiting_for.is_none() && self.pending_requests.len() > 0
+        self.waiting_for.is_none() && !self.pending_requests.is_empty()
+    }
+
+    /// Merges a FLUSH into the union, if it's for the request in flight
+    pub fn collect_flush(&mut self, request_id: RequestId, from: PeerId, messages: Vec<Mcast>) {
+        if self.waiting_for != Some(request_id) {
+            return;
+        }
+        self.flushers.insert(from);
+        for m in messages {
+            self.flushed.insert((m.from, m.seq), m);
+        }
+    }
+
+    /// Everything delivered by anyone in the view being left, and who FLUSHed it
+    pub fn take_flushed(&mut self) -> (HashSet<PeerId>, Vec<Mcast>) {
+        let flushed = std::mem::take(&mut self.flushed).into_values().collect();
+        (std::mem::take(&mut self.flushers), flushed)
     }
 
     pub fn start_req(&mut self) -> Instruction {
@@ -48,13 +155,14 @@ impl Leading {
             request_id,
             peer_id: req.0,
             view_id: req.1,
-            op: req.3,
+            op: req.3.clone(),
         }
     }
 
     pub fn check_req_complete(
         &mut self,
         memberships: &HashMap<ViewId, HashSet<PeerId>>,
+        membership: Membership,
     ) -> Option<Instruction> {
         if let Some(request_id) = self.waiting_for {
             let req = self.pending_requests.get(&request_id).unwrap();
@@ -62,7 +170,13 @@ impl Leading {
                 .get(&req.1)
                 .expect("View should exist in memberships");
 
-            if req.2 == *members {
+            let complete = match membership {
+                Membership::Unanimous => req.2 == *members,
+                Membership::Majority => members.intersection(&req.2).count() * 2 > members.len(),
+                Membership::Gossip => unreachable!("nobody leads in gossip mode"),
+                Membership::Raft => unreachable!("raft commits changes through its log"),
+            };
+            if complete {
                 self.waiting_for = None;
                 let val = self.pending_requests.remove(&request_id);
                 //println!("REQ_COMPLETE: {:?}", val);
@@ -84,12 +198,35 @@ impl Leading {
 pub struct Following {
     leader_id: PeerId,
     ack_queue: HashMap<RequestId, Instruction>,
+    pub ordered: InOrder,
 }
 impl Following {
+    /// A leader that found out another member was elected
+    pub fn step_down(lead: Leading, leader_id: PeerId) -> Self {
+        Self {
+            leader_id,
+            ack_queue: HashMap::new(),
+            ordered: InOrder::resume(lead.sequencer.into_log()),
+        }
+    }
+
     pub fn leader_id(&self) -> PeerId {
         self.leader_id
     }
 
+    /// Follows a newly elected leader. REQs from the old one will never complete
+    pub fn set_leader(&mut self, leader_id: PeerId) {
+        self.leader_id = leader_id;
+        self.ack_queue.clear();
+    }
+
+    /// Copies out the REQs we haven't OKed yet, oldest first
+    pub fn queued(&self) -> Vec<Instruction> {
+        let mut out: Vec<_> = self.ack_queue.values().cloned().collect();
+        out.sort_by_key(|i| i.request_id);
+        out
+    }
+
     pub fn push_instruction(&mut self, instr: Instruction) {
         self.ack_queue.insert(instr.request_id, instr);
     }
@@ -107,16 +244,79 @@ impl Following {
 pub enum Role {
     Leader(Leading),
     Follower(Following),
+    // every member on its own, see `Membership::Gossip`
+    Gossip(Gossiping),
+    // leader or not, see `Membership::Raft`
+    Raft(Rafting),
 }
 impl Role {
-    pub fn new(is_leader: bool) -> Self {
-        if is_leader {
+    pub fn new(is_leader: bool, membership: Membership, own_id: PeerId) -> Self {
+        if membership == Membership::Gossip {
+            Self::Gossip(Gossiping::new(own_id))
+        } else if membership == Membership::Raft {
+            Self::Raft(Rafting::new(own_id))
+        } else if is_leader {
             Self::Leader(Leading::default())
         } else {
             Self::Follower(Following {
                 leader_id: DEFAULT_LEADER_ID,
                 ack_queue: HashMap::new(),
+                ordered: InOrder::default(),
             })
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a leader of `members` in view 1 waiting on an add of peer 9, OKed by `oks`
+    fn waiting(members: &[PeerId], oks: &[PeerId]) -> (Leading, HashMap<ViewId, HashSet<PeerId>>) {
+        let mut lead = Leading::default();
+        lead.push_request(9, 1, Operation::Add);
+        lead.start_req();
+        for &ok in oks {
+            lead.acknowledge_ok(lead.latest_request(), ok);
+        }
+        (
+            lead,
+            HashMap::from([(1, members.iter().copied().collect())]),
+        )
+    }
+
+    #[test]
+    fn majority_needs_more_than_half_of_the_view() {
+        for (members, oks, complete) in [
+            (&[1, 2, 3][..], &[1][..], false),
+            (&[1, 2, 3], &[1, 2], true),
+            (&[1, 2, 3, 4], &[1, 2], false),
+            (&[1, 2, 3, 4], &[1, 2, 3], true),
+            (&[1, 2, 3, 4, 5], &[1, 4, 5], true),
+            // OKs from outside the view don't count
+            (&[1, 2, 3], &[1, 7, 8], false),
+        ] {
+            let (mut lead, views) = waiting(members, oks);
+            assert_eq!(
+                lead.check_req_complete(&views, Membership::Majority)
+                    .is_some(),
+                complete,
+                "{oks:?} of {members:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn unanimous_needs_every_member() {
+        let (mut lead, views) = waiting(&[1, 2, 3], &[1, 2]);
+        assert!(lead
+            .check_req_complete(&views, Membership::Unanimous)
+            .is_none());
+        lead.acknowledge_ok(lead.latest_request(), 3);
+        let done = lead
+            .check_req_complete(&views, Membership::Unanimous)
+            .unwrap();
+        assert_eq!(done.peer_id, 9);
+        assert!(!lead.can_proceed());
+    }
+}
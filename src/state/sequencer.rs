@@ -0,0 +1,218 @@
+use std::collections::{BTreeMap, HashMap};
+
+use super::messaging::Ordered;
+use super::PeerId;
+
+// the leader's side of totally ordered broadcast: it numbers every
+// submitted message and keeps them all, so a member that missed some
+// (or joined late) can ask for the rest
+#[derive(Default, Debug)]
+pub struct Sequencer {
+    // every message numbered so far, seq n at index n - 1
+    log: Vec<Ordered>,
+    // how much of the log has gone out to the view
+    sent: usize,
+    // K: peer that found a gap
+    // V: the first seq it's missing
+    resends: HashMap<PeerId, u64>,
+}
+
+impl Sequencer {
+    /// Picks up numbering after `log`, what a member that becomes leader delivered
+    pub fn resume(log: Vec<Ordered>) -> Self {
+        Self {
+            sent: log.len(),
+            log,
+            resends: HashMap::new(),
+        }
+    }
+
+    /// The whole log, for a leader stepping down to keep delivering after
+    pub fn into_log(self) -> Vec<Ordered> {
+        self.log
+    }
+
+    /// Numbers `payload` as the next message in the log
+    pub fn assign(&mut self, from: PeerId, payload: Vec<u8>) -> Ordered {
+        let ordered = Ordered {
+            seq: self.log.len() as u64 + 1,
+            from,
+            payload,
+        };
+        self.log.push(ordered.clone());
+        ordered
+    }
+
+    /// What was numbered since the last call, to forward to the view
+    pub fn unsent(&mut self) -> Vec<Ordered> {
+        let unsent = self.log[self.sent..].to_vec();
+        self.sent = self.log.len();
+        unsent
+    }
+
+    pub fn resend(&mut self, peer: PeerId, from_seq: u64) {
+        let earliest = self.resends.entry(peer).or_insert(from_seq);
+        *earliest = (*earliest).min(from_seq);
+    }
+
+    /// Everything each peer asked for, from its first missing seq on
+    pub fn take_resends(&mut self) -> Vec<(PeerId, Vec<Ordered>)> {
+        let sent = &self.log[..self.sent];
+        self.resends
+            .drain()
+            .map(|(peer, from_seq)| {
+                let skip = (from_seq.max(1) - 1) as usize;
+                (peer, sent.iter().skip(skip).cloned().collect())
+            })
+            .collect()
+    }
+
+    pub fn latest(&self) -> u64 {
+        self.log.len() as u64
+    }
+}
+
+// a member's side: delivers in seq order, holding anything that
+// arrives past a gap until the leader resends what's missing. It keeps
+// what it delivered, in case it's elected and has to take over the log
+#[derive(Default, Debug)]
+pub struct InOrder {
+    log: Vec<Ordered>,
+    held: BTreeMap<u64, Ordered>,
+    // the seq we last asked the leader to resend from, asked once per gap
+    asked: Option<u64>,
+    ask: Option<u64>,
+}
+
+impl InOrder {
+    /// Takes one message off the wire and returns whatever can now be
+    /// delivered, in order. Duplicates are dropped.
+    pub fn receive(&mut self, ordered: Ordered) -> Vec<Ordered> {
+        if ordered.seq > self.delivered() {
+            self.held.insert(ordered.seq, ordered);
+        }
+        let mut ready = Vec::new();
+        while let Some(next) = self.held.remove(&(self.delivered() + 1)) {
+            self.log.push(next.clone());
+            ready.push(next);
+        }
+        // a resend that closed part of a gap brings the rest right behind it
+        let missing = self.delivered() + 1;
+        if ready.is_empty() && !self.held.is_empty() && self.asked != Some(missing) {
+            self.asked = Some(missing);
+            self.ask = Some(missing);
+        }
+        ready
+    }
+
+    /// The first seq to ask the leader for, once for each gap found
+    pub fn take_gap(&mut self) -> Option<u64> {
+        self.ask.take()
+    }
+
+    pub fn delivered(&self) -> u64 {
+        self.log.len() as u64
+    }
+
+    /// Carries on from a log we numbered ourselves
+    pub fn resume(log: Vec<Ordered>) -> Self {
+        Self {
+            log,
+            ..Self::default()
+        }
+    }
+
+    pub fn into_log(self) -> Vec<Ordered> {
+        self.log
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbered(count: u64) -> Vec<Ordered> {
+        let mut leader = Sequencer::default();
+        (1..=count)
+            .map(|n| leader.assign(1, vec![n as u8]))
+            .collect()
+    }
+
+    fn seqs(delivered: &[Ordered]) -> Vec<u64> {
+        delivered.iter().map(|o| o.seq).collect()
+    }
+
+    #[test]
+    fn numbers_and_forwards_each_message_once() {
+        let mut leader = Sequencer::default();
+        leader.assign(2, b"a".to_vec());
+        leader.assign(3, b"b".to_vec());
+        assert_eq!(seqs(&leader.unsent()), [1, 2]);
+        assert!(leader.unsent().is_empty());
+        leader.assign(2, b"c".to_vec());
+        assert_eq!(seqs(&leader.unsent()), [3]);
+        assert_eq!(leader.latest(), 3);
+    }
+
+    #[test]
+    fn delivers_in_order_and_drops_duplicates() {
+        let log = numbered(3);
+        let mut member = InOrder::default();
+        assert_eq!(seqs(&member.receive(log[0].clone())), [1]);
+        assert!(member.receive(log[0].clone()).is_empty());
+        assert_eq!(seqs(&member.receive(log[1].clone())), [2]);
+        assert_eq!(seqs(&member.receive(log[2].clone())), [3]);
+        assert_eq!(member.take_gap(), None);
+    }
+
+    #[test]
+    fn asks_once_per_gap() {
+        let log = numbered(5);
+        let mut member = InOrder::default();
+        member.receive(log[0].clone());
+        // 2 and 3 went missing
+        assert!(member.receive(log[3].clone()).is_empty());
+        assert!(member.receive(log[4].clone()).is_empty());
+        assert_eq!(member.take_gap(), Some(2));
+        assert_eq!(member.take_gap(), None);
+
+        // the resend covers 3 as well, so its absence isn't asked about again
+        assert_eq!(seqs(&member.receive(log[1].clone())), [2]);
+        assert_eq!(member.take_gap(), None);
+        assert_eq!(seqs(&member.receive(log[2].clone())), [3, 4, 5]);
+        assert_eq!(member.delivered(), 5);
+    }
+
+    #[test]
+    fn resends_from_the_earliest_seq_asked_for() {
+        let mut leader = Sequencer::default();
+        for n in 1..=5 {
+            leader.assign(1, vec![n]);
+        }
+        leader.unsent();
+        // not sent out yet, so not resent either
+        leader.assign(1, vec![6]);
+
+        leader.resend(2, 4);
+        leader.resend(2, 3);
+        leader.resend(3, 0);
+        let mut resends = leader.take_resends();
+        resends.sort_by_key(|(peer, _)| *peer);
+        assert_eq!(resends.len(), 2);
+        assert_eq!(seqs(&resends[0].1), [3, 4, 5]);
+        assert_eq!(seqs(&resends[1].1), [1, 2, 3, 4, 5]);
+        assert!(leader.take_resends().is_empty());
+    }
+
+    #[test]
+    fn a_new_leader_carries_on_the_log() {
+        let log = numbered(2);
+        let mut member = InOrder::default();
+        for ordered in log {
+            member.receive(ordered);
+        }
+        let mut leader = Sequencer::resume(member.into_log());
+        assert!(leader.unsent().is_empty());
+        assert_eq!(leader.assign(4, vec![]).seq, 3);
+    }
+}
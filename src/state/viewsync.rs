@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+
+use super::messaging::Mcast;
+use super::{PeerId, ViewId};
+
+// application messages multicast within the current view. Once a view change
+// starts every member hands the leader what it delivered in this view (its
+// FLUSH), and the leader sends the union back ahead of the NEWVIEW, so every
+// member that survives into the next view delivered the same messages.
+#[derive(Default, Debug)]
+pub struct ViewSync {
+    seq: u64,
+    // K: (sender, seq)
+    // V: everything delivered in the current view
+    delivered: HashMap<(PeerId, u64), Mcast>,
+    // our FLUSH is out, anything else for this view has to come with the NEWVIEW
+    flushing: bool,
+    // payloads we were asked to send while flushing, they go out in the next view
+    outbox: Vec<Vec<u8>>,
+    // sent in a view we haven't installed yet
+    early: Vec<Mcast>,
+}
+
+impl ViewSync {
+    /// Stamps `payload` for the current view and delivers it to ourselves.
+    /// None while flushing, it's held until the next view is installed.
+    pub fn send(&mut self, payload: Vec<u8>, from: PeerId, view_id: ViewId) -> Option<Mcast> {
+        if self.flushing {
+            self.outbox.push(payload);
+            return None;
+        }
+        self.seq += 1;
+        let mcast = Mcast {
+            from,
+            view_id,
+            seq: self.seq,
+            payload,
+        };
+        self.delivered.insert((from, mcast.seq), mcast.clone());
+        Some(mcast)
+    }
+
+    /// True if `mcast` should be delivered now. Ones for a later view are
+    /// held until we install it, ones for an older view are dropped.
+    pub fn receive(&mut self, mcast: &Mcast, view_id: ViewId) -> bool {
+        if mcast.view_id > view_id {
+            self.early.push(mcast.clone());
+            return false;
+        }
+        if mcast.view_id < view_id || self.flushing {
+            return false;
+        }
+        self.record(mcast)
+    }
+
+    /// Everything delivered in this view, for the leader. Nothing more
+    /// gets delivered in it except what the leader sends back.
+    pub fn flush(&mut self) -> Vec<Mcast> {
+        self.flushing = true;
+        self.delivered.values().cloned().collect()
+    }
+
+    /// Takes the union of the FLUSHes for `view_id`, the view we're leaving,
+    /// and returns the ones we never delivered, each sender's in order.
+    pub fn flushed(&mut self, mut union: Vec<Mcast>, view_id: ViewId) -> Vec<Mcast> {
+        union.sort_by_key(|m| (m.from, m.seq));
+        union
+            .into_iter()
+            .filter(|m| m.view_id == view_id && self.record(m))
+            .collect()
+    }
+
+    /// Starts over in `view_id`. Returns the messages that arrived for it
+    /// early, to deliver.
+    pub fn install(&mut self, view_id: ViewId) -> Vec<Mcast> {
+        self.delivered.clear();
+        self.flushing = false;
+        let (now, later): (Vec<_>, Vec<_>) = std::mem::take(&mut self.early)
+            .into_iter()
+            .filter(|m| m.view_id >= view_id)
+            .partition(|m| m.view_id == view_id);
+        self.early = later;
+        now.into_iter().filter(|m| self.record(m)).collect()
+    }
+
+    /// The payloads held back while flushing, once there's a view to send them in
+    pub fn release(&mut self) -> Vec<Vec<u8>> {
+        if self.flushing {
+            return Vec::new();
+        }
+        std::mem::take(&mut self.outbox)
+    }
+
+    // true if `mcast` wasn't delivered before
+    fn record(&mut self, mcast: &Mcast) -> bool {
+        self.delivered
+            .insert((mcast.from, mcast.seq), mcast.clone())
+            .is_none()
+    }
+}
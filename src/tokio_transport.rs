@@ -0,0 +1,468 @@
+//! The poll loop behind `Group` redone on tokio: a task per connection and per
+//! heartbeat socket feed one protocol task that owns `Data`, and the timers
+//! are `tokio::time` intervals. Built with `--features tokio`, run with `run --tokio`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    future::{pending, poll_fn},
+    io::{self, Write},
+    net::SocketAddr,
+    task::Poll,
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream, UdpSocket,
+    },
+    runtime,
+    signal::unix::{signal, SignalKind},
+    sync::mpsc::{self, UnboundedSender},
+    time::{interval_at, sleep, Instant, Interval, MissedTickBehavior},
+};
+
+use crate::{
+    admin::{self, ADMIN_ID},
+    failures::{During, Failure, Op, Reasons},
+    metrics::Metrics,
+    socketry::{
+        framing::{Frame, FrameReader, MAX_FRAME},
+        wire::{self, Parcel},
+        ATTEMPT_WAIT, MAX_ATTEMPTS, PORT,
+    },
+    state::{
+        lifecycle::{Tick, Timers},
+        messaging::Letter,
+        Data, PeerId,
+    },
+    Config, Views,
+};
+
+type ConnId = u64;
+
+/// What the connection and heartbeat tasks tell the protocol task
+enum Event {
+    Accepted(ConnId, Conn),
+    Frame(ConnId, Vec<u8>),
+    Dropped(ConnId, String),
+    Heartbeat(Letter),
+    // a host the hostsfile added answered, or didn't
+    Dialed(PeerId, Option<TcpStream>),
+    // what a peer said to the HELLO on the connection we opened to it
+    Answered(PeerId, Result<u16, Failure>),
+}
+
+enum Wake {
+    Event(Event),
+    Tick(Tick),
+    // the start delay ran out
+    Join,
+    Shutdown,
+}
+
+/// Write end of a connection. Bytes written here are queued for the
+/// connection's writer task, so `Data` can keep sending through `impl Write`.
+pub struct Outbox(UnboundedSender<Vec<u8>>);
+
+impl Outbox {
+    fn spawn(mut half: OwnedWriteHalf) -> Self {
+        let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+        tokio::spawn(async move {
+            while let Some(bytes) = rx.recv().await {
+                // dropping rx makes every later write a BrokenPipe
+                if half.write_all(&bytes).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Self(tx)
+    }
+}
+
+impl Write for Outbox {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .send(buf.to_vec())
+            .map_err(|_| io::ErrorKind::BrokenPipe)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An accepted connection, same bookkeeping as `socketry::Inbound`
+struct Conn {
+    reply: Outbox,
+    addr: Option<SocketAddr>,
+    peer: Option<PeerId>,
+    version: Option<u16>,
+    admin: bool,
+}
+
+/// `Timers` on `tokio::time`. Ticks armed with `once` are forgotten after firing.
+#[derive(Default)]
+pub struct Intervals(HashMap<Tick, (Interval, bool)>);
+
+impl Intervals {
+    /// Waits for the next armed tick, forever if nothing is armed
+    async fn next(&mut self) -> Tick {
+        if self.0.is_empty() {
+            return pending().await;
+        }
+        let tick = poll_fn(|cx| {
+            for (&tick, (interval, _)) in self.0.iter_mut() {
+                if interval.poll_tick(cx).is_ready() {
+                    return Poll::Ready(tick);
+                }
+            }
+            Poll::Pending
+        })
+        .await;
+        if self.0.get(&tick).is_some_and(|(_, repeats)| !repeats) {
+            self.0.remove(&tick);
+        }
+        tick
+    }
+
+    fn arm(&mut self, tick: Tick, delay: Duration, period: Duration, repeats: bool) {
+        let mut interval = interval_at(Instant::now() + delay, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        self.0.insert(tick, (interval, repeats));
+    }
+}
+
+impl Timers for Intervals {
+    fn every(&mut self, tick: Tick, delay: Duration, period: Duration) -> Result<(), Failure> {
+        self.arm(tick, delay, period, true);
+        Ok(())
+    }
+
+    fn once(&mut self, tick: Tick, delay: Duration) -> Result<(), Failure> {
+        // the period is never reached, it just can't be zero
+        self.arm(tick, delay, delay.max(Duration::from_millis(1)), false);
+        Ok(())
+    }
+
+    fn cancel(&mut self, tick: Tick) {
+        self.0.remove(&tick);
+    }
+}
+
+/// Runs `run` to completion on a fresh single threaded runtime
+pub fn block_on(config: Config, start_delay: Duration, views: Views) -> Result<(), Failure> {
+    runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .during(Op::Poll)?
+        .block_on(run(config, start_delay, views))
+}
+
+/// Runs a peer until it leaves the group, on whatever runtime it's awaited from.
+/// It JOINs `start_delay` after every channel is up and publishes its views to `views`.
+pub async fn run(config: Config, start_delay: Duration, views: Views) -> Result<(), Failure> {
+    if config.tls.is_some() {
+        return Err(Failure::new(Op::Handshake, Reasons::TlsUnsupported));
+    }
+    // blocks the runtime, but there's nothing on it to starve yet
+    let peer_list = admin::load_or_admit(
+        config.hostsfile.clone(),
+        config.admission_token.as_deref(),
+        None,
+    )?;
+    let metrics = Metrics::default();
+    if let Some(addr) = &config.metrics {
+        metrics.serve(addr)?;
+    }
+    let mut sigterm = signal(SignalKind::terminate()).during(Op::Signals)?;
+    let mut sigint = signal(SignalKind::interrupt()).during(Op::Signals)?;
+
+    let (events, mut inbox) = mpsc::unbounded_channel();
+    let listener = attempt(Op::Bind, peer_list.hostname(), TcpListener::bind).await?;
+    tokio::spawn(accept_all(listener, events.clone()));
+
+    let mut outgoing_channels = HashMap::new();
+    for (id, peer_name) in peer_list.ids_and_names() {
+        let stream = attempt(Op::Connect, peer_name, TcpStream::connect)
+            .await
+            .map_err(|f| f.peer(id))?;
+        let outbox = open(id, stream, peer_list.id(), &config, &events).map_err(|f| f.peer(id))?;
+        outgoing_channels.insert(id, outbox);
+    }
+
+    let mut timers = Intervals::default();
+    let mut data = Data::new(peer_list, &config, metrics, views, &mut timers)?;
+    // admin clients get answered while we wait, `prj3 join` can cut it short
+    let join_at = sleep(start_delay);
+    tokio::pin!(join_at);
+    let mut join_due = true;
+
+    let mut conns = HashMap::new();
+    // heartbeat sockets with a task reading them, a reloaded hostsfile adds more
+    let mut hearing = HashSet::new();
+    let mut dialing = HashSet::new();
+    loop {
+        if data.has_left() {
+            data.shutdown(&mut timers);
+            return Ok(());
+        }
+
+        let wake = tokio::select! {
+            Some(event) = inbox.recv() => Wake::Event(event),
+            tick = timers.next() => Wake::Tick(tick),
+            _ = &mut join_at, if join_due => Wake::Join,
+            _ = sigterm.recv() => Wake::Shutdown,
+            _ = sigint.recv() => Wake::Shutdown,
+        };
+        match wake {
+            Wake::Event(Event::Accepted(id, conn)) => {
+                conns.insert(id, conn);
+            }
+            Wake::Event(Event::Frame(id, body)) => {
+                let Some(conn) = conns.get_mut(&id) else {
+                    continue;
+                };
+                if let Some(reason) = deliver(&mut data, conn, &body, &mut outgoing_channels) {
+                    // the writer hangs up once the outbox is gone
+                    report_drop(&data, conns.remove(&id), reason);
+                }
+            }
+            Wake::Event(Event::Dropped(id, reason)) => {
+                report_drop(&data, conns.remove(&id), reason)
+            }
+            Wake::Event(Event::Heartbeat(letter)) => data.recv_datagram(&letter),
+            Wake::Event(Event::Dialed(id, stream)) => {
+                dialing.remove(&id);
+                if let Some(stream) = stream {
+                    let own_id = data.peer_list().id();
+                    let outbox =
+                        open(id, stream, own_id, &config, &events).map_err(|f| f.peer(id))?;
+                    outgoing_channels.insert(id, outbox);
+                    data.dialed(id);
+                }
+            }
+            Wake::Event(Event::Answered(id, answer)) => {
+                data.answered(id, answer, &mut outgoing_channels);
+            }
+            Wake::Tick(tick) => data.on_tick(tick, &mut timers),
+            Wake::Join => {
+                join_due = false;
+                data.ask_to_join(&mut outgoing_channels)?;
+            }
+            Wake::Shutdown => data.leave(&mut outgoing_channels),
+        }
+
+        data.proceed_reqs(&mut outgoing_channels)?;
+        data.flush_instructions(&mut outgoing_channels, &mut timers)?;
+
+        // the heartbeat sockets only exist once we're Living
+        for sock in data.heartbeat_sockets() {
+            // ports aren't reused, a removed host's stays where it was
+            let Ok(port) = sock.local_addr() else {
+                continue;
+            };
+            if !hearing.insert(port) {
+                continue;
+            }
+            let sock = sock
+                .try_clone()
+                .and_then(UdpSocket::from_std)
+                .during(Op::Heartbeat)?;
+            tokio::spawn(hear(sock, events.clone()));
+        }
+
+        // hosts the hostsfile added since we started, ones that aren't
+        // up yet get tried again after the next failure check
+        for (id, name) in data.to_dial(&outgoing_channels) {
+            if dialing.insert(id) {
+                tokio::spawn(dial(id, name, events.clone()));
+            }
+        }
+    }
+}
+
+/// Retries like `socketry::attempt_op`, without blocking the runtime in between
+async fn attempt<Socket, Fut>(
+    during: Op,
+    peer_name: &str,
+    op: impl Fn(String) -> Fut,
+) -> Result<Socket, Failure>
+where
+    Fut: std::future::Future<Output = io::Result<Socket>>,
+{
+    let addr = format!("{peer_name}:{PORT}");
+    let mut attempts = 0;
+    loop {
+        match op(addr.clone()).await {
+            Ok(sock) => return Ok(sock),
+            Err(e) if attempts == MAX_ATTEMPTS => {
+                return Err(e).during(during).map_err(|f| f.addr(addr))
+            }
+            Err(_) => {
+                attempts += 1;
+                sleep(ATTEMPT_WAIT).await;
+            }
+        }
+    }
+}
+
+async fn accept_all(listener: TcpListener, events: UnboundedSender<Event>) {
+    let mut next_id: ConnId = 0;
+    while let Ok((stream, addr)) = listener.accept().await {
+        let (read, write) = stream.into_split();
+        let conn = Conn {
+            reply: Outbox::spawn(write),
+            addr: Some(addr),
+            peer: None,
+            version: None,
+            admin: false,
+        };
+        if events.send(Event::Accepted(next_id, conn)).is_err() {
+            return;
+        }
+        tokio::spawn(read_frames(next_id, read, events.clone()));
+        next_id += 1;
+    }
+}
+
+/// Forwards every frame on a connection until it closes or turns to garbage
+async fn read_frames(id: ConnId, mut half: OwnedReadHalf, events: UnboundedSender<Event>) {
+    let mut frames = FrameReader::default();
+    let mut chunk = [0; 4096];
+    let reason = 'conn: loop {
+        match half.read(&mut chunk).await {
+            Ok(0) => break "connection closed".to_string(),
+            Ok(n) => frames.push(&chunk[..n]),
+            Err(e) => break e.to_string(),
+        }
+        while let Some(frame) = frames.next_frame() {
+            match frame {
+                Frame::Whole(body) => {
+                    if events.send(Event::Frame(id, body)).is_err() {
+                        return;
+                    }
+                }
+                Frame::Oversized(len) => {
+                    break 'conn format!("{len} byte frame is over the {MAX_FRAME} byte limit")
+                }
+            }
+        }
+    };
+    let _ = events.send(Event::Dropped(id, reason));
+}
+
+/// Says HELLO on a connection we opened. Past the answer peers only ever
+/// answer admin clients, so that's all that gets read off it
+fn open(
+    id: PeerId,
+    stream: TcpStream,
+    own_id: PeerId,
+    config: &Config,
+    events: &UnboundedSender<Event>,
+) -> Result<Outbox, Failure> {
+    let (read, write) = stream.into_split();
+    let mut outbox = Outbox::spawn(write);
+    wire::say_hello(&mut outbox, own_id, config.codec)?;
+    tokio::spawn(hear_answer(id, read, events.clone()));
+    Ok(outbox)
+}
+
+/// Waits for the frame answering our HELLO
+async fn hear_answer(id: PeerId, mut half: OwnedReadHalf, events: UnboundedSender<Event>) {
+    let mut frames = FrameReader::default();
+    let mut chunk = [0; 4096];
+    let answer = loop {
+        match frames.next_frame() {
+            Some(Frame::Whole(answer)) => break Ok(answer),
+            Some(Frame::Oversized(_)) => break Err(io::ErrorKind::InvalidData.into()),
+            None => {}
+        }
+        match half.read(&mut chunk).await {
+            Ok(0) => break Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => frames.push(&chunk[..n]),
+            Err(e) => break Err(e),
+        }
+    };
+    let answer = answer
+        .during(Op::Handshake)
+        .and_then(|answer| wire::hear_answer(&answer));
+    let _ = events.send(Event::Answered(id, answer));
+}
+
+/// Connects to a host once, without retrying
+async fn dial(id: PeerId, name: String, events: UnboundedSender<Event>) {
+    let stream = TcpStream::connect(format!("{name}:{PORT}")).await.ok();
+    let _ = events.send(Event::Dialed(id, stream));
+}
+
+/// Forwards the HEARTBEATs and ACKs arriving on one socket
+async fn hear(sock: UdpSocket, events: UnboundedSender<Event>) {
+    let mut buf = [0; 1024];
+    while let Ok(bytes_read) = sock.recv(&mut buf).await {
+        if let Ok(Parcel::Letter(letter)) = wire::decode(&buf[..bytes_read]) {
+            if events.send(Event::Heartbeat(letter)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+fn report_drop(data: &Data, conn: Option<Conn>, reason: String) {
+    let Some(conn) = conn else {
+        return;
+    };
+    let who = match (conn.peer, conn.addr) {
+        (Some(id), _) => format!("peer {id}"),
+        (None, Some(addr)) => addr.to_string(),
+        (None, None) => "unknown peer".to_string(),
+    };
+    if !conn.admin {
+        data.log(format_args!("dropping channel from {who}: {reason}"));
+    }
+}
+
+/// Hands one frame from `conn` to `Data`, same rules as `read_letters` in group.rs.
+/// Returns why the connection should be dropped, if it should.
+fn deliver(
+    data: &mut Data,
+    conn: &mut Conn,
+    body: &[u8],
+    outgoing_channels: &mut HashMap<PeerId, Outbox>,
+) -> Option<String> {
+    let parcel = match wire::decode(body) {
+        Ok(parcel) => parcel,
+        Err(e) if conn.version.is_none() => return Some(format!("bad HELLO: {e}")),
+        Err(e) => {
+            data.log(format_args!("skipping malformed letter: {e}"));
+            return None;
+        }
+    };
+    let (letter, version) = match (parcel, conn.version) {
+        (Parcel::Letter(letter), Some(version)) => (letter, version),
+        (Parcel::Hello(hello), None) => {
+            conn.admin = hello.peer_id == ADMIN_ID;
+            conn.version = data.answer_hello(&hello, &mut conn.reply);
+            return conn
+                .version
+                .is_none()
+                .then(|| "no wire version in common".to_string());
+        }
+        (_, None) => return Some("didn't open with a HELLO".to_string()),
+        (parcel, Some(_)) => {
+            data.log(format_args!("skipping unexpected {parcel:?}"));
+            return None;
+        }
+    };
+    if letter.from_whom() == ADMIN_ID {
+        conn.admin = true;
+        // no TLS, so no certificate to hold admin clients to
+        data.answer_admin(&letter, &mut conn.reply, version, true, outgoing_channels);
+        return None;
+    }
+    conn.peer = Some(letter.from_whom());
+    data.recv_message(&letter);
+    None
+}
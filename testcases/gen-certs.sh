@@ -0,0 +1,21 @@
+#!/bin/sh
+# Generates a throwaway CA and one certificate per host in the hostsfile
+# for the TLS testcase. Output goes into testcases/certs/.
+set -e
+
+HOSTSFILE=${1:-hostsfile.txt}
+OUT=$(dirname "$0")/certs
+mkdir -p "$OUT"
+
+openssl req -x509 -newkey rsa:2048 -nodes -days 30 \
+    -keyout "$OUT/ca.key" -out "$OUT/ca.pem" -subj "/CN=prj3 test ca"
+
+while read -r host; do
+    [ -z "$host" ] && continue
+    openssl req -newkey rsa:2048 -nodes \
+        -keyout "$OUT/$host.key" -out "$OUT/$host.csr" -subj "/CN=$host"
+    printf "subjectAltName=DNS:%s\nextendedKeyUsage=serverAuth,clientAuth\n" "$host" > "$OUT/$host.ext"
+    openssl x509 -req -in "$OUT/$host.csr" -CA "$OUT/ca.pem" -CAkey "$OUT/ca.key" \
+        -CAcreateserial -days 30 -out "$OUT/$host.pem" -extfile "$OUT/$host.ext"
+    rm "$OUT/$host.csr" "$OUT/$host.ext"
+done < "$HOSTSFILE"